@@ -56,7 +56,7 @@ async fn main() -> anyhow::Result<()> {
         .await?;
 
     // Create the output file.
-    let sample_spec = stream.sample_spec().clone();
+    let sample_spec = stream.sample_spec();
     let (bits_per_sample, sample_format) = match sample_spec.format {
         protocol::SampleFormat::S16Le => (16, hound::SampleFormat::Int),
         protocol::SampleFormat::Float32Le => (32, hound::SampleFormat::Float),
@@ -1,22 +1,39 @@
 use std::{
+    collections::VecDeque,
     ffi::{CStr, CString},
-    io::{BufReader, Read, Write},
+    io::{self, BufReader, Read, Write},
+    os::fd::{AsFd, OwnedFd},
+    sync::Arc,
+    time,
 };
 
-use mio::net::UnixStream;
-
 use super::protocol;
 
+mod default_device;
 mod playback_source;
 mod playback_stream;
 mod reactor;
+mod reconnect;
 mod record_sink;
 mod record_stream;
-
+mod server_state;
+mod shm;
+mod socket;
+mod srbchannel;
+mod stream_events;
+mod subscription;
+#[cfg(test)]
+mod test_util;
+
+pub use default_device::*;
 pub use playback_source::*;
 pub use playback_stream::*;
+pub use reconnect::*;
 pub use record_sink::*;
 pub use record_stream::*;
+pub use server_state::*;
+pub use stream_events::*;
+pub use subscription::*;
 
 /// An error encountered by a [Client].
 #[derive(Debug, thiserror::Error)]
@@ -36,10 +53,18 @@ pub enum ClientError {
     /// An error occurred reading or writing to the socket, or communicating
     /// with the worker thread.
     #[error("I/O error")]
-    Io(#[from] std::io::Error),
+    Io(#[from] io::Error),
     /// The client has disconnected, usually because an error occurred.
     #[error("Client disconnected")]
     Disconnected,
+    /// The stream was killed by the server, for example because its device
+    /// was removed.
+    #[error("Stream killed")]
+    StreamKilled,
+    /// The server didn't reply to a command in time. See
+    /// [Client::set_request_timeout].
+    #[error("Request timed out")]
+    Timeout,
 }
 
 /// The result of a [Client] operation.
@@ -61,76 +86,148 @@ impl std::fmt::Debug for Client {
 }
 
 impl Client {
-    /// Creates a new client, using the environment to find the socket and cookie file.
+    /// Creates a new client, using the environment to find the server and
+    /// cookie file.
     ///
-    /// See the documentation for [socket_path_from_env](super::socket_path_from_env) and
+    /// The candidates from [ClientConfig::server_candidates] are tried in
+    /// order, as libpulse does, which takes `$PULSE_SERVER` and `client.conf`
+    /// into account. See the documentation for
     /// [cookie_path_from_env](super::cookie_path_from_env) for an explanation
-    /// of how the socket path and cookie are determined.
+    /// of how the cookie is determined.
     pub fn from_env(client_name: impl AsRef<CStr>) -> Result<Self> {
-        let socket_path = super::socket_path_from_env().ok_or(ClientError::ServerUnavailable)?;
-        let cookie = super::cookie_path_from_env().and_then(|p| std::fs::read(p).ok());
+        let config = super::ClientConfig::from_env();
+        let cookie = super::cookie_path(&config).and_then(|p| std::fs::read(p).ok());
+
+        let mut last_err = None;
+        for addr in config.server_candidates() {
+            log::info!("connecting to PulseAudio server at {addr}");
+            match Self::connect(client_name.as_ref(), &addr, cookie.as_ref()) {
+                Err(ClientError::Io(err)) => {
+                    log::info!("failed to connect to {addr}: {err}");
+                    last_err = Some((addr, err));
+                }
+                res => return res,
+            }
+        }
 
-        log::info!(
-            "connecting to PulseAudio server at {}",
-            socket_path.display()
-        );
-        let socket = std::os::unix::net::UnixStream::connect(socket_path)?;
-        Self::new_unix(client_name, socket, cookie)
+        if let Some((addr, err)) = last_err {
+            log::warn!("no PulseAudio server available, last error from {addr}: {err}");
+        }
+
+        Err(ClientError::ServerUnavailable)
+    }
+
+    /// Creates a new client connected to the given server address.
+    pub fn connect(
+        client_name: impl AsRef<CStr>,
+        addr: &super::ServerAddress,
+        cookie: Option<impl AsRef<[u8]>>,
+    ) -> Result<Self> {
+        let (desc, conn) = connect_addr(client_name.as_ref(), addr, cookie)?;
+        let handle = reactor::Reactor::spawn(conn, None)?;
+
+        Ok(Self { desc, handle })
+    }
+
+    /// Creates a new client connected to the given server address, which
+    /// reconnects automatically if the connection is lost, for example
+    /// because the server restarted.
+    ///
+    /// While disconnected, the client retries with exponential backoff, and
+    /// commands fail with [ClientError::Disconnected]. Once reconnected, open
+    /// streams are recreated with their original parameters and subscriptions
+    /// are renewed, after which [reconnects](Self::reconnects) yields an
+    /// event. Streams emit [StreamEvent::Recreated], and their channel and
+    /// index may change. Playback streams that had already been drained are
+    /// killed instead, as are streams the new server refuses to create.
+    /// Subscriptions the new server refuses are logged, but the event is sent
+    /// regardless. Restoring uses the default timeout of 30 seconds, whatever
+    /// was passed to [set_request_timeout](Self::set_request_timeout).
+    pub fn connect_reconnecting(
+        client_name: impl AsRef<CStr>,
+        addr: super::ServerAddress,
+        cookie: Option<impl AsRef<[u8]>>,
+    ) -> Result<Self> {
+        let client_name = client_name.as_ref().to_owned();
+        let cookie = cookie.map(|c| c.as_ref().to_owned());
+
+        let (desc, conn) = connect_addr(&client_name, &addr, cookie.as_ref())?;
+        let connector = Box::new(move || {
+            connect_addr(&client_name, &addr, cookie.as_ref()).map(|(_, conn)| conn)
+        });
+
+        let handle = reactor::Reactor::spawn(conn, Some(connector))?;
+        Ok(Self { desc, handle })
     }
 
     /// Creates a new client, using the given connected unix domain socket to
     /// communicate with the PulseAudio server.
     pub fn new_unix(
         client_name: impl AsRef<CStr>,
-        mut socket: std::os::unix::net::UnixStream,
+        socket: std::os::unix::net::UnixStream,
         cookie: Option<impl AsRef<[u8]>>,
     ) -> std::result::Result<Self, ClientError> {
-        let desc = if let Some(path) = socket.peer_addr()?.as_pathname() {
-            format!("unix:{}", path.display())
-        } else {
-            "<unknown>".into()
-        };
+        let (desc, conn) = connect_unix(client_name.as_ref(), socket, cookie)?;
+        let handle = reactor::Reactor::spawn(conn, None)?;
 
-        // Perform the handshake.
-        let protocol_version;
-        {
-            let mut reader = BufReader::new(&mut socket);
-            let cookie = cookie.as_ref().map(AsRef::as_ref).unwrap_or(&[]).to_owned();
-            let auth = protocol::AuthParams {
-                version: protocol::MAX_VERSION,
-                supports_shm: false,
-                supports_memfd: false,
-                cookie,
-            };
-
-            let auth_reply: protocol::AuthReply = roundtrip_blocking(
-                &mut reader,
-                protocol::Command::Auth(auth),
-                0,
-                protocol::MAX_VERSION,
-            )?;
-
-            protocol_version = std::cmp::min(protocol::MAX_VERSION, auth_reply.version);
-
-            let mut props = protocol::Props::new();
-            props.set(protocol::Prop::ApplicationName, client_name.as_ref());
-
-            let _: protocol::SetClientNameReply = roundtrip_blocking(
-                &mut reader,
-                protocol::Command::SetClientName(props),
-                1,
-                protocol_version,
-            )?;
-        }
+        Ok(Self { desc, handle })
+    }
 
-        // Set up the reactor.
-        socket.set_nonblocking(true)?;
-        let socket = UnixStream::from_std(socket);
-        let handle = reactor::Reactor::spawn(socket, protocol_version)?;
+    /// Creates a new client, using the given connected TCP socket to
+    /// communicate with the PulseAudio server, for example one loaded with
+    /// `module-native-protocol-tcp`.
+    pub fn new_tcp(
+        client_name: impl AsRef<CStr>,
+        socket: std::net::TcpStream,
+        cookie: Option<impl AsRef<[u8]>>,
+    ) -> Result<Self> {
+        let (desc, conn) = connect_tcp(client_name.as_ref(), socket, cookie)?;
+        let handle = reactor::Reactor::spawn(conn, None)?;
 
         Ok(Self { desc, handle })
     }
 
+    /// Returns a [Stream](futures::Stream) which yields an event each time the
+    /// client reconnects to the server, once the server has replied to the
+    /// commands restoring streams and subscriptions, whether or not they
+    /// succeeded. Streams that couldn't be restored are killed.
+    ///
+    /// Only clients created with
+    /// [connect_reconnecting](Self::connect_reconnecting) ever reconnect.
+    pub fn reconnects(&self) -> Reconnects {
+        Reconnects::new(self.handle.reconnect_events())
+    }
+
+    /// Sets how long to wait for the server to reply to each command before
+    /// failing with [ClientError::Timeout], or `None` to wait indefinitely.
+    /// The default is 30 seconds.
+    ///
+    /// The timeout applies to commands sent through this client, and through
+    /// clones and streams created from it afterwards. A command that times
+    /// out may still take effect on the server. Commands that restore streams
+    /// and subscriptions after reconnecting always use the default.
+    pub fn set_request_timeout(&mut self, timeout: Option<time::Duration>) {
+        self.handle.set_timeout(timeout);
+    }
+
+    /// Deletes any open streams and closes the connection to the server.
+    /// Afterwards, commands sent through this client or any clone of it fail
+    /// with [ClientError::Disconnected].
+    ///
+    /// If the connection had already failed, this returns the error that
+    /// caused it, like [closed](Self::closed).
+    pub async fn close(&self) -> std::result::Result<(), Arc<ClientError>> {
+        self.handle.close().await
+    }
+
+    /// Waits for the connection to the server to end. This resolves with
+    /// `Ok(())` if the client was closed with [close](Self::close), and
+    /// otherwise with the error that ended the connection, which is shared
+    /// between all callers.
+    pub async fn closed(&self) -> std::result::Result<(), Arc<ClientError>> {
+        self.handle.closed().await
+    }
+
     /// Fetches basic information on the server.
     pub async fn server_info(&self) -> Result<protocol::ServerInfo> {
         self.handle
@@ -152,6 +249,29 @@ impl Client {
             .await
     }
 
+    /// Updates the properties of this client, for example to set
+    /// [ApplicationName](protocol::Prop::ApplicationName).
+    pub async fn update_props(
+        &self,
+        mode: protocol::props::PropsUpdateMode,
+        props: protocol::Props,
+    ) -> Result<()> {
+        self.handle
+            .roundtrip_ack(protocol::Command::UpdateClientProplist(
+                protocol::UpdateClientProplistParams { mode, props },
+            ))
+            .await
+    }
+
+    /// Removes properties from this client, by key.
+    pub async fn remove_props(&self, keys: Vec<CString>) -> Result<()> {
+        self.handle
+            .roundtrip_ack(protocol::Command::RemoveClientProplist(
+                protocol::RemoveClientProplistParams { keys },
+            ))
+            .await
+    }
+
     /// Fetches all sinks available on the server.
     pub async fn list_sinks(&self) -> Result<Vec<protocol::SinkInfo>> {
         self.handle
@@ -206,6 +326,34 @@ impl Client {
             .await
     }
 
+    /// Fetches all sink inputs, i.e. playback streams connected to sinks.
+    pub async fn list_sink_inputs(&self) -> Result<Vec<protocol::SinkInputInfo>> {
+        self.handle
+            .roundtrip_reply(protocol::Command::GetSinkInputInfoList)
+            .await
+    }
+
+    /// Fetches a specific sink input by its index.
+    pub async fn sink_input_info(&self, index: u32) -> Result<protocol::SinkInputInfo> {
+        self.handle
+            .roundtrip_reply(protocol::Command::GetSinkInputInfo(index))
+            .await
+    }
+
+    /// Fetches all source outputs, i.e. record streams connected to sources.
+    pub async fn list_source_outputs(&self) -> Result<Vec<protocol::SourceOutputInfo>> {
+        self.handle
+            .roundtrip_reply(protocol::Command::GetSourceOutputInfoList)
+            .await
+    }
+
+    /// Fetches a specific source output by its index.
+    pub async fn source_output_info(&self, index: u32) -> Result<protocol::SourceOutputInfo> {
+        self.handle
+            .roundtrip_reply(protocol::Command::GetSourceOutputInfo(index))
+            .await
+    }
+
     /// Looks up a sink by its index.
     pub async fn lookup_sink(&self, index: u32) -> Result<u32> {
         let cmd = protocol::Command::LookupSink(CString::new(index.to_string()).unwrap());
@@ -273,6 +421,120 @@ impl Client {
             .await
     }
 
+    /// Sets the active profile of a card, by the card's index.
+    pub async fn set_card_profile(&self, index: u32, profile_name: CString) -> Result<()> {
+        self.handle
+            .roundtrip_ack(protocol::Command::SetCardProfile(
+                protocol::SetCardProfileParams {
+                    card_index: Some(index),
+                    card_name: None,
+                    profile_name,
+                },
+            ))
+            .await
+    }
+
+    /// Sets the active profile of a card, by the card's name.
+    pub async fn set_card_profile_by_name(
+        &self,
+        name: CString,
+        profile_name: CString,
+    ) -> Result<()> {
+        self.handle
+            .roundtrip_ack(protocol::Command::SetCardProfile(
+                protocol::SetCardProfileParams {
+                    card_index: None,
+                    card_name: Some(name),
+                    profile_name,
+                },
+            ))
+            .await
+    }
+
+    /// Sets the active port of a sink, by the sink's index.
+    pub async fn set_sink_port(&self, index: u32, port_name: CString) -> Result<()> {
+        self.handle
+            .roundtrip_ack(protocol::Command::SetSinkPort(protocol::SetPortParams {
+                index: Some(index),
+                name: None,
+                port_name,
+            }))
+            .await
+    }
+
+    /// Sets the active port of a sink, by the sink's name.
+    pub async fn set_sink_port_by_name(&self, name: CString, port_name: CString) -> Result<()> {
+        self.handle
+            .roundtrip_ack(protocol::Command::SetSinkPort(protocol::SetPortParams {
+                index: None,
+                name: Some(name),
+                port_name,
+            }))
+            .await
+    }
+
+    /// Sets the active port of a source, by the source's index.
+    pub async fn set_source_port(&self, index: u32, port_name: CString) -> Result<()> {
+        self.handle
+            .roundtrip_ack(protocol::Command::SetSourcePort(protocol::SetPortParams {
+                index: Some(index),
+                name: None,
+                port_name,
+            }))
+            .await
+    }
+
+    /// Sets the active port of a source, by the source's name.
+    pub async fn set_source_port_by_name(&self, name: CString, port_name: CString) -> Result<()> {
+        self.handle
+            .roundtrip_ack(protocol::Command::SetSourcePort(protocol::SetPortParams {
+                index: None,
+                name: Some(name),
+                port_name,
+            }))
+            .await
+    }
+
+    /// Sets the latency offset of a port on a card, by the card's index. The
+    /// offset is in microseconds.
+    pub async fn set_port_latency_offset(
+        &self,
+        card_index: u32,
+        port_name: CString,
+        offset: i64,
+    ) -> Result<()> {
+        self.handle
+            .roundtrip_ack(protocol::Command::SetPortLatencyOffset(
+                protocol::SetPortLatencyOffsetParams {
+                    index: Some(card_index),
+                    name: None,
+                    port_name,
+                    offset,
+                },
+            ))
+            .await
+    }
+
+    /// Sets the latency offset of a port on a card, by the card's name. The
+    /// offset is in microseconds.
+    pub async fn set_port_latency_offset_by_name(
+        &self,
+        card_name: CString,
+        port_name: CString,
+        offset: i64,
+    ) -> Result<()> {
+        self.handle
+            .roundtrip_ack(protocol::Command::SetPortLatencyOffset(
+                protocol::SetPortLatencyOffsetParams {
+                    index: None,
+                    name: Some(card_name),
+                    port_name,
+                    offset,
+                },
+            ))
+            .await
+    }
+
     /// Fetches a specific module.
     pub async fn module_info(&self, index: u32) -> Result<protocol::ModuleInfo> {
         self.handle
@@ -287,6 +549,104 @@ impl Client {
             .await
     }
 
+    /// Loads a module, returning the index of the newly loaded module.
+    pub async fn load_module(&self, name: CString, args: &protocol::ModuleArgs) -> Result<u32> {
+        let arguments = if args.is_empty() {
+            None
+        } else {
+            Some(args.to_c_string()?)
+        };
+
+        let cmd = protocol::Command::LoadModule(protocol::LoadModuleParams { name, arguments });
+        let reply = self
+            .handle
+            .roundtrip_reply::<protocol::LoadModuleReply>(cmd)
+            .await?;
+        Ok(reply.0)
+    }
+
+    /// Unloads a module.
+    pub async fn unload_module(&self, index: u32) -> Result<()> {
+        self.handle
+            .roundtrip_ack(protocol::Command::UnloadModule(index))
+            .await
+    }
+
+    /// Sends a message to an object on the server, like `/core`, returning
+    /// the response, if any. Use [protocol::MessageParams] to build the
+    /// parameters or parse the response. Requires protocol version 35 or
+    /// later.
+    pub async fn send_message(
+        &self,
+        object_path: CString,
+        message: CString,
+        params: &protocol::MessageParams,
+    ) -> Result<Option<CString>> {
+        let parameters = if params.is_empty() {
+            None
+        } else {
+            Some(params.to_c_string()?)
+        };
+
+        let cmd = protocol::Command::SendObjectMessage(protocol::SendObjectMessageParams {
+            object_path,
+            message,
+            parameters,
+        });
+
+        let reply = self
+            .handle
+            .roundtrip_reply::<protocol::SendObjectMessageReply>(cmd)
+            .await?;
+        Ok(reply.0)
+    }
+
+    /// Fetches all entries stored by module-stream-restore.
+    pub async fn stream_restore_entries(&self) -> Result<Vec<protocol::StreamRestoreEntry>> {
+        self.handle
+            .roundtrip_reply(protocol::Command::Extension(
+                protocol::ExtensionParams::stream_restore(protocol::StreamRestoreCommand::Read),
+            ))
+            .await
+    }
+
+    /// Writes entries to module-stream-restore. If `apply_immediately` is
+    /// set, existing streams matching the entries are updated as well.
+    pub async fn write_stream_restore_entries(
+        &self,
+        entries: Vec<protocol::StreamRestoreEntry>,
+        mode: protocol::props::PropsUpdateMode,
+        apply_immediately: bool,
+    ) -> Result<()> {
+        self.handle
+            .roundtrip_ack(protocol::Command::Extension(
+                protocol::ExtensionParams::stream_restore(protocol::StreamRestoreCommand::Write(
+                    protocol::StreamRestoreWriteParams {
+                        mode,
+                        apply_immediately,
+                        entries,
+                    },
+                )),
+            ))
+            .await
+    }
+
+    /// Deletes entries from module-stream-restore by name.
+    pub async fn delete_stream_restore_entries(&self, names: Vec<CString>) -> Result<()> {
+        self.handle
+            .roundtrip_ack(protocol::Command::Extension(
+                protocol::ExtensionParams::stream_restore(protocol::StreamRestoreCommand::Delete(
+                    names,
+                )),
+            ))
+            .await
+    }
+
+    /// Subscribes to changes of the entries stored by module-stream-restore.
+    pub async fn subscribe_stream_restore(&self) -> Result<StreamRestoreSubscription> {
+        StreamRestoreSubscription::new(self.handle.clone()).await
+    }
+
     /// Fetches memory usage information from the server.
     pub async fn stat(&self) -> Result<protocol::StatInfo> {
         self.handle.roundtrip_reply(protocol::Command::Stat).await
@@ -306,6 +666,67 @@ impl Client {
             .await
     }
 
+    /// Uploads a sample to the server's sample cache, so that it can later be
+    /// played with [play_sample](Self::play_sample).
+    ///
+    /// The upload isn't streamed: the server needs to know the length of the
+    /// sample up front, so the source is read fully into memory before
+    /// anything is sent. It must eventually return EOF, and may provide at
+    /// most 16 MiB of data.
+    ///
+    /// If a sample with the same name already exists, it is replaced.
+    pub async fn upload_sample(
+        &self,
+        name: CString,
+        sample_spec: protocol::SampleSpec,
+        channel_map: protocol::ChannelMap,
+        props: protocol::Props,
+        source: impl PlaybackSource,
+    ) -> Result<()> {
+        let chunks = read_chunks(source).await?;
+        let params = protocol::UploadStreamParams {
+            media_name: Some(name),
+            sample_spec,
+            channel_map,
+            length: chunks.iter().map(Vec::len).sum::<usize>() as u32,
+            props,
+        };
+
+        self.handle.upload_sample(params, chunks).await
+    }
+
+    /// Plays a sample from the sample cache on the given sink. Pass
+    /// [protocol::DEFAULT_SINK] to use the default sink. If no volume is
+    /// given, the sample is played at its default volume.
+    ///
+    /// Returns the index of the sink input created to play the sample.
+    pub async fn play_sample(
+        &self,
+        name: CString,
+        sink_name: CString,
+        volume: Option<protocol::Volume>,
+        props: protocol::Props,
+    ) -> Result<u32> {
+        let reply: protocol::PlaySampleReply = self
+            .handle
+            .roundtrip_reply(protocol::Command::PlaySample(protocol::PlaySampleParams {
+                sink_index: None,
+                sink_name: Some(sink_name),
+                volume: volume.map_or(u32::MAX, |v| v.as_u32()),
+                name,
+                props,
+            }))
+            .await?;
+        Ok(reply.0)
+    }
+
+    /// Removes a sample from the sample cache.
+    pub async fn remove_sample(&self, name: CString) -> Result<()> {
+        self.handle
+            .roundtrip_ack(protocol::Command::RemoveSample(name))
+            .await
+    }
+
     /// Sets the default sink.
     pub async fn set_default_sink(&self, name: CString) -> Result<()> {
         self.handle
@@ -385,6 +806,247 @@ impl Client {
             .await
     }
 
+    /// Sets the volume of a sink by its index.
+    pub async fn set_sink_volume(&self, index: u32, volume: protocol::ChannelVolume) -> Result<()> {
+        self.handle
+            .roundtrip_ack(protocol::Command::SetSinkVolume(
+                protocol::SetDeviceVolumeParams {
+                    device_index: Some(index),
+                    device_name: None,
+                    volume,
+                },
+            ))
+            .await
+    }
+
+    /// Sets the volume of a sink by its name. Use [protocol::DEFAULT_SINK] to
+    /// address the default sink.
+    pub async fn set_sink_volume_by_name(
+        &self,
+        name: CString,
+        volume: protocol::ChannelVolume,
+    ) -> Result<()> {
+        self.handle
+            .roundtrip_ack(protocol::Command::SetSinkVolume(
+                protocol::SetDeviceVolumeParams {
+                    device_index: None,
+                    device_name: Some(name),
+                    volume,
+                },
+            ))
+            .await
+    }
+
+    /// Sets the volume of a source by its index.
+    pub async fn set_source_volume(
+        &self,
+        index: u32,
+        volume: protocol::ChannelVolume,
+    ) -> Result<()> {
+        self.handle
+            .roundtrip_ack(protocol::Command::SetSourceVolume(
+                protocol::SetDeviceVolumeParams {
+                    device_index: Some(index),
+                    device_name: None,
+                    volume,
+                },
+            ))
+            .await
+    }
+
+    /// Sets the volume of a source by its name. Use [protocol::DEFAULT_SOURCE]
+    /// to address the default source.
+    pub async fn set_source_volume_by_name(
+        &self,
+        name: CString,
+        volume: protocol::ChannelVolume,
+    ) -> Result<()> {
+        self.handle
+            .roundtrip_ack(protocol::Command::SetSourceVolume(
+                protocol::SetDeviceVolumeParams {
+                    device_index: None,
+                    device_name: Some(name),
+                    volume,
+                },
+            ))
+            .await
+    }
+
+    /// Sets the volume of a sink input.
+    pub async fn set_sink_input_volume(
+        &self,
+        index: u32,
+        volume: protocol::ChannelVolume,
+    ) -> Result<()> {
+        self.handle
+            .roundtrip_ack(protocol::Command::SetSinkInputVolume(
+                protocol::SetStreamVolumeParams { index, volume },
+            ))
+            .await
+    }
+
+    /// Sets the volume of a source output.
+    pub async fn set_source_output_volume(
+        &self,
+        index: u32,
+        volume: protocol::ChannelVolume,
+    ) -> Result<()> {
+        self.handle
+            .roundtrip_ack(protocol::Command::SetSourceOutputVolume(
+                protocol::SetStreamVolumeParams { index, volume },
+            ))
+            .await
+    }
+
+    /// Mutes or unmutes a sink by its index.
+    pub async fn set_sink_mute(&self, index: u32, mute: bool) -> Result<()> {
+        self.handle
+            .roundtrip_ack(protocol::Command::SetSinkMute(
+                protocol::SetDeviceMuteParams {
+                    device_index: Some(index),
+                    device_name: None,
+                    mute,
+                },
+            ))
+            .await
+    }
+
+    /// Mutes or unmutes a sink by its name. Use [protocol::DEFAULT_SINK] to
+    /// address the default sink.
+    pub async fn set_sink_mute_by_name(&self, name: CString, mute: bool) -> Result<()> {
+        self.handle
+            .roundtrip_ack(protocol::Command::SetSinkMute(
+                protocol::SetDeviceMuteParams {
+                    device_index: None,
+                    device_name: Some(name),
+                    mute,
+                },
+            ))
+            .await
+    }
+
+    /// Mutes or unmutes a source by its index.
+    pub async fn set_source_mute(&self, index: u32, mute: bool) -> Result<()> {
+        self.handle
+            .roundtrip_ack(protocol::Command::SetSourceMute(
+                protocol::SetDeviceMuteParams {
+                    device_index: Some(index),
+                    device_name: None,
+                    mute,
+                },
+            ))
+            .await
+    }
+
+    /// Mutes or unmutes a source by its name. Use [protocol::DEFAULT_SOURCE]
+    /// to address the default source.
+    pub async fn set_source_mute_by_name(&self, name: CString, mute: bool) -> Result<()> {
+        self.handle
+            .roundtrip_ack(protocol::Command::SetSourceMute(
+                protocol::SetDeviceMuteParams {
+                    device_index: None,
+                    device_name: Some(name),
+                    mute,
+                },
+            ))
+            .await
+    }
+
+    /// Mutes or unmutes a sink input.
+    pub async fn set_sink_input_mute(&self, index: u32, mute: bool) -> Result<()> {
+        self.handle
+            .roundtrip_ack(protocol::Command::SetSinkInputMute(
+                protocol::SetStreamMuteParams { index, mute },
+            ))
+            .await
+    }
+
+    /// Mutes or unmutes a source output.
+    pub async fn set_source_output_mute(&self, index: u32, mute: bool) -> Result<()> {
+        self.handle
+            .roundtrip_ack(protocol::Command::SetSourceOutputMute(
+                protocol::SetStreamMuteParams { index, mute },
+            ))
+            .await
+    }
+
+    /// Moves a sink input to a different sink, by the sink's index.
+    pub async fn move_sink_input(&self, index: u32, sink_index: u32) -> Result<()> {
+        self.handle
+            .roundtrip_ack(protocol::Command::MoveSinkInput(
+                protocol::MoveStreamParams {
+                    index: Some(index),
+                    device_index: Some(sink_index),
+                    device_name: None,
+                },
+            ))
+            .await
+    }
+
+    /// Moves a sink input to a different sink, by the sink's name.
+    pub async fn move_sink_input_by_name(&self, index: u32, sink_name: CString) -> Result<()> {
+        self.handle
+            .roundtrip_ack(protocol::Command::MoveSinkInput(
+                protocol::MoveStreamParams {
+                    index: Some(index),
+                    device_index: None,
+                    device_name: Some(sink_name),
+                },
+            ))
+            .await
+    }
+
+    /// Moves a source output to a different source, by the source's index.
+    pub async fn move_source_output(&self, index: u32, source_index: u32) -> Result<()> {
+        self.handle
+            .roundtrip_ack(protocol::Command::MoveSourceOutput(
+                protocol::MoveStreamParams {
+                    index: Some(index),
+                    device_index: Some(source_index),
+                    device_name: None,
+                },
+            ))
+            .await
+    }
+
+    /// Moves a source output to a different source, by the source's name.
+    pub async fn move_source_output_by_name(&self, index: u32, source_name: CString) -> Result<()> {
+        self.handle
+            .roundtrip_ack(protocol::Command::MoveSourceOutput(
+                protocol::MoveStreamParams {
+                    index: Some(index),
+                    device_index: None,
+                    device_name: Some(source_name),
+                },
+            ))
+            .await
+    }
+
+    /// Subscribes to events from the server. The returned [Subscription] is a
+    /// [Stream](futures::Stream) of events matching the given mask.
+    ///
+    /// Multiple subscriptions can be active at once; the server is sent the
+    /// union of all their masks.
+    pub async fn subscribe(&self, mask: protocol::SubscriptionMask) -> Result<Subscription> {
+        Subscription::new(self.handle.clone(), mask).await
+    }
+
+    /// Watches the default sink. The returned stream yields the current
+    /// default sink, and then the new default sink or its updated info
+    /// whenever either changes. It yields `None` while there is no default
+    /// sink.
+    pub async fn watch_default_sink(&self) -> Result<DefaultDeviceStream<protocol::SinkInfo>> {
+        DefaultDeviceStream::sink(self.clone()).await
+    }
+
+    /// Watches the default source. The returned stream yields the current
+    /// default source, and then the new default source or its updated info
+    /// whenever either changes. It yields `None` while there is no default
+    /// source.
+    pub async fn watch_default_source(&self) -> Result<DefaultDeviceStream<protocol::SourceInfo>> {
+        DefaultDeviceStream::source(self.clone()).await
+    }
+
     /// Creates a new playback stream. The given callback will be called when the
     /// server requests data for the stream.
     pub async fn create_playback_stream(
@@ -406,21 +1068,314 @@ impl Client {
     }
 }
 
-fn roundtrip_blocking<R: protocol::CommandReply>(
-    socket: &mut BufReader<impl Read + Write>,
-    cmd: protocol::Command,
-    req_seq: u32,
-    protocol_version: u16,
-) -> Result<R> {
-    log::debug!("CLIENT [{req_seq}]: {cmd:?}");
-    protocol::write_command_message(socket.get_mut(), req_seq, &cmd, protocol_version)?;
+/// How long to wait for a TCP connection to each address of the server.
+const CONNECT_TIMEOUT: time::Duration = time::Duration::from_secs(5);
+
+/// PA_SCACHE_ENTRY_SIZE_MAX from the Pulse source. This is the maximum size
+/// of a sample in the sample cache, in bytes.
+const MAX_SAMPLE_SIZE: usize = 1024 * 1024 * 16;
+
+/// Reads a source to the end, in chunks that can be sent to the server as
+/// individual memblocks.
+async fn read_chunks(source: impl PlaybackSource) -> Result<Vec<Vec<u8>>> {
+    let mut source = std::pin::pin!(source);
+    let mut chunks = Vec::new();
+    let mut total = 0;
+
+    loop {
+        let mut chunk = vec![0; reactor::UPLOAD_CHUNK_SIZE];
+        let mut len = 0;
+        while len < chunk.len() {
+            let n = futures::future::poll_fn(|cx| source.as_mut().poll_read(cx, &mut chunk[len..]))
+                .await;
+            if n == 0 {
+                break;
+            }
 
-    let (reply_seq, reply) = protocol::read_reply_message(socket, protocol_version)?;
-    if req_seq != reply_seq {
-        return Err(ClientError::UnexpectedSequenceNumber);
-    }
+            len += n;
+        }
 
-    Ok(reply)
+        total += len;
+        if total > MAX_SAMPLE_SIZE {
+            return Err(ClientError::Protocol(protocol::ProtocolError::Invalid(
+                "sample too large".into(),
+            )));
+        }
+
+        if len > 0 {
+            chunk.truncate(len);
+            chunks.push(chunk);
+        }
+
+        // A short chunk means the source reached EOF.
+        if len < reactor::UPLOAD_CHUNK_SIZE {
+            return Ok(chunks);
+        }
+    }
+}
+
+/// Opens a socket to the given address and performs the handshake.
+fn connect_addr(
+    client_name: &CStr,
+    addr: &super::ServerAddress,
+    cookie: Option<impl AsRef<[u8]>>,
+) -> Result<(String, reactor::Connection)> {
+    match addr {
+        super::ServerAddress::Unix(path) => {
+            let socket = std::os::unix::net::UnixStream::connect(path)?;
+            connect_unix(client_name, socket, cookie)
+        }
+        super::ServerAddress::Tcp { .. } => {
+            let socket = tcp_connect_timeout(&addr.to_socket_addrs()?)?;
+            connect_tcp(client_name, socket, cookie)
+        }
+    }
+}
+
+/// Connects to the first of the given addresses that accepts the connection,
+/// giving up on each after [CONNECT_TIMEOUT].
+fn tcp_connect_timeout(addrs: &[std::net::SocketAddr]) -> io::Result<std::net::TcpStream> {
+    let mut last_err = None;
+    for addr in addrs {
+        match std::net::TcpStream::connect_timeout(addr, CONNECT_TIMEOUT) {
+            Ok(socket) => return Ok(socket),
+            Err(err) => {
+                log::debug!("failed to connect to {addr}: {err}");
+                last_err = Some(err);
+            }
+        }
+    }
+
+    Err(last_err.unwrap_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "could not resolve to any addresses",
+        )
+    }))
+}
+
+/// Performs the handshake on a unix domain socket, setting up shared memory
+/// if possible.
+fn connect_unix(
+    client_name: &CStr,
+    socket: std::os::unix::net::UnixStream,
+    cookie: Option<impl AsRef<[u8]>>,
+) -> Result<(String, reactor::Connection)> {
+    let desc = if let Some(path) = socket.peer_addr()?.as_pathname() {
+        format!("unix:{}", path.display())
+    } else {
+        "<unknown>".into()
+    };
+
+    // Offer to send stream data over shared memory, if we can set up a
+    // pool, preferring memfd. The server only accepts if it's running as
+    // the same user.
+    let shm = match shm::ShmPool::new_memfd().or_else(|_| shm::ShmPool::new()) {
+        Ok(pool) => Some(pool),
+        Err(err) => {
+            log::debug!("shared memory unavailable: {err}");
+            None
+        }
+    };
+
+    let supports_memfd = shm.as_ref().is_some_and(shm::ShmPool::is_memfd);
+    let Handshake { auth, backlog, fds } = handshake(
+        socket::FdStream::new(&socket),
+        client_name,
+        cookie,
+        shm.is_some(),
+        supports_memfd,
+    )?;
+
+    // The server may accept shm, but not memfd.
+    let shm = match shm {
+        Some(pool) if pool.is_memfd() && !auth.use_memfd => shm::ShmPool::new().ok(),
+        pool => pool,
+    }
+    .filter(|_| auth.use_shm);
+
+    if let Some(pool) = &shm {
+        log::debug!(
+            "using shared memory pool {} (memfd: {})",
+            pool.id(),
+            pool.is_memfd()
+        );
+
+        if let Some(memfd) = pool.memfd() {
+            register_memfd(&socket, pool.id(), memfd, auth.version)?;
+        }
+    }
+
+    socket.set_nonblocking(true)?;
+    let conn = reactor::Connection {
+        socket: socket::Socket::Unix(mio::net::UnixStream::from_std(socket)),
+        protocol_version: auth.version,
+        shm,
+        backlog,
+        fds,
+    };
+
+    Ok((desc, conn))
+}
+
+/// Performs the handshake on a TCP socket.
+fn connect_tcp(
+    client_name: &CStr,
+    mut socket: std::net::TcpStream,
+    cookie: Option<impl AsRef<[u8]>>,
+) -> Result<(String, reactor::Connection)> {
+    let desc = format!("tcp:{}", socket.peer_addr()?);
+
+    // Commands are small and latency-sensitive.
+    socket.set_nodelay(true)?;
+
+    let Handshake { auth, backlog, fds } =
+        handshake(&mut socket, client_name, cookie, false, false)?;
+
+    socket.set_nonblocking(true)?;
+    let conn = reactor::Connection {
+        socket: socket::Socket::Tcp(mio::net::TcpStream::from_std(socket)),
+        protocol_version: auth.version,
+        shm: None,
+        backlog,
+        fds,
+    };
+
+    Ok((desc, conn))
+}
+
+/// The result of a successful handshake.
+struct Handshake {
+    /// Contains the negotiated protocol version and whether shm and memfd were
+    /// accepted by the server.
+    auth: protocol::AuthReply,
+    /// Messages sent by the server during the handshake which weren't replies,
+    /// like memfd registrations, to be handled by the reactor.
+    backlog: Vec<u8>,
+    /// File descriptors passed along with the backlog.
+    fds: VecDeque<OwnedFd>,
+}
+
+/// Authenticates and sets the client name, on a blocking socket.
+fn handshake(
+    mut socket: impl Read + Write + AsFd + socket::TakeFds,
+    client_name: &CStr,
+    cookie: Option<impl AsRef<[u8]>>,
+    supports_shm: bool,
+    supports_memfd: bool,
+) -> Result<Handshake> {
+    // Don't wait forever for a server that accepted the connection but
+    // doesn't respond. The socket is switched to non-blocking mode once the
+    // handshake is done, which makes the timeouts irrelevant.
+    socket::set_timeouts(socket.as_fd(), reactor::DEFAULT_TIMEOUT)?;
+
+    let mut reader = BufReader::new(&mut socket);
+    let mut backlog = Vec::new();
+    let cookie = cookie.as_ref().map(AsRef::as_ref).unwrap_or(&[]).to_owned();
+    let auth = protocol::AuthParams {
+        version: protocol::MAX_VERSION,
+        supports_shm,
+        supports_memfd,
+        cookie,
+    };
+
+    let auth_reply: protocol::AuthReply = roundtrip_blocking(
+        &mut reader,
+        protocol::Command::Auth(auth),
+        0,
+        protocol::MAX_VERSION,
+        &mut backlog,
+    )?;
+
+    let protocol_version = std::cmp::min(protocol::MAX_VERSION, auth_reply.version);
+
+    let mut props = protocol::Props::new();
+    props.set(protocol::Prop::ApplicationName, client_name);
+
+    let _: protocol::SetClientNameReply = roundtrip_blocking(
+        &mut reader,
+        protocol::Command::SetClientName(props),
+        1,
+        protocol_version,
+        &mut backlog,
+    )?;
+
+    // Anything already buffered belongs to the reactor, too.
+    backlog.extend_from_slice(reader.buffer());
+    drop(reader);
+
+    Ok(Handshake {
+        auth: protocol::AuthReply {
+            version: protocol_version,
+            use_shm: supports_shm && auth_reply.use_shm,
+            use_memfd: supports_memfd && auth_reply.use_shm && auth_reply.use_memfd,
+        },
+        backlog,
+        fds: socket.take_fds(),
+    })
+}
+
+/// Sends a command and waits for the reply. Any other messages sent by the
+/// server in the meantime are appended to `backlog`, as-is.
+fn roundtrip_blocking<R: protocol::CommandReply, S: Read + Write>(
+    socket: &mut BufReader<S>,
+    cmd: protocol::Command,
+    req_seq: u32,
+    protocol_version: u16,
+    backlog: &mut Vec<u8>,
+) -> Result<R> {
+    log::debug!("CLIENT [{req_seq}]: {cmd:?}");
+    protocol::write_command_message(socket.get_mut(), req_seq, &cmd, protocol_version)?;
+
+    loop {
+        let desc = protocol::read_descriptor(socket)?;
+        let mut payload = vec![0; desc.length as usize];
+        socket.read_exact(&mut payload)?;
+
+        if desc.channel == u32::MAX {
+            let mut cursor = io::Cursor::new(&payload);
+            match protocol::Command::read_tag_prefixed(&mut cursor, protocol_version) {
+                Ok((reply_seq, protocol::Command::Reply)) => {
+                    if req_seq != reply_seq {
+                        return Err(ClientError::UnexpectedSequenceNumber);
+                    }
+
+                    return Ok(
+                        protocol::TagStructReader::new(&mut cursor, protocol_version).read()?,
+                    );
+                }
+                Ok((_, protocol::Command::Error(err))) => {
+                    return Err(protocol::ProtocolError::ServerError(err).into());
+                }
+                _ => (),
+            }
+        }
+
+        let mut header = [0; protocol::DESCRIPTOR_SIZE];
+        protocol::encode_descriptor(&mut header, &desc);
+        backlog.extend_from_slice(&header);
+        backlog.extend_from_slice(&payload);
+    }
+}
+
+/// Registers a memfd pool with the server, passing the memfd along with the
+/// command.
+fn register_memfd(
+    socket: &std::os::unix::net::UnixStream,
+    shm_id: u32,
+    memfd: std::os::fd::BorrowedFd<'_>,
+    protocol_version: u16,
+) -> Result<()> {
+    let cmd = protocol::Command::RegisterMemfdShmid(shm_id);
+    log::debug!("CLIENT [-1]: {cmd:?}");
+
+    let mut buf = Vec::new();
+    protocol::write_command_message(&mut buf, u32::MAX, &cmd, protocol_version)?;
+
+    // The fd must arrive with the start of the message.
+    let n = socket::send_with_fds(socket, &buf, &[memfd])?;
+    (&*socket).write_all(&buf[n..])?;
+    Ok(())
 }
 #[cfg(all(test, feature = "_integration-tests"))]
 mod tests {
@@ -572,6 +1527,58 @@ mod tests {
         Ok(())
     }
 
+    #[test_log::test]
+    fn sink_input_info() -> anyhow::Result<()> {
+        let client =
+            Client::from_env(random_client_name()).context("connecting to PulseAudio server")?;
+
+        let params = protocol::PlaybackStreamParams {
+            sample_spec: protocol::SampleSpec {
+                format: protocol::SampleFormat::S16Le,
+                channels: 2,
+                sample_rate: 44100,
+            },
+            channel_map: protocol::ChannelMap::stereo(),
+            ..Default::default()
+        };
+
+        let stream = block_on(
+            client
+                .create_playback_stream(params, (|buf: &mut [u8]| buf.len()).as_playback_source()),
+        )?;
+
+        let sink_inputs = block_on(client.list_sink_inputs())?;
+        let expected = sink_inputs
+            .iter()
+            .find(|info| info.index == stream.stream_index())
+            .ok_or(anyhow!("no sink input for stream"))?;
+
+        let mut sink_input_info = block_on(client.sink_input_info(expected.index))?;
+        sink_input_info.buffer_latency = expected.buffer_latency;
+        sink_input_info.sink_latency = expected.sink_latency;
+        assert_eq!(expected, &sink_input_info);
+
+        Ok(())
+    }
+
+    #[test_log::test]
+    fn source_output_info() -> anyhow::Result<()> {
+        let client =
+            Client::from_env(random_client_name()).context("connecting to PulseAudio server")?;
+
+        let source_outputs = block_on(client.list_source_outputs())?;
+
+        // The list is often empty.
+        if let Some(expected) = source_outputs.first() {
+            let mut source_output_info = block_on(client.source_output_info(expected.index))?;
+            source_output_info.buffer_latency = expected.buffer_latency;
+            source_output_info.source_latency = expected.source_latency;
+            assert_eq!(expected, &source_output_info);
+        }
+
+        Ok(())
+    }
+
     #[test_log::test]
     fn lookup_sink() -> anyhow::Result<()> {
         let client =
@@ -679,6 +1686,26 @@ mod tests {
         Ok(())
     }
 
+    #[test_log::test]
+    fn set_card_profile() -> anyhow::Result<()> {
+        let client =
+            Client::from_env(random_client_name()).context("connecting to PulseAudio server")?;
+
+        let res = block_on(client.set_card_profile(999, CString::new("off")?));
+        assert!(matches!(
+            res,
+            Err(ClientError::ServerError(protocol::PulseError::NoEntity))
+        ));
+
+        for card in block_on(client.list_cards())? {
+            if let Some(profile) = card.active_profile.clone() {
+                block_on(client.set_card_profile_by_name(card.name.clone(), profile))?;
+            }
+        }
+
+        Ok(())
+    }
+
     #[test_log::test]
     fn module_info() -> anyhow::Result<()> {
         let client =
@@ -706,6 +1733,28 @@ mod tests {
         Ok(())
     }
 
+    #[test_log::test]
+    fn load_module() -> anyhow::Result<()> {
+        let client =
+            Client::from_env(random_client_name()).context("connecting to PulseAudio server")?;
+
+        let args = protocol::ModuleArgs::new()
+            .with("sink_name", "pulseaudio_rs_test_load_module")
+            .with("sink_properties", "device.description='Test Sink'");
+        let index = block_on(client.load_module(c"module-null-sink".to_owned(), &args))?;
+
+        let module_info = block_on(client.module_info(index))?;
+        assert_eq!(module_info.parse_arguments()?, args);
+
+        block_on(client.unload_module(index))?;
+        assert!(matches!(
+            block_on(client.module_info(index)),
+            Err(ClientError::ServerError(protocol::PulseError::NoEntity))
+        ));
+
+        Ok(())
+    }
+
     #[test_log::test]
     fn stat() -> anyhow::Result<()> {
         let client =
@@ -744,6 +1793,597 @@ mod tests {
         Ok(())
     }
 
+    #[test_log::test]
+    fn upload_sample() -> anyhow::Result<()> {
+        use futures::io::AllowStdIo;
+
+        let client =
+            Client::from_env(random_client_name()).context("connecting to PulseAudio server")?;
+
+        let name = c"pulseaudio_rs_test_upload_sample";
+        let sample_spec = protocol::SampleSpec {
+            format: protocol::SampleFormat::S16Le,
+            channels: 2,
+            sample_rate: 44100,
+        };
+
+        // A tenth of a second of silence.
+        let data = vec![0; 44100 * 4 / 10];
+        block_on(client.upload_sample(
+            name.to_owned(),
+            sample_spec,
+            protocol::ChannelMap::stereo(),
+            protocol::Props::new(),
+            AllowStdIo::new(io::Cursor::new(data)),
+        ))?;
+
+        let sample = block_on(client.list_samples())?
+            .into_iter()
+            .find(|sample| sample.name.as_c_str() == name)
+            .ok_or(anyhow!("sample not found"))?;
+        assert_eq!(sample.sample_spec, sample_spec);
+
+        block_on(client.play_sample(
+            name.to_owned(),
+            protocol::DEFAULT_SINK.to_owned(),
+            None,
+            protocol::Props::new(),
+        ))?;
+
+        block_on(client.remove_sample(name.to_owned()))?;
+        let res = block_on(client.play_sample(
+            name.to_owned(),
+            protocol::DEFAULT_SINK.to_owned(),
+            None,
+            protocol::Props::new(),
+        ));
+        assert!(matches!(
+            res,
+            Err(ClientError::ServerError(protocol::PulseError::NoEntity))
+        ));
+
+        Ok(())
+    }
+
+    #[test_log::test]
+    fn set_sink_volume() -> anyhow::Result<()> {
+        let client =
+            Client::from_env(random_client_name()).context("connecting to PulseAudio server")?;
+
+        let sink_list = block_on(client.list_sinks())?;
+        assert!(!sink_list.is_empty());
+
+        let sink = &sink_list[0];
+        let mut volume = protocol::ChannelVolume::empty();
+        for _ in 0..sink.cvolume.channels().len() {
+            volume.push(protocol::Volume::from_linear(0.5));
+        }
+
+        block_on(client.set_sink_volume(sink.index, volume))?;
+        let sink_info = block_on(client.sink_info(sink.index))?;
+        assert_eq!(volume, sink_info.cvolume);
+
+        block_on(client.set_sink_volume(sink.index, sink.cvolume))?;
+        Ok(())
+    }
+
+    #[test_log::test]
+    fn set_sink_mute_by_name() -> anyhow::Result<()> {
+        let client =
+            Client::from_env(random_client_name()).context("connecting to PulseAudio server")?;
+
+        let default_sink = block_on(client.sink_info_by_name(protocol::DEFAULT_SINK.to_owned()))?;
+
+        block_on(client.set_sink_mute_by_name(protocol::DEFAULT_SINK.to_owned(), true))?;
+        assert!(block_on(client.sink_info(default_sink.index))?.muted);
+
+        block_on(
+            client.set_sink_mute_by_name(protocol::DEFAULT_SINK.to_owned(), default_sink.muted),
+        )?;
+        Ok(())
+    }
+
+    #[test_log::test]
+    fn set_sink_input_volume() -> anyhow::Result<()> {
+        let client =
+            Client::from_env(random_client_name()).context("connecting to PulseAudio server")?;
+
+        let res = block_on(client.set_sink_input_volume(999, protocol::ChannelVolume::norm(2)));
+        assert!(matches!(
+            res,
+            Err(ClientError::ServerError(protocol::PulseError::NoEntity))
+        ));
+
+        let res = block_on(client.set_source_output_mute(999, true));
+        assert!(matches!(
+            res,
+            Err(ClientError::ServerError(protocol::PulseError::NoEntity))
+        ));
+
+        Ok(())
+    }
+
+    #[test_log::test]
+    fn move_sink_input() -> anyhow::Result<()> {
+        let client =
+            Client::from_env(random_client_name()).context("connecting to PulseAudio server")?;
+
+        let res = block_on(client.move_sink_input(999, 999));
+        assert!(matches!(
+            res,
+            Err(ClientError::ServerError(protocol::PulseError::NoEntity))
+        ));
+
+        let params = protocol::PlaybackStreamParams {
+            sample_spec: protocol::SampleSpec {
+                format: protocol::SampleFormat::S16Le,
+                channels: 2,
+                sample_rate: 44100,
+            },
+            channel_map: protocol::ChannelMap::stereo(),
+            ..Default::default()
+        };
+
+        let stream = block_on(
+            client
+                .create_playback_stream(params, (|buf: &mut [u8]| buf.len()).as_playback_source()),
+        )?;
+
+        let default_sink = block_on(client.lookup_sink_by_name(protocol::DEFAULT_SINK.to_owned()))?;
+        block_on(stream.move_to_by_name(protocol::DEFAULT_SINK.to_owned()))?;
+        assert_eq!(stream.sink(), default_sink);
+
+        block_on(stream.move_to(default_sink))?;
+        assert_eq!(stream.sink(), default_sink);
+
+        Ok(())
+    }
+
+    #[test_log::test]
+    fn playback_stream_events() -> anyhow::Result<()> {
+        use futures::StreamExt as _;
+
+        let client =
+            Client::from_env(random_client_name()).context("connecting to PulseAudio server")?;
+
+        let sink_name = c"pulseaudio_rs_test_stream_events";
+        let args = protocol::ModuleArgs::new().with("sink_name", sink_name.to_str()?);
+        let module = block_on(client.load_module(c"module-null-sink".to_owned(), &args))?;
+        let sink_index = block_on(client.lookup_sink_by_name(sink_name.to_owned()))?;
+
+        let params = protocol::PlaybackStreamParams {
+            sample_spec: protocol::SampleSpec {
+                format: protocol::SampleFormat::S16Le,
+                channels: 2,
+                sample_rate: 44100,
+            },
+            channel_map: protocol::ChannelMap::stereo(),
+            ..Default::default()
+        };
+
+        let stream = block_on(
+            client
+                .create_playback_stream(params, (|buf: &mut [u8]| buf.len()).as_playback_source()),
+        )?;
+
+        let mut events = stream.events();
+
+        // Move the stream from another "client", to simulate a mixer.
+        block_on(client.move_sink_input(stream.stream_index(), sink_index))?;
+        assert_eq!(stream.sink(), sink_index);
+
+        let event = block_on(events.next()).ok_or(anyhow!("event stream ended"))?;
+        assert!(matches!(
+            event,
+            StreamEvent::Moved { device_index, .. } if device_index == sink_index
+        ));
+
+        drop(stream);
+        block_on(client.unload_module(module))?;
+
+        Ok(())
+    }
+
+    #[test_log::test]
+    fn playback_stream_underflow() -> anyhow::Result<()> {
+        use futures::StreamExt as _;
+
+        let client =
+            Client::from_env(random_client_name()).context("connecting to PulseAudio server")?;
+
+        let params = protocol::PlaybackStreamParams {
+            sample_spec: protocol::SampleSpec {
+                format: protocol::SampleFormat::S16Le,
+                channels: 2,
+                sample_rate: 44100,
+            },
+            channel_map: protocol::ChannelMap::stereo(),
+            buffer_attr: protocol::stream::BufferAttr {
+                pre_buffering: 4096,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+
+        // Write a little bit of silence, and then nothing, so that the stream
+        // starts and then underflows.
+        let mut remaining = 8192;
+        let source = move |buf: &mut [u8]| {
+            let len = buf.len().min(remaining);
+            buf[..len].fill(0);
+            remaining -= len;
+            len
+        };
+
+        let stream = block_on(client.create_playback_stream(params, source.as_playback_source()))?;
+        let mut events = stream.events();
+
+        let event = block_on(events.next()).ok_or(anyhow!("event stream ended"))?;
+        assert!(matches!(event, StreamEvent::Underflow { .. }));
+        assert_eq!(stream.underflow_count(), 1);
+        assert_eq!(stream.overflow_count(), 0);
+
+        Ok(())
+    }
+
+    #[test_log::test]
+    fn playback_stream_killed() -> anyhow::Result<()> {
+        use futures::StreamExt as _;
+
+        let client =
+            Client::from_env(random_client_name()).context("connecting to PulseAudio server")?;
+
+        let params = protocol::PlaybackStreamParams {
+            sample_spec: protocol::SampleSpec {
+                format: protocol::SampleFormat::S16Le,
+                channels: 2,
+                sample_rate: 44100,
+            },
+            channel_map: protocol::ChannelMap::stereo(),
+            ..Default::default()
+        };
+
+        let stream = block_on(
+            client
+                .create_playback_stream(params, (|buf: &mut [u8]| buf.len()).as_playback_source()),
+        )?;
+
+        let mut events = stream.events();
+        block_on(client.kill_sink_input(stream.stream_index()))?;
+
+        assert!(block_on(events.next()).is_none());
+        assert!(matches!(
+            block_on(stream.drain()),
+            Err(ClientError::StreamKilled)
+        ));
+        assert!(matches!(
+            block_on(stream.play_all()),
+            Err(ClientError::StreamKilled)
+        ));
+
+        // The client should still work.
+        block_on(client.server_info())?;
+
+        Ok(())
+    }
+
+    #[test_log::test]
+    fn playback_stream_set_buffer_attr() -> anyhow::Result<()> {
+        let client =
+            Client::from_env(random_client_name()).context("connecting to PulseAudio server")?;
+
+        let params = protocol::PlaybackStreamParams {
+            sample_spec: protocol::SampleSpec {
+                format: protocol::SampleFormat::S16Le,
+                channels: 2,
+                sample_rate: 44100,
+            },
+            channel_map: protocol::ChannelMap::stereo(),
+            ..Default::default()
+        };
+
+        let stream = block_on(
+            client
+                .create_playback_stream(params, (|buf: &mut [u8]| buf.len()).as_playback_source()),
+        )?;
+
+        // About 50ms.
+        let target_length = 44100 * 4 / 20;
+        let granted = block_on(stream.set_buffer_attr(
+            protocol::stream::BufferAttr {
+                target_length,
+                ..Default::default()
+            },
+            true,
+            false,
+        ))?;
+
+        assert_ne!(granted.target_length, u32::MAX);
+        assert_eq!(stream.buffer_attr(), granted);
+
+        Ok(())
+    }
+
+    #[test_log::test]
+    fn playback_stream_update_sample_rate() -> anyhow::Result<()> {
+        let client =
+            Client::from_env(random_client_name()).context("connecting to PulseAudio server")?;
+
+        let params = protocol::PlaybackStreamParams {
+            sample_spec: protocol::SampleSpec {
+                format: protocol::SampleFormat::S16Le,
+                channels: 2,
+                sample_rate: 44100,
+            },
+            channel_map: protocol::ChannelMap::stereo(),
+            flags: protocol::stream::StreamFlags {
+                variable_rate: true,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+
+        let stream = block_on(
+            client
+                .create_playback_stream(params, (|buf: &mut [u8]| buf.len()).as_playback_source()),
+        )?;
+
+        block_on(stream.update_sample_rate(44101))?;
+        assert_eq!(stream.sample_spec().sample_rate, 44101);
+
+        let res = block_on(stream.update_sample_rate(protocol::sample_spec::MAX_RATE + 1));
+        assert!(matches!(res, Err(ClientError::Protocol(_))));
+        assert_eq!(stream.sample_spec().sample_rate, 44101);
+
+        Ok(())
+    }
+
+    #[test_log::test]
+    fn update_client_props() -> anyhow::Result<()> {
+        let client_name = random_client_name();
+        let client =
+            Client::from_env(client_name.clone()).context("connecting to PulseAudio server")?;
+
+        let mut props = protocol::Props::new();
+        props.set(protocol::Prop::ApplicationId, c"org.pulseaudio-rs.test");
+        block_on(client.update_props(protocol::props::PropsUpdateMode::Replace, props))?;
+
+        let find_self = || -> anyhow::Result<protocol::ClientInfo> {
+            block_on(client.list_clients())?
+                .into_iter()
+                .find(|info| info.name == client_name)
+                .ok_or(anyhow!("no client with matching name"))
+        };
+
+        let info = find_self()?;
+        assert_eq!(
+            info.props.get(protocol::Prop::ApplicationId),
+            Some(&b"org.pulseaudio-rs.test\0"[..])
+        );
+
+        block_on(client.remove_props(vec![protocol::Prop::ApplicationId.to_c_str().to_owned()]))?;
+
+        let info = find_self()?;
+        assert_eq!(info.props.get(protocol::Prop::ApplicationId), None);
+
+        Ok(())
+    }
+
+    #[test_log::test]
+    fn playback_stream_update_props() -> anyhow::Result<()> {
+        let client =
+            Client::from_env(random_client_name()).context("connecting to PulseAudio server")?;
+
+        let params = protocol::PlaybackStreamParams {
+            sample_spec: protocol::SampleSpec {
+                format: protocol::SampleFormat::S16Le,
+                channels: 2,
+                sample_rate: 44100,
+            },
+            channel_map: protocol::ChannelMap::stereo(),
+            ..Default::default()
+        };
+
+        let stream = block_on(
+            client
+                .create_playback_stream(params, (|buf: &mut [u8]| buf.len()).as_playback_source()),
+        )?;
+
+        let mut props = protocol::Props::new();
+        props.set(protocol::Prop::MediaTitle, c"Track 1");
+        props.set(protocol::Prop::MediaArtist, c"Artist");
+        block_on(stream.update_props(protocol::props::PropsUpdateMode::Replace, props))?;
+
+        let info = block_on(client.sink_input_info(stream.stream_index()))?;
+        assert_eq!(
+            info.props.get(protocol::Prop::MediaTitle),
+            Some(&b"Track 1\0"[..])
+        );
+
+        block_on(stream.remove_props(vec![protocol::Prop::MediaArtist.to_c_str().to_owned()]))?;
+
+        let info = block_on(client.sink_input_info(stream.stream_index()))?;
+        assert_eq!(info.props.get(protocol::Prop::MediaArtist), None);
+
+        Ok(())
+    }
+
+    #[test_log::test]
+    fn subscribe() -> anyhow::Result<()> {
+        use futures::StreamExt as _;
+
+        let client =
+            Client::from_env(random_client_name()).context("connecting to PulseAudio server")?;
+
+        let mut clients = block_on(client.subscribe(protocol::SubscriptionMask::CLIENT))?;
+        let sinks = block_on(client.subscribe(protocol::SubscriptionMask::SINK))?;
+        drop(sinks);
+        let mut all = block_on(client.subscribe(protocol::SubscriptionMask::ALL))?;
+
+        // Connecting a second client should generate an event.
+        let _client2 = Client::from_env(random_client_name())?;
+
+        for sub in [&mut clients, &mut all] {
+            let event = block_on(sub.next()).ok_or(anyhow!("subscription ended"))?;
+            assert_eq!(
+                event.event_facility,
+                protocol::SubscriptionEventFacility::Client
+            );
+            assert_eq!(event.event_type, protocol::SubscriptionEventType::New);
+        }
+
+        Ok(())
+    }
+
+    #[test_log::test]
+    fn server_state() -> anyhow::Result<()> {
+        use futures::StreamExt as _;
+
+        let client =
+            Client::from_env(random_client_name()).context("connecting to PulseAudio server")?;
+
+        let mut state = block_on(ServerState::new(client.clone()))?;
+        assert!(!state.clients().is_empty());
+        assert!(!state.modules().is_empty());
+
+        let sink_name = c"pulseaudio_rs_test_server_state";
+        let args = protocol::ModuleArgs::new().with("sink_name", sink_name.to_str()?);
+        let module = block_on(client.load_module(c"module-null-sink".to_owned(), &args))?;
+        let sink_index = block_on(client.lookup_sink_by_name(sink_name.to_owned()))?;
+
+        while !state.sinks().contains_key(&sink_index) {
+            let change = block_on(state.next()).ok_or(anyhow!("state ended"))??;
+            if change.facility == protocol::SubscriptionEventFacility::Sink
+                && change.index == Some(sink_index)
+            {
+                assert_eq!(change.old, None);
+                assert!(matches!(change.new, Some(ServerObject::Sink(_))));
+            }
+        }
+
+        assert_eq!(state.sinks()[&sink_index].name.as_c_str(), sink_name);
+
+        // The server announces the module once it's done loading, which is
+        // after the sink appears.
+        while !state.modules().contains_key(&module) {
+            block_on(state.next()).ok_or(anyhow!("state ended"))??;
+        }
+
+        block_on(client.unload_module(module))?;
+        while state.sinks().contains_key(&sink_index) {
+            let change = block_on(state.next()).ok_or(anyhow!("state ended"))??;
+            if change.facility == protocol::SubscriptionEventFacility::Sink
+                && change.index == Some(sink_index)
+            {
+                assert!(matches!(change.old, Some(ServerObject::Sink(_))));
+                assert_eq!(change.new, None);
+            }
+        }
+
+        Ok(())
+    }
+
+    #[test_log::test]
+    fn watch_default_sink() -> anyhow::Result<()> {
+        use futures::StreamExt as _;
+
+        let client =
+            Client::from_env(random_client_name()).context("connecting to PulseAudio server")?;
+
+        let sink_name = c"pulseaudio_rs_test_watch_default_sink";
+        let args = protocol::ModuleArgs::new().with("sink_name", sink_name.to_str()?);
+        let module = block_on(client.load_module(c"module-null-sink".to_owned(), &args))?;
+        let sink_index = block_on(client.lookup_sink_by_name(sink_name.to_owned()))?;
+
+        let original = block_on(client.server_info())?.default_sink_name;
+        let mut watch = block_on(client.watch_default_sink())?;
+
+        block_on(client.set_default_sink(sink_name.to_owned()))?;
+        loop {
+            let sink = block_on(watch.next()).ok_or(anyhow!("watch ended"))??;
+            if sink.is_some_and(|sink| sink.index == sink_index) {
+                break;
+            }
+        }
+
+        // Changing the default sink's own properties should also be reported.
+        block_on(client.set_sink_mute(sink_index, true))?;
+        let sink = block_on(watch.next())
+            .ok_or(anyhow!("watch ended"))??
+            .ok_or(anyhow!("no default sink"))?;
+        assert_eq!(sink.index, sink_index);
+        assert!(sink.muted);
+
+        if let Some(original) = original {
+            block_on(client.set_default_sink(original))?;
+        }
+
+        block_on(client.unload_module(module))?;
+        Ok(())
+    }
+
+    #[test_log::test]
+    fn send_message() -> anyhow::Result<()> {
+        let client =
+            Client::from_env(random_client_name()).context("connecting to PulseAudio server")?;
+
+        let response = block_on(client.send_message(
+            c"/core".to_owned(),
+            c"list-handlers".to_owned(),
+            &protocol::MessageParams::new(),
+        ))?
+        .ok_or(anyhow!("no response"))?;
+
+        let handlers: protocol::MessageParams = response.to_str()?.parse()?;
+        assert!(
+            handlers
+                .iter()
+                .any(|h| h.as_list().and_then(|h| h.first()?.as_str()) == Some("/core"))
+        );
+
+        Ok(())
+    }
+
+    #[test_log::test]
+    fn stream_restore_entries() -> anyhow::Result<()> {
+        let client =
+            Client::from_env(random_client_name()).context("connecting to PulseAudio server")?;
+
+        let name = c"sink-input-by-media-role:pulseaudio-rs-test";
+        let entry = protocol::StreamRestoreEntry {
+            name: name.to_owned(),
+            channel_map: protocol::ChannelMap::stereo(),
+            volume: Some(protocol::ChannelVolume::norm(2)),
+            device: None,
+            muted: true,
+        };
+
+        block_on(client.write_stream_restore_entries(
+            vec![entry.clone()],
+            protocol::props::PropsUpdateMode::Replace,
+            false,
+        ))?;
+
+        let entries = block_on(client.stream_restore_entries())?;
+        let stored = entries
+            .iter()
+            .find(|e| e.name.as_c_str() == name)
+            .ok_or(anyhow!("entry not stored"))?;
+        assert_eq!(stored.channel_map, entry.channel_map);
+        assert_eq!(
+            stored.volume.as_ref().map(|v| v.channels()),
+            entry.volume.as_ref().map(|v| v.channels())
+        );
+        assert!(stored.muted);
+
+        block_on(client.delete_stream_restore_entries(vec![name.to_owned()]))?;
+
+        let entries = block_on(client.stream_restore_entries())?;
+        assert!(!entries.iter().any(|e| e.name.as_c_str() == name));
+
+        Ok(())
+    }
+
     #[test_log::test]
     fn kill_client() -> anyhow::Result<()> {
         let client_name1 = random_client_name();
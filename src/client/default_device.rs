@@ -0,0 +1,163 @@
+use std::{
+    pin::Pin,
+    task::{Context, Poll, ready},
+};
+
+use futures::{FutureExt as _, Stream, StreamExt as _, future::BoxFuture};
+
+use super::{Client, ClientError, Result as ClientResult, Subscription};
+use crate::protocol;
+
+type Fetch<T> = fn(Client) -> BoxFuture<'static, ClientResult<Option<T>>>;
+
+/// A [Stream] that yields the default sink or source of the server, created
+/// with [Client::watch_default_sink](super::Client::watch_default_sink) or
+/// [Client::watch_default_source](super::Client::watch_default_source).
+///
+/// The current default device is yielded first, and then again whenever the
+/// default changes or the default device itself changes, for example because
+/// its volume was adjusted. `None` is yielded if there is no default device,
+/// for example because it was removed. The stream ends if the client
+/// disconnects.
+pub struct DefaultDeviceStream<T> {
+    client: Client,
+    subscription: Subscription,
+    facility: protocol::SubscriptionEventFacility,
+    fetch: Fetch<T>,
+    index: fn(&T) -> u32,
+    pending: Option<BoxFuture<'static, ClientResult<Option<T>>>>,
+    current: Option<T>,
+    // Whether the initial value has been yielded.
+    started: bool,
+}
+
+impl<T: std::fmt::Debug> std::fmt::Debug for DefaultDeviceStream<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("DefaultDeviceStream")
+            .field("facility", &self.facility)
+            .field("current", &self.current)
+            .finish_non_exhaustive()
+    }
+}
+
+impl DefaultDeviceStream<protocol::SinkInfo> {
+    pub(super) async fn sink(client: Client) -> ClientResult<Self> {
+        Self::new(
+            client,
+            protocol::SubscriptionEventFacility::Sink,
+            |client| {
+                async move {
+                    not_found_to_none(
+                        client
+                            .sink_info_by_name(protocol::DEFAULT_SINK.to_owned())
+                            .await,
+                    )
+                }
+                .boxed()
+            },
+            |info| info.index,
+        )
+        .await
+    }
+}
+
+impl DefaultDeviceStream<protocol::SourceInfo> {
+    pub(super) async fn source(client: Client) -> ClientResult<Self> {
+        Self::new(
+            client,
+            protocol::SubscriptionEventFacility::Source,
+            |client| {
+                async move {
+                    not_found_to_none(
+                        client
+                            .source_info_by_name(protocol::DEFAULT_SOURCE.to_owned())
+                            .await,
+                    )
+                }
+                .boxed()
+            },
+            |info| info.index,
+        )
+        .await
+    }
+}
+
+impl<T> DefaultDeviceStream<T> {
+    async fn new(
+        client: Client,
+        facility: protocol::SubscriptionEventFacility,
+        fetch: Fetch<T>,
+        index: fn(&T) -> u32,
+    ) -> ClientResult<Self> {
+        let mask = protocol::SubscriptionEventFacility::Server.mask() | facility.mask();
+        let subscription = client.subscribe(mask).await?;
+
+        // Subscribe before fetching the initial value, so that no changes
+        // are missed.
+        let pending = Some(fetch(client.clone()));
+
+        Ok(Self {
+            client,
+            subscription,
+            facility,
+            fetch,
+            index,
+            pending,
+            current: None,
+            started: false,
+        })
+    }
+
+    /// Whether an event could change the default device or its info.
+    fn is_relevant(&self, event: &protocol::SubscriptionEvent) -> bool {
+        if event.event_facility == protocol::SubscriptionEventFacility::Server {
+            return true;
+        } else if event.event_facility != self.facility {
+            return false;
+        }
+
+        match &self.current {
+            Some(current) => event.index == Some((self.index)(current)),
+            None => event.event_type == protocol::SubscriptionEventType::New,
+        }
+    }
+}
+
+fn not_found_to_none<T>(res: ClientResult<T>) -> ClientResult<Option<T>> {
+    match res {
+        Ok(v) => Ok(Some(v)),
+        Err(ClientError::ServerError(protocol::PulseError::NoEntity)) => Ok(None),
+        Err(err) => Err(err),
+    }
+}
+
+impl<T: Clone + PartialEq + Unpin> Stream for DefaultDeviceStream<T> {
+    type Item = ClientResult<Option<T>>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        loop {
+            if let Some(pending) = &mut self.pending {
+                let res = ready!(pending.poll_unpin(cx));
+                self.pending = None;
+
+                match res {
+                    Ok(info) if !self.started || self.current != info => {
+                        self.started = true;
+                        self.current = info.clone();
+                        return Poll::Ready(Some(Ok(info)));
+                    }
+                    Ok(_) => (),
+                    Err(err) => return Poll::Ready(Some(Err(err))),
+                }
+            }
+
+            let Some(event) = ready!(self.subscription.poll_next_unpin(cx)) else {
+                return Poll::Ready(None);
+            };
+
+            if self.is_relevant(&event) {
+                self.pending = Some((self.fetch)(self.client.clone()));
+            }
+        }
+    }
+}
@@ -6,26 +6,32 @@ use futures::channel::oneshot;
 use futures::FutureExt as _;
 
 use super::reactor::ReactorHandle;
-use super::{ClientError, PlaybackSource, Result as ClientResult};
+use super::stream_events::SharedStreamState;
+use super::{ClientError, PlaybackSource, Result as ClientResult, StreamEvents};
 use crate::protocol;
 
 /// A stream of audio data sent from the client to the server for playback in
 /// a sink.
 ///
 /// The stream handle can be freely cloned and shared between threads.
+///
+/// If the server kills the stream, for example because its device was
+/// removed, any pending or future operations fail with
+/// [ClientError::StreamKilled].
 #[derive(Clone)]
 pub struct PlaybackStream(Arc<InnerPlaybackStream>);
 
 struct InnerPlaybackStream {
     handle: ReactorHandle,
     info: protocol::CreatePlaybackStreamReply,
+    state: SharedStreamState,
     eof_notify: futures::future::Shared<oneshot::Receiver<()>>,
 }
 
 impl std::fmt::Debug for PlaybackStream {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.debug_tuple("PlaybackStream")
-            .field(&self.0.info.channel)
+            .field(&self.0.channel())
             .finish()
     }
 }
@@ -37,31 +43,41 @@ impl PlaybackStream {
         source: impl PlaybackSource,
     ) -> Result<Self, ClientError> {
         let (eof_tx, eof_rx) = oneshot::channel();
-        let info = handle
+        let (info, state) = handle
             .insert_playback_stream(params, source, Some(eof_tx))
             .await?;
 
         Ok(Self(Arc::new(InnerPlaybackStream {
             handle,
+            state,
             info,
             eof_notify: eof_rx.shared(),
         })))
     }
 
-    /// The ID of the stream.
+    /// The ID of the stream. This can change if the client reconnects (see
+    /// [Client::connect_reconnecting](super::Client::connect_reconnecting)).
     pub fn channel(&self) -> u32 {
-        self.0.info.channel
+        self.0.channel()
+    }
+
+    /// The server-side index of the stream, which is the index of the
+    /// corresponding sink input (see [Client::sink_input_info](super::Client::sink_input_info)).
+    pub fn stream_index(&self) -> u32 {
+        self.0.stream_index()
     }
 
-    /// The attributes of the server-side buffer.
-    pub fn buffer_attr(&self) -> &protocol::stream::BufferAttr {
-        &self.0.info.buffer_attr
+    /// The attributes of the server-side buffer. These can be changed by the
+    /// server, for example when the stream is moved to a different device.
+    pub fn buffer_attr(&self) -> protocol::stream::BufferAttr {
+        self.0.state.lock().unwrap().buffer_attr
     }
 
     /// The sample specification for the stream. Can differ from the client's
-    /// requested sample spec.
-    pub fn sample_spec(&self) -> &protocol::SampleSpec {
-        &self.0.info.sample_spec
+    /// requested sample spec, and the sample rate can be changed with
+    /// [update_sample_rate](Self::update_sample_rate).
+    pub fn sample_spec(&self) -> protocol::SampleSpec {
+        self.0.state.lock().unwrap().sample_spec
     }
 
     /// The channel map for the stream.
@@ -71,16 +87,69 @@ impl PlaybackStream {
 
     /// The sink the stream is connected to.
     pub fn sink(&self) -> u32 {
-        self.0.info.sink_index
+        self.0.state.lock().unwrap().device_index
+    }
+
+    /// Whether the sink the stream is connected to is suspended.
+    pub fn is_suspended(&self) -> bool {
+        self.0.state.lock().unwrap().suspended
+    }
+
+    /// The number of times the server has reported an underflow (the buffer
+    /// running empty) since the stream was created.
+    pub fn underflow_count(&self) -> u64 {
+        self.0.state.lock().unwrap().underflows
+    }
+
+    /// The number of times the server has reported an overflow (more data
+    /// being written than fits in the buffer) since the stream was created.
+    pub fn overflow_count(&self) -> u64 {
+        self.0.state.lock().unwrap().overflows
+    }
+
+    /// Returns a [Stream](futures::Stream) of events for the stream, such as
+    /// the stream being moved to a different sink by the server, or
+    /// underflows and overflows. Only events that occur after this method is
+    /// called are delivered.
+    pub fn events(&self) -> StreamEvents {
+        self.0.handle.playback_stream_events(self.0.channel())
+    }
+
+    /// Moves the stream to a different sink, by the sink's index.
+    ///
+    /// The server notifies the stream of the move before acknowledging it, so
+    /// [sink](Self::sink) reports the new device as soon as this returns.
+    pub async fn move_to(&self, sink_index: u32) -> ClientResult<()> {
+        self.0
+            .roundtrip_ack(protocol::Command::MoveSinkInput(
+                protocol::MoveStreamParams {
+                    index: Some(self.0.stream_index()),
+                    device_index: Some(sink_index),
+                    device_name: None,
+                },
+            ))
+            .await
+    }
+
+    /// Moves the stream to a different sink, by the sink's name.
+    pub async fn move_to_by_name(&self, sink_name: CString) -> ClientResult<()> {
+        self.0
+            .roundtrip_ack(protocol::Command::MoveSinkInput(
+                protocol::MoveStreamParams {
+                    index: Some(self.0.stream_index()),
+                    device_index: None,
+                    device_name: Some(sink_name),
+                },
+            ))
+            .await
     }
 
     /// Sets the name of the playback stream.
     pub async fn set_name(&self, name: CString) -> ClientResult<()> {
         self.0
-            .handle
             .roundtrip_ack(protocol::Command::SetPlaybackStreamName(
                 protocol::SetStreamNameParams {
-                    index: self.0.info.stream_index,
+                    index: self.0.stream_index(),
                     name,
                 },
             ))
@@ -90,10 +159,9 @@ impl PlaybackStream {
     /// Fetches playback timing information for the playback stream.
     pub async fn timing_info(&self) -> ClientResult<protocol::PlaybackLatency> {
         self.0
-            .handle
             .roundtrip_reply(protocol::Command::GetPlaybackLatency(
                 protocol::LatencyParams {
-                    channel: self.0.info.channel,
+                    channel: self.0.channel(),
                     now: time::SystemTime::now(),
                 },
             ))
@@ -103,10 +171,9 @@ impl PlaybackStream {
     /// Corks the playback stream (temporarily pausing playback).
     pub async fn cork(&self) -> ClientResult<()> {
         self.0
-            .handle
             .roundtrip_ack(protocol::Command::CorkPlaybackStream(
                 protocol::CorkStreamParams {
-                    channel: self.0.info.channel,
+                    channel: self.0.channel(),
                     cork: true,
                 },
             ))
@@ -116,23 +183,110 @@ impl PlaybackStream {
     /// Uncorks the playback stream.
     pub async fn uncork(&self) -> ClientResult<()> {
         self.0
-            .handle
             .roundtrip_ack(protocol::Command::CorkPlaybackStream(
                 protocol::CorkStreamParams {
-                    channel: self.0.info.channel,
+                    channel: self.0.channel(),
                     cork: false,
                 },
             ))
             .await
     }
 
+    /// Requests new attributes for the server-side buffer, for example to
+    /// switch between low-latency and power-saving modes. `fragment_size` is
+    /// ignored.
+    ///
+    /// `adjust_latency` and `early_requests` have the same meaning as the
+    /// corresponding [StreamFlags](protocol::stream::StreamFlags). Returns the
+    /// attributes that the server actually granted, which are also reflected
+    /// in [buffer_attr](Self::buffer_attr) afterwards.
+    pub async fn set_buffer_attr(
+        &self,
+        buffer_attr: protocol::stream::BufferAttr,
+        adjust_latency: bool,
+        early_requests: bool,
+    ) -> ClientResult<protocol::stream::BufferAttr> {
+        let reply: protocol::SetPlaybackStreamBufferAttrReply = self
+            .0
+            .roundtrip_reply(protocol::Command::SetPlaybackStreamBufferAttr(
+                protocol::SetPlaybackStreamBufferAttrParams {
+                    index: self.0.channel(),
+                    buffer_attr,
+                    adjust_latency,
+                    early_requests,
+                },
+            ))
+            .await?;
+
+        self.0.state.lock().unwrap().buffer_attr = reply.buffer_attr;
+        Ok(reply.buffer_attr)
+    }
+
+    /// Changes the sample rate of the stream, for example to compensate for
+    /// clock drift. The stream must have been created with the
+    /// `variable_rate` [flag](protocol::stream::StreamFlags).
+    ///
+    /// Returns an error without contacting the server if the rate is zero or
+    /// greater than [MAX_RATE](protocol::sample_spec::MAX_RATE).
+    pub async fn update_sample_rate(&self, sample_rate: u32) -> ClientResult<()> {
+        if sample_rate == 0 || sample_rate > protocol::sample_spec::MAX_RATE {
+            return Err(ClientError::Protocol(protocol::ProtocolError::Invalid(
+                format!("invalid sample rate: {sample_rate}"),
+            )));
+        }
+
+        self.0
+            .roundtrip_ack(protocol::Command::UpdatePlaybackStreamSampleRate(
+                protocol::UpdateSampleRateParams {
+                    index: self.0.channel(),
+                    sample_rate,
+                },
+            ))
+            .await?;
+
+        self.0.state.lock().unwrap().sample_spec.sample_rate = sample_rate;
+        Ok(())
+    }
+
+    /// Updates the properties of the stream, for example to set
+    /// [MediaTitle](protocol::Prop::MediaTitle) when the track changes.
+    pub async fn update_props(
+        &self,
+        mode: protocol::props::PropsUpdateMode,
+        props: protocol::Props,
+    ) -> ClientResult<()> {
+        self.0
+            .roundtrip_ack(protocol::Command::UpdatePlaybackStreamProplist(
+                protocol::UpdatePropsParams {
+                    index: self.0.channel(),
+                    mode,
+                    props,
+                },
+            ))
+            .await
+    }
+
+    /// Removes properties from the stream, by key.
+    pub async fn remove_props(&self, keys: Vec<CString>) -> ClientResult<()> {
+        self.0
+            .roundtrip_ack(protocol::Command::RemovePlaybackStreamProplist(
+                protocol::RemovePropsParams {
+                    index: self.0.channel(),
+                    keys,
+                },
+            ))
+            .await
+    }
+
     /// Returns a future that resolves when the stream's [AudioSource] has reached the end.
     pub async fn source_eof(&self) -> ClientResult<()> {
-        self.0
+        let res = self
+            .0
             .eof_notify
             .clone()
             .await
-            .map_err(|_| ClientError::Disconnected)
+            .map_err(|_| ClientError::Disconnected);
+        self.0.check_killed(res)
     }
 
     /// Waits until the given [AudioSource] has reached the end (and returns 0 in [AudioSource::poll_read]),
@@ -148,37 +302,84 @@ impl PlaybackStream {
     pub async fn drain(&self) -> ClientResult<()> {
         self.0
             .handle
-            .mark_playback_stream_draining(self.0.info.channel);
+            .mark_playback_stream_draining(self.0.channel());
         self.0
-            .handle
-            .roundtrip_ack(protocol::Command::DrainPlaybackStream(self.0.info.channel))
+            .roundtrip_ack(protocol::Command::DrainPlaybackStream(self.0.channel()))
             .await
     }
 
     /// Instructs the server to discard any buffered data.
     pub async fn flush(&self) -> super::Result<()> {
         self.0
-            .handle
-            .roundtrip_ack(protocol::Command::FlushPlaybackStream(self.0.info.channel))
+            .roundtrip_ack(protocol::Command::FlushPlaybackStream(self.0.channel()))
             .await
     }
 
     /// Deletes the stream from the server.
     pub async fn delete(self) -> ClientResult<()> {
-        self.0
-            .handle
-            .delete_playback_stream(self.0.info.channel)
-            .await
+        if self.0.killed() {
+            return Err(ClientError::StreamKilled);
+        }
+
+        self.0.handle.delete_playback_stream(self.0.channel()).await
+    }
+}
+
+impl InnerPlaybackStream {
+    fn channel(&self) -> u32 {
+        self.state.lock().unwrap().channel
+    }
+
+    fn stream_index(&self) -> u32 {
+        self.state.lock().unwrap().stream_index
+    }
+
+    fn killed(&self) -> bool {
+        self.state.lock().unwrap().killed
+    }
+
+    /// Replaces an error with [ClientError::StreamKilled] if the stream was
+    /// killed by the server in the meantime.
+    fn check_killed<T>(&self, res: ClientResult<T>) -> ClientResult<T> {
+        match res {
+            Err(_) if self.killed() => Err(ClientError::StreamKilled),
+            res => res,
+        }
+    }
+
+    async fn roundtrip_ack(&self, cmd: protocol::Command) -> ClientResult<()> {
+        if self.killed() {
+            return Err(ClientError::StreamKilled);
+        }
+
+        let res = self.handle.roundtrip_ack(cmd).await;
+        self.check_killed(res)
+    }
+
+    async fn roundtrip_reply<R: protocol::CommandReply + Send + 'static>(
+        &self,
+        cmd: protocol::Command,
+    ) -> ClientResult<R> {
+        if self.killed() {
+            return Err(ClientError::StreamKilled);
+        }
+
+        let res = self.handle.roundtrip_reply(cmd).await;
+        self.check_killed(res)
     }
 }
 
 impl Drop for InnerPlaybackStream {
     fn drop(&mut self) {
+        if self.killed() {
+            return;
+        }
+
         // Sends the delete command to the server, but doesn't wait for the
         // response.
         let _ = self
             .handle
-            .delete_playback_stream(self.info.channel)
+            .delete_playback_stream(self.channel())
             .now_or_never();
     }
 }
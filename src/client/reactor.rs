@@ -1,30 +1,53 @@
 use std::{
-    collections::BTreeMap,
+    collections::{BTreeMap, BTreeSet, VecDeque},
     io::{self},
+    os::fd::OwnedFd,
     pin::Pin,
     sync::{
         Arc, Mutex, Weak,
-        atomic::{self, AtomicU32},
+        atomic::{self, AtomicBool, AtomicU32},
         mpsc::{Receiver, Sender, TryRecvError},
     },
     task::{Context, Poll},
     thread::JoinHandle,
+    time::{Duration, Instant},
 };
 
-use futures::channel::oneshot;
-use mio::net::UnixStream;
+use futures::{
+    FutureExt as _,
+    channel::{mpsc, oneshot},
+    future::Shared,
+};
 
 use crate::protocol::{self, DescriptorFlags};
 
-use super::{ClientError, PlaybackSource, RecordSink};
+use super::{
+    ClientError, PlaybackSource, RecordSink, StreamEvent, StreamEvents,
+    shm::{ShmImports, ShmPool},
+    socket::Socket,
+    srbchannel::Srbchannel,
+    stream_events::{SharedStreamState, StreamState, StreamTracker},
+};
+
+type ReplyResult<'a> = Result<&'a mut dyn io::BufRead, ClientError>;
+type ReplyHandler = Box<dyn FnOnce(&mut ReactorState, ReplyResult<'_>) + Send + 'static>;
+type ExitResult = Result<(), Arc<ClientError>>;
+
+/// How long to wait for a reply to a command by default. This matches
+/// libpulse.
+pub(super) const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
 
-type ReplyResult<'a> =
-    Result<(&'a mut ReactorState, &'a mut dyn io::BufRead), protocol::PulseError>;
-type ReplyHandler = Box<dyn FnOnce(ReplyResult<'_>) + Send + 'static>;
+struct PendingReply {
+    handler: ReplyHandler,
+    deadline: Option<Instant>,
+}
 
 struct PlaybackStreamState {
+    // Kept to recreate the stream after reconnecting.
+    params: protocol::PlaybackStreamParams,
     stream_info: protocol::CreatePlaybackStreamReply,
     source: Pin<Box<dyn PlaybackSource>>,
+    tracker: StreamTracker,
 
     requested_bytes: usize,
     done: bool,
@@ -32,21 +55,129 @@ struct PlaybackStreamState {
 }
 
 pub(super) struct RecordStreamState {
+    params: protocol::RecordStreamParams,
     sink: Box<dyn RecordSink>,
     start_notify: Option<oneshot::Sender<()>>,
+    tracker: StreamTracker,
+}
+
+struct SubscriberState {
+    mask: protocol::SubscriptionMask,
+    events: mpsc::UnboundedSender<protocol::SubscriptionEvent>,
+}
+
+/// A message queued for the reactor to write to the socket.
+enum Outgoing {
+    Command(u32, Box<protocol::Command>),
+    Memblock(u32, Vec<u8>),
+    /// Stops the reactor once everything queued before it has been written.
+    Close,
 }
 
 #[derive(Default)]
 struct ReactorState {
-    handlers: BTreeMap<u32, ReplyHandler>,
+    // Replies are decoded with the version negotiated for the current
+    // connection.
+    protocol_version: u16,
+    handlers: BTreeMap<u32, PendingReply>,
+    // Deadlines for pending replies, ordered by expiry.
+    deadlines: BTreeSet<(Instant, u32)>,
     playback_streams: BTreeMap<u32, PlaybackStreamState>,
     record_streams: BTreeMap<u32, RecordStreamState>,
+    // Streams whose connection was lost while they were being recreated.
+    lost_playback_streams: Vec<PlaybackStreamState>,
+    lost_record_streams: Vec<RecordStreamState>,
+    subscribers: BTreeMap<u64, SubscriberState>,
+    stream_restore_subscribers: BTreeMap<u64, mpsc::UnboundedSender<()>>,
+    next_subscriber_id: u64,
+    reconnect_listeners: Vec<mpsc::UnboundedSender<()>>,
+    // Commands sent by reply handlers, which don't wait for a reply.
+    queued_commands: Vec<protocol::Command>,
+    // The number of streams and subscriptions that couldn't be restored
+    // after the last reconnect.
+    restore_failures: usize,
+}
+
+impl ReactorState {
+    fn subscription_mask(&self) -> protocol::SubscriptionMask {
+        self.subscribers
+            .values()
+            .fold(protocol::SubscriptionMask::empty(), |acc, s| acc | s.mask)
+    }
+
+    fn subscribe_command(&self) -> protocol::Command {
+        protocol::Command::Subscribe(self.subscription_mask())
+    }
+
+    fn stream_restore_subscribe_command(&self) -> protocol::Command {
+        protocol::Command::Extension(protocol::ExtensionParams::stream_restore(
+            protocol::StreamRestoreCommand::Subscribe(!self.stream_restore_subscribers.is_empty()),
+        ))
+    }
+
+    fn insert_handler(&mut self, seq: u32, handler: ReplyHandler, timeout: Option<Duration>) {
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        if let Some(deadline) = deadline {
+            self.deadlines.insert((deadline, seq));
+        }
+
+        self.handlers
+            .insert(seq, PendingReply { handler, deadline });
+    }
+
+    fn remove_handler(&mut self, seq: u32) -> Option<ReplyHandler> {
+        let pending = self.handlers.remove(&seq)?;
+        if let Some(deadline) = pending.deadline {
+            self.deadlines.remove(&(deadline, seq));
+        }
+
+        Some(pending.handler)
+    }
+
+    fn take_handlers(&mut self) -> Vec<ReplyHandler> {
+        self.deadlines.clear();
+        std::mem::take(&mut self.handlers)
+            .into_values()
+            .map(|pending| pending.handler)
+            .collect()
+    }
+
+    /// Queues a command to be sent by the reactor, ignoring the reply. This is
+    /// how reply handlers send commands, since they run on the reactor thread.
+    fn queue_command(&mut self, cmd: protocol::Command) {
+        self.queued_commands.push(cmd);
+    }
+
+    fn notify_reconnected(&mut self) {
+        self.reconnect_listeners
+            .retain(|tx| tx.unbounded_send(()).is_ok());
+    }
+
+    fn next_deadline(&self) -> Option<Instant> {
+        self.deadlines.first().map(|(deadline, _)| *deadline)
+    }
+
+    /// Removes and returns the handlers whose deadline has passed.
+    fn take_expired(&mut self, now: Instant) -> Vec<(u32, ReplyHandler)> {
+        let mut expired = Vec::new();
+        while let Some(&(deadline, seq)) = self.deadlines.first()
+            && deadline <= now
+        {
+            self.deadlines.pop_first();
+            if let Some(pending) = self.handlers.remove(&seq) {
+                expired.push((seq, pending.handler));
+            }
+        }
+
+        expired
+    }
 }
 
 struct SharedState {
-    protocol_version: u16,
-    next_seq: AtomicU32,
-    _thread_handle: JoinHandle<super::Result<()>>,
+    next_seq: Arc<AtomicU32>,
+    closing: AtomicBool,
+    thread_handle: Mutex<Option<JoinHandle<()>>>,
+    exit: Shared<oneshot::Receiver<ExitResult>>,
 }
 
 // We need to wrap this to implement futures::task::ArcWake.
@@ -62,8 +193,47 @@ impl futures::task::ArcWake for Waker {
 pub(super) struct ReactorHandle {
     state: Weak<Mutex<ReactorState>>,
     shared: Arc<SharedState>,
-    outgoing: Sender<(u32, protocol::Command)>,
+    outgoing: Sender<Outgoing>,
     waker: Arc<Waker>,
+    timeout: Option<Duration>,
+}
+
+/// Removes the handler for a command when dropped, so that the handler
+/// doesn't outlive a cancelled future waiting for the reply.
+struct HandlerGuard<'a> {
+    handle: &'a ReactorHandle,
+    seq: u32,
+}
+
+impl Drop for HandlerGuard<'_> {
+    fn drop(&mut self) {
+        if let Some(state) = self.handle.state.upgrade() {
+            state.lock().unwrap().remove_handler(self.seq);
+        }
+    }
+}
+
+/// Removes a subscriber when dropped, so that a cancelled subscription
+/// request doesn't leave it behind.
+struct SubscriberGuard<'a> {
+    handle: &'a ReactorHandle,
+    id: u64,
+    remove: fn(&ReactorHandle, u64),
+}
+
+impl SubscriberGuard<'_> {
+    /// Keeps the subscriber, returning its ID.
+    fn keep(self) -> u64 {
+        let id = self.id;
+        std::mem::forget(self);
+        id
+    }
+}
+
+impl Drop for SubscriberGuard<'_> {
+    fn drop(&mut self) {
+        (self.remove)(self.handle, self.id);
+    }
 }
 
 impl ReactorHandle {
@@ -75,12 +245,8 @@ impl ReactorHandle {
 
         // Install a handler for the sequence number.
         let (tx, rx) = oneshot::channel();
-        let protocol_version = self.shared.protocol_version;
-        self.install_handler(seq, move |res: ReplyResult<'_>| {
-            let _ = match res {
-                Ok((_, buf)) => tx.send(read_tagstruct(buf, protocol_version)),
-                Err(err) => tx.send(Err(ClientError::ServerError(err))),
-            };
+        let _guard = self.install_handler(seq, move |state, res| {
+            let _ = tx.send(res.and_then(|buf| read_tagstruct(buf, state.protocol_version)));
         })?;
 
         // Send the message.
@@ -95,11 +261,8 @@ impl ReactorHandle {
 
         // Install a handler for the sequence number.
         let (tx, rx) = oneshot::channel();
-        self.install_handler(seq, move |res: ReplyResult<'_>| {
-            let _ = match res {
-                Ok(_) => tx.send(Ok(())),
-                Err(err) => tx.send(Err(ClientError::ServerError(err))),
-            };
+        let _guard = self.install_handler(seq, move |_, res| {
+            let _ = tx.send(res.map(drop));
         })?;
 
         // Send the message.
@@ -114,22 +277,33 @@ impl ReactorHandle {
         params: protocol::PlaybackStreamParams,
         source: impl PlaybackSource,
         eof_notify: Option<oneshot::Sender<()>>,
-    ) -> Result<protocol::CreatePlaybackStreamReply, ClientError> {
+    ) -> Result<(protocol::CreatePlaybackStreamReply, SharedStreamState), ClientError> {
         // This is the seq for the CreatePlaybackStream command.
         let seq = self.next_seq();
+        let cmd_params = params.clone();
 
-        let protocol_version = self.shared.protocol_version;
-        let handler = move |res: ReplyResult<'_>| {
-            let (state, buf) = res.map_err(ClientError::ServerError)?;
+        let handler = move |state: &mut ReactorState, res: ReplyResult<'_>| {
+            let buf = res?;
             let stream_info: protocol::CreatePlaybackStreamReply =
-                read_tagstruct(buf, protocol_version)?;
+                read_tagstruct(buf, state.protocol_version)?;
+
+            let shared = StreamState::new_shared(
+                stream_info.channel,
+                stream_info.stream_index,
+                stream_info.sample_spec,
+                stream_info.sink_index,
+                stream_info.buffer_attr,
+                stream_info.suspended,
+            );
 
             let requested_bytes = stream_info.requested_bytes as usize;
             state.playback_streams.insert(
                 stream_info.channel,
                 PlaybackStreamState {
+                    params,
                     stream_info: stream_info.clone(),
                     source: Box::pin(source),
+                    tracker: StreamTracker::new(shared.clone()),
 
                     requested_bytes,
                     done: false,
@@ -137,16 +311,26 @@ impl ReactorHandle {
                 },
             );
 
-            Ok(stream_info)
+            Ok((stream_info, shared))
         };
 
+        // The handler stays installed if the future is dropped, so that the
+        // stream can be deleted again if the server creates it anyway.
         let (tx, rx) = oneshot::channel();
-        self.install_handler(seq, move |res: ReplyResult<'_>| {
-            let _ = tx.send(handler(res));
+        self.install_detached_handler(seq, move |state, res| {
+            if matches!(res, Err(ClientError::Timeout)) {
+                state.insert_handler(seq, Box::new(delete_late_playback_stream), None);
+            }
+
+            if let Err(Ok((stream_info, _))) = tx.send(handler(state, res)) {
+                log::debug!("deleting abandoned playback stream {}", stream_info.channel);
+                state.playback_streams.remove(&stream_info.channel);
+                state.queue_command(protocol::Command::DeletePlaybackStream(stream_info.channel));
+            }
         })?;
 
         // Send the message.
-        self.write_command(seq, protocol::Command::CreatePlaybackStream(params))?;
+        self.write_command(seq, protocol::Command::CreatePlaybackStream(cmd_params))?;
 
         // Wait for the response.
         rx.await.map_err(|_| ClientError::Disconnected)?
@@ -155,9 +339,12 @@ impl ReactorHandle {
     pub(super) async fn delete_playback_stream(&self, channel: u32) -> Result<(), ClientError> {
         let seq = self.next_seq();
 
+        // The stream is removed even if nobody waits for the reply. If the
+        // connection was lost, the server-side stream is gone anyway, and it
+        // mustn't be recreated.
         let (tx, rx) = oneshot::channel();
-        self.install_handler(seq, move |res| {
-            if let Ok((state, _ack)) = res {
+        self.install_detached_handler(seq, move |state, res| {
+            if matches!(res, Ok(_) | Err(ClientError::Disconnected)) {
                 state.playback_streams.remove(&channel);
             }
 
@@ -181,33 +368,54 @@ impl ReactorHandle {
         params: protocol::RecordStreamParams,
         sink: impl RecordSink,
         start_notify: Option<oneshot::Sender<()>>,
-    ) -> Result<protocol::CreateRecordStreamReply, ClientError> {
+    ) -> Result<(protocol::CreateRecordStreamReply, SharedStreamState), ClientError> {
         let seq = self.next_seq();
+        let cmd_params = params.clone();
 
-        let protocol_version = self.shared.protocol_version;
-        let handler = move |res: ReplyResult<'_>| {
-            let (state, buf) = res.map_err(ClientError::ServerError)?;
+        let handler = move |state: &mut ReactorState, res: ReplyResult<'_>| {
+            let buf = res?;
             let stream_info: protocol::CreateRecordStreamReply =
-                read_tagstruct(buf, protocol_version)?;
+                read_tagstruct(buf, state.protocol_version)?;
+
+            let shared = StreamState::new_shared(
+                stream_info.channel,
+                stream_info.stream_index,
+                stream_info.sample_spec,
+                stream_info.sink_index,
+                stream_info.buffer_attr,
+                stream_info.suspended,
+            );
 
             state.record_streams.insert(
                 stream_info.channel,
                 RecordStreamState {
+                    params,
                     sink: Box::new(sink),
                     start_notify,
+                    tracker: StreamTracker::new(shared.clone()),
                 },
             );
 
-            Ok(stream_info)
+            Ok((stream_info, shared))
         };
 
+        // As for playback streams, the handler stays installed to clean up
+        // after a dropped future.
         let (tx, rx) = oneshot::channel();
-        self.install_handler(seq, move |res: ReplyResult<'_>| {
-            let _ = tx.send(handler(res));
+        self.install_detached_handler(seq, move |state, res| {
+            if matches!(res, Err(ClientError::Timeout)) {
+                state.insert_handler(seq, Box::new(delete_late_record_stream), None);
+            }
+
+            if let Err(Ok((stream_info, _))) = tx.send(handler(state, res)) {
+                log::debug!("deleting abandoned record stream {}", stream_info.channel);
+                state.record_streams.remove(&stream_info.channel);
+                state.queue_command(protocol::Command::DeleteRecordStream(stream_info.channel));
+            }
         })?;
 
         // Send the message.
-        self.write_command(seq, protocol::Command::CreateRecordStream(params))?;
+        self.write_command(seq, protocol::Command::CreateRecordStream(cmd_params))?;
 
         // Wait for the response.
         rx.await.map_err(|_| ClientError::Disconnected)?
@@ -216,9 +424,12 @@ impl ReactorHandle {
     pub(super) async fn delete_record_stream(&self, channel: u32) -> Result<(), ClientError> {
         let seq = self.next_seq();
 
+        // The stream is removed even if nobody waits for the reply. If the
+        // connection was lost, the server-side stream is gone anyway, and it
+        // mustn't be recreated.
         let (tx, rx) = oneshot::channel();
-        self.install_handler(seq, move |res| {
-            if let Ok((state, _ack)) = res {
+        self.install_detached_handler(seq, move |state, res| {
+            if matches!(res, Ok(_) | Err(ClientError::Disconnected)) {
                 state.record_streams.remove(&channel);
             }
 
@@ -229,56 +440,398 @@ impl ReactorHandle {
         rx.await.map_err(|_| ClientError::Disconnected)
     }
 
+    pub(super) fn playback_stream_events(&self, channel: u32) -> StreamEvents {
+        let Some(state) = self.state.upgrade() else {
+            return StreamEvents::ended();
+        };
+
+        match state.lock().unwrap().playback_streams.get_mut(&channel) {
+            Some(stream) => stream.tracker.listen(),
+            None => StreamEvents::ended(),
+        }
+    }
+
+    pub(super) fn record_stream_events(&self, channel: u32) -> StreamEvents {
+        let Some(state) = self.state.upgrade() else {
+            return StreamEvents::ended();
+        };
+
+        match state.lock().unwrap().record_streams.get_mut(&channel) {
+            Some(stream) => stream.tracker.listen(),
+            None => StreamEvents::ended(),
+        }
+    }
+
+    /// Returns a channel that receives a message each time the reactor
+    /// reconnects to the server. It ends once the reactor exits.
+    pub(super) fn reconnect_events(&self) -> mpsc::UnboundedReceiver<()> {
+        let (tx, rx) = mpsc::unbounded();
+        if let Some(state) = self.state.upgrade() {
+            state.lock().unwrap().reconnect_listeners.push(tx);
+        }
+
+        rx
+    }
+
+    pub(super) async fn insert_subscriber(
+        &self,
+        mask: protocol::SubscriptionMask,
+    ) -> Result<(u64, mpsc::UnboundedReceiver<protocol::SubscriptionEvent>), ClientError> {
+        let (events_tx, events_rx) = mpsc::unbounded();
+        let (tx, rx) = oneshot::channel();
+
+        let id = self.update_subscriptions(ReactorState::subscribe_command, move |state| {
+            let id = state.next_subscriber_id;
+            state.next_subscriber_id += 1;
+            state.subscribers.insert(
+                id,
+                SubscriberState {
+                    mask,
+                    events: events_tx,
+                },
+            );
+
+            let handler = move |_: &mut ReactorState, res: ReplyResult<'_>| {
+                let _ = tx.send(res.map(drop));
+            };
+
+            (id, handler)
+        })?;
+
+        let guard = SubscriberGuard {
+            handle: self,
+            id,
+            remove: Self::remove_subscriber,
+        };
+
+        rx.await.map_err(|_| ClientError::Disconnected)??;
+        Ok((guard.keep(), events_rx))
+    }
+
+    pub(super) fn remove_subscriber(&self, id: u64) {
+        // Sends the updated mask to the server, but doesn't wait for the
+        // response.
+        let _ = self.update_subscriptions(ReactorState::subscribe_command, move |state| {
+            state.subscribers.remove(&id);
+            ((), |_: &mut ReactorState, _: ReplyResult<'_>| {})
+        });
+    }
+
+    pub(super) async fn insert_stream_restore_subscriber(
+        &self,
+    ) -> Result<(u64, mpsc::UnboundedReceiver<()>), ClientError> {
+        let (events_tx, events_rx) = mpsc::unbounded();
+        let (tx, rx) = oneshot::channel();
+
+        let id = self.update_subscriptions(
+            ReactorState::stream_restore_subscribe_command,
+            move |state| {
+                let id = state.next_subscriber_id;
+                state.next_subscriber_id += 1;
+                state.stream_restore_subscribers.insert(id, events_tx);
+
+                let handler = move |_: &mut ReactorState, res: ReplyResult<'_>| {
+                    let _ = tx.send(res.map(drop));
+                };
+
+                (id, handler)
+            },
+        )?;
+
+        let guard = SubscriberGuard {
+            handle: self,
+            id,
+            remove: Self::remove_stream_restore_subscriber,
+        };
+
+        rx.await.map_err(|_| ClientError::Disconnected)??;
+        Ok((guard.keep(), events_rx))
+    }
+
+    pub(super) fn remove_stream_restore_subscriber(&self, id: u64) {
+        let _ = self.update_subscriptions(
+            ReactorState::stream_restore_subscribe_command,
+            move |state| {
+                state.stream_restore_subscribers.remove(&id);
+                ((), |_: &mut ReactorState, _: ReplyResult<'_>| {})
+            },
+        );
+    }
+
+    /// Modifies the set of subscribers, and then sends the resulting
+    /// subscription command to the server. The state lock is held until the
+    /// command is queued, so that concurrent updates reach the server in the
+    /// same order they were applied.
+    fn update_subscriptions<T, F, H>(
+        &self,
+        command: fn(&ReactorState) -> protocol::Command,
+        f: F,
+    ) -> Result<T, ClientError>
+    where
+        F: FnOnce(&mut ReactorState) -> (T, H),
+        H: FnOnce(&mut ReactorState, ReplyResult<'_>) + Send + 'static,
+    {
+        let state = self.state.upgrade().ok_or(ClientError::Disconnected)?;
+        let mut state = state.lock().unwrap();
+
+        let (res, handler) = f(&mut state);
+        let cmd = command(&state);
+
+        let seq = self.next_seq();
+        state.insert_handler(seq, Box::new(handler), self.timeout);
+        self.write_command(seq, cmd)?;
+
+        Ok(res)
+    }
+
+    pub(super) async fn upload_sample(
+        &self,
+        params: protocol::UploadStreamParams,
+        chunks: Vec<Vec<u8>>,
+    ) -> Result<(), ClientError> {
+        let reply: protocol::CreateUploadStreamReply = self
+            .roundtrip_reply(protocol::Command::CreateUploadStream(params))
+            .await?;
+
+        // The data is queued along with commands, so it's guaranteed to reach
+        // the server before the FinishUploadStream command.
+        for chunk in chunks {
+            self.write_outgoing(Outgoing::Memblock(reply.channel, chunk))?;
+        }
+
+        self.roundtrip_ack(protocol::Command::FinishUploadStream(reply.channel))
+            .await
+    }
+
+    /// Deletes any open streams, and then stops the reactor and waits for
+    /// it to exit.
+    pub(super) async fn close(&self) -> ExitResult {
+        if !self.shared.closing.swap(true, atomic::Ordering::AcqRel) {
+            let (playback, record) = match self.state.upgrade() {
+                Some(state) => {
+                    let state = state.lock().unwrap();
+                    (
+                        state.playback_streams.keys().copied().collect(),
+                        state.record_streams.keys().copied().collect(),
+                    )
+                }
+                None => (Vec::new(), Vec::new()),
+            };
+
+            let deletes = playback
+                .into_iter()
+                .map(|channel| self.delete_playback_stream(channel).boxed())
+                .chain(
+                    record
+                        .into_iter()
+                        .map(|channel| self.delete_record_stream(channel).boxed()),
+                );
+            futures::future::join_all(deletes).await;
+
+            let _ = self.write_outgoing(Outgoing::Close);
+        }
+
+        let res = self.closed().await;
+        if let Some(thread) = self.shared.thread_handle.lock().unwrap().take() {
+            let _ = thread.join();
+        }
+
+        res
+    }
+
+    /// Resolves once the reactor has exited.
+    pub(super) async fn closed(&self) -> ExitResult {
+        self.shared
+            .exit
+            .clone()
+            .await
+            .unwrap_or_else(|_| Err(Arc::new(ClientError::Disconnected)))
+    }
+
     fn write_command(&self, seq: u32, cmd: protocol::Command) -> Result<(), ClientError> {
+        // Once the client is closing, the only thing left to do is delete
+        // streams.
+        if self.shared.closing.load(atomic::Ordering::Acquire)
+            && !matches!(
+                cmd,
+                protocol::Command::DeletePlaybackStream(_)
+                    | protocol::Command::DeleteRecordStream(_)
+            )
+        {
+            return Err(ClientError::Disconnected);
+        }
+
+        self.write_outgoing(Outgoing::Command(seq, Box::new(cmd)))
+    }
+
+    fn write_outgoing(&self, msg: Outgoing) -> Result<(), ClientError> {
         self.outgoing
-            .send((seq, cmd))
+            .send(msg)
             .map_err(|_| ClientError::Disconnected)?;
         self.waker.0.wake()?;
 
         Ok(())
     }
 
-    fn install_handler<F>(&self, seq: u32, handler: F) -> Result<(), ClientError>
+    /// Installs a handler for the reply to a command, which is called with
+    /// [ClientError::Timeout] if the reply doesn't arrive in time. The
+    /// handler is removed when the returned guard is dropped.
+    fn install_handler<F>(&self, seq: u32, handler: F) -> Result<HandlerGuard<'_>, ClientError>
+    where
+        F: FnOnce(&mut ReactorState, ReplyResult<'_>) + Send + 'static,
+    {
+        self.install_detached_handler(seq, handler)?;
+        Ok(HandlerGuard { handle: self, seq })
+    }
+
+    /// Like [Self::install_handler], but the handler stays installed until
+    /// the reply arrives or times out.
+    fn install_detached_handler<F>(&self, seq: u32, handler: F) -> Result<(), ClientError>
     where
-        F: FnOnce(ReplyResult<'_>) + Send + 'static,
+        F: FnOnce(&mut ReactorState, ReplyResult<'_>) + Send + 'static,
     {
         self.state
             .upgrade()
             .ok_or(ClientError::Disconnected)?
             .lock()
             .unwrap()
-            .handlers
-            .insert(seq, Box::new(handler));
+            .insert_handler(seq, Box::new(handler), self.timeout);
 
         Ok(())
     }
 
+    /// Sets the timeout for replies to commands sent through this handle.
+    pub(super) fn set_timeout(&mut self, timeout: Option<Duration>) {
+        self.timeout = timeout;
+    }
+
     fn next_seq(&self) -> u32 {
         self.shared.next_seq.fetch_add(1, atomic::Ordering::Relaxed)
     }
 }
 
+/// The delay before the first attempt to reconnect, which doubles after
+/// each failed attempt.
+const RECONNECT_MIN_DELAY: Duration = Duration::from_millis(100);
+const RECONNECT_MAX_DELAY: Duration = Duration::from_secs(10);
+
+/// The maximum size of each memblock sent for an upload stream.
+pub(super) const UPLOAD_CHUNK_SIZE: usize = 64 * 1024;
+
 pub(super) const WAKER: mio::Token = mio::Token(0);
 pub(super) const SOCKET: mio::Token = mio::Token(1);
+pub(super) const SRBCHANNEL: mio::Token = mio::Token(2);
+
+/// Incoming data, which may end with a partial message.
+#[derive(Default)]
+struct ReadBuffer {
+    buf: Vec<u8>,
+    in_progress: Option<protocol::Descriptor>,
+}
+
+impl ReadBuffer {
+    /// Appends data using `read`, returning the number of bytes read.
+    fn fill(&mut self, read: impl FnOnce(&mut [u8]) -> io::Result<usize>) -> io::Result<usize> {
+        let off = self.buf.len();
+        self.buf.resize(off + 1024 * 1024, 0);
+
+        let res = read(&mut self.buf[off..]);
+        self.buf.truncate(off + *res.as_ref().unwrap_or(&0));
+        res
+    }
+
+    /// Returns the descriptor and total length of the next message, if it has
+    /// been read in full.
+    fn next_message(&mut self) -> Result<Option<(protocol::Descriptor, usize)>, ClientError> {
+        // Continue the previous read, if it was unfinished.
+        let desc = if let Some(desc) = self.in_progress.take() {
+            desc
+        } else if self.buf.len() >= protocol::DESCRIPTOR_SIZE {
+            protocol::read_descriptor(&mut io::Cursor::new(&self.buf))?
+        } else {
+            if !self.buf.is_empty() {
+                log::trace!("very short read ({} bytes)", self.buf.len());
+            }
+
+            return Ok(None);
+        };
+
+        // If we don't have all the message, poll until we do.
+        let len = desc.length as usize + protocol::DESCRIPTOR_SIZE;
+        if self.buf.len() < len {
+            log::trace!("partial read ({}/{} bytes)", self.buf.len(), len);
+            self.in_progress = Some(desc);
+            return Ok(None);
+        }
+
+        Ok(Some((desc, len)))
+    }
+}
+
+/// A connection to the server which has completed the handshake.
+pub(super) struct Connection {
+    pub(super) socket: Socket,
+    pub(super) protocol_version: u16,
+    // Only set if shm was negotiated with the server.
+    pub(super) shm: Option<ShmPool>,
+    // Any messages read from the socket during the handshake, and any file
+    // descriptors passed along with them.
+    pub(super) backlog: Vec<u8>,
+    pub(super) fds: VecDeque<OwnedFd>,
+}
+
+/// Establishes a new connection to the server, for a client that reconnects
+/// automatically.
+pub(super) type Connector = Box<dyn FnMut() -> Result<Connection, ClientError> + Send>;
 
 pub(super) struct Reactor {
-    socket: UnixStream,
+    socket: Socket,
     poll: mio::Poll,
     waker: Arc<Waker>,
     state: Arc<Mutex<ReactorState>>,
-    outgoing: Receiver<(u32, protocol::Command)>,
+    outgoing: Receiver<Outgoing>,
     protocol_version: u16,
 
     write_buf: Vec<u8>,
-    read_buf: Vec<u8>,
-    in_progress_read: Option<protocol::Descriptor>,
+    read_buf: ReadBuffer,
+
+    // Only set if shm was negotiated with the server.
+    shm: Option<ShmPool>,
+    shm_imports: ShmImports,
+    // File descriptors passed by the server, waiting for the messages they
+    // belong to.
+    fds: VecDeque<OwnedFd>,
+
+    // Set once the server offers an srbchannel, until its memblock arrives.
+    srb_setup: Option<(u32, OwnedFd, OwnedFd)>,
+    srb: Option<Srbchannel>,
+    srb_read_buf: ReadBuffer,
+    // Writes switch over to the srbchannel once everything queued before
+    // the acknowledgement has been written to the socket.
+    srb_writes: bool,
+
+    // Set once the client is closed. Nothing queued afterwards is written.
+    closing: bool,
+
+    next_seq: Arc<AtomicU32>,
+    connector: Option<Connector>,
 }
 
 impl Reactor {
+    /// Starts the reactor thread. If a `connector` is given, the reactor uses
+    /// it to reconnect whenever the connection is lost.
     pub(super) fn spawn(
-        mut socket: UnixStream,
-        protocol_version: u16,
+        conn: Connection,
+        connector: Option<Connector>,
     ) -> Result<ReactorHandle, ClientError> {
+        let Connection {
+            mut socket,
+            protocol_version,
+            shm,
+            backlog,
+            fds,
+        } = conn;
+
         let poll = mio::Poll::new()?;
         let waker = Arc::new(Waker(mio::Waker::new(poll.registry(), WAKER)?));
         poll.registry().register(
@@ -287,7 +840,11 @@ impl Reactor {
             mio::Interest::READABLE | mio::Interest::WRITABLE,
         )?;
 
-        let state = Arc::new(Mutex::new(ReactorState::default()));
+        let state = Arc::new(Mutex::new(ReactorState {
+            protocol_version,
+            ..Default::default()
+        }));
+        let next_seq = Arc::new(AtomicU32::new(1024));
 
         let (cmd_tx, cmd_rx) = std::sync::mpsc::channel();
         let mut reactor = Self {
@@ -299,181 +856,771 @@ impl Reactor {
             protocol_version,
 
             write_buf: Vec::new(),
-            read_buf: Vec::new(),
-            in_progress_read: None,
+            read_buf: ReadBuffer {
+                buf: backlog,
+                in_progress: None,
+            },
+
+            shm,
+            shm_imports: ShmImports::default(),
+            fds,
+
+            srb_setup: None,
+            srb: None,
+            srb_read_buf: ReadBuffer::default(),
+            srb_writes: false,
+
+            closing: false,
+
+            next_seq: next_seq.clone(),
+            connector,
         };
 
-        let reactor_thread = std::thread::spawn(move || match reactor.run() {
-            Ok(_) => Ok(()),
-            Err(err) => {
+        let (exit_tx, exit_rx) = oneshot::channel();
+        let reactor_thread = std::thread::spawn(move || {
+            let res = reactor.run_reconnecting().map_err(|err| {
                 log::error!("Reactor error: {err}");
-                Err(err)
-            }
+                Arc::new(err)
+            });
+
+            // Fail any pending commands before reporting the exit.
+            drop(reactor);
+            let _ = exit_tx.send(res);
         });
 
         Ok(ReactorHandle {
             state: Arc::downgrade(&state),
             outgoing: cmd_tx,
             waker,
+            timeout: Some(DEFAULT_TIMEOUT),
             shared: Arc::new(SharedState {
-                protocol_version,
-                next_seq: AtomicU32::new(1024),
-                _thread_handle: reactor_thread,
+                next_seq,
+                closing: AtomicBool::new(false),
+                thread_handle: Mutex::new(Some(reactor_thread)),
+                exit: exit_rx.shared(),
             }),
         })
     }
 
-    pub(super) fn run(&mut self) -> Result<(), ClientError> {
-        let mut events = mio::Events::with_capacity(1024);
-
+    /// Runs the reactor, reconnecting whenever the connection is lost if the
+    /// client was created with a connector.
+    fn run_reconnecting(&mut self) -> Result<(), ClientError> {
         loop {
-            self.poll.poll(&mut events, None)?;
-            self.recv()?;
-
-            // Handle any requested writes.
-            self.write_streams()?;
-            self.write_commands()?;
+            match self.run() {
+                Err(err @ (ClientError::Disconnected | ClientError::Io(_)))
+                    if self.connector.is_some() && !self.closing =>
+                {
+                    log::warn!("lost connection to server: {err}");
+                    if !self.reconnect()? {
+                        return Ok(());
+                    }
+                }
+                res => return res,
+            }
         }
     }
 
-    fn recv(&mut self) -> Result<(), ClientError> {
-        use io::Read;
+    /// Reconnects to the server, retrying with backoff, and then recreates
+    /// streams and subscriptions. Returns false if the client was closed in
+    /// the meantime.
+    fn reconnect(&mut self) -> Result<bool, ClientError> {
+        self.reset_connection();
 
-        'read: loop {
-            let off = self.read_buf.len();
-            self.read_buf.resize(off + 1024 * 1024, 0);
+        let mut backoff = Backoff::default();
+        let conn = loop {
+            if !self.wait_disconnected(backoff.next_delay())? {
+                return Ok(false);
+            }
 
-            match self.socket.read(&mut self.read_buf[off..]) {
-                Ok(0) => return Err(ClientError::Disconnected),
-                Ok(n) => self.read_buf.truncate(off + n),
-                Err(err) if err.kind() == io::ErrorKind::WouldBlock => {
-                    self.read_buf.truncate(off);
-                    return Ok(());
-                }
-                Err(err) => return Err(err.into()),
-            };
+            match self.connect()? {
+                Some(Ok(conn)) => break conn,
+                Some(Err(err)) => log::debug!("failed to reconnect: {err}"),
+                None => return Ok(false),
+            }
+        };
 
-            // Decode messages (there may be multiple).
-            while !self.read_buf.is_empty() {
-                // Continue the previous read, if it was unfinished.
-                let desc = if let Some(desc) = self.in_progress_read.take() {
-                    desc
-                } else if self.read_buf.len() >= protocol::DESCRIPTOR_SIZE {
-                    protocol::read_descriptor(&mut io::Cursor::new(&self.read_buf))?
-                } else {
-                    log::trace!("very short read ({} bytes)", self.read_buf.len());
-                    continue 'read;
-                };
+        log::info!("reconnected to server");
+        self.socket = conn.socket;
+        self.poll.registry().register(
+            &mut self.socket,
+            SOCKET,
+            mio::Interest::READABLE | mio::Interest::WRITABLE,
+        )?;
 
-                // If we don't have all the message, poll until we do.
-                let len = desc.length as usize + protocol::DESCRIPTOR_SIZE;
-                if self.read_buf.len() < len {
-                    self.in_progress_read = Some(desc);
-                    log::trace!("partial read ({}/{} bytes)", self.read_buf.len(), len);
-                    continue 'read;
-                }
+        self.protocol_version = conn.protocol_version;
+        self.shm = conn.shm;
+        self.read_buf.buf = conn.backlog;
+        self.fds = conn.fds;
 
-                if desc.channel == u32::MAX {
-                    self.handle_command(len);
-                } else {
-                    // Stream data for a record stream.
-                    let mut guard = self.state.lock().unwrap();
-                    if let Some(RecordStreamState { sink, start_notify }) =
-                        guard.record_streams.get_mut(&desc.channel)
-                    {
-                        log::trace!("reading {len} bytes from stream {}", desc.channel,);
-                        if let Some(start_notify) = start_notify.take() {
-                            let _ = start_notify.send(());
-                        }
-
-                        sink.write(&self.read_buf[protocol::DESCRIPTOR_SIZE..len])
-                    } else {
-                        log::warn!("Received data for unknown record stream {}", desc.channel);
-                    }
-                }
+        self.restore_state()?;
+        Ok(true)
+    }
 
-                self.read_buf.drain(..len);
-            }
+    /// Drops everything tied to the lost connection, and fails any commands
+    /// waiting for a reply.
+    fn reset_connection(&mut self) {
+        let registry = self.poll.registry();
+        let _ = registry.deregister(&mut self.socket);
+        if let Some(srb) = self.srb.take() {
+            let _ = registry.deregister(&mut mio::unix::SourceFd(&srb.read_fd()));
         }
-    }
 
-    fn handle_command(&mut self, len: usize) {
-        let mut cursor = io::Cursor::new(&self.read_buf[protocol::DESCRIPTOR_SIZE..len]);
-        let (seq, cmd) =
-            match protocol::Command::read_tag_prefixed(&mut cursor, self.protocol_version) {
-                Ok((seq, cmd)) => (seq, cmd),
-                Err(err) => {
-                    log::error!("failed to read command message: {err}");
-                    return;
-                }
-            };
+        self.write_buf.clear();
+        self.read_buf = ReadBuffer::default();
+        self.shm = None;
+        self.shm_imports = ShmImports::default();
+        self.fds.clear();
+        self.srb_setup = None;
+        self.srb_read_buf = ReadBuffer::default();
+        self.srb_writes = false;
 
         let mut state = self.state.lock().unwrap();
+        for handler in state.take_handlers() {
+            handler(&mut state, Err(ClientError::Disconnected));
+        }
+    }
 
-        log::debug!("SERVER [{}]: {cmd:?}", seq as i32);
-        if matches!(cmd, protocol::Command::Reply | protocol::Command::Error(_)) {
-            let Some(handler) = state.handlers.remove(&seq) else {
-                log::warn!("no reply handler found for sequence {seq}");
-                return;
-            };
+    /// Waits for `delay` while disconnected, failing any commands sent in
+    /// the meantime. Returns false if the client was closed.
+    fn wait_disconnected(&mut self, delay: Duration) -> Result<bool, ClientError> {
+        let deadline = Instant::now() + delay;
+        let mut events = mio::Events::with_capacity(16);
 
-            match cmd {
-                protocol::Command::Reply => handler(Ok((&mut state, &mut cursor))),
-                protocol::Command::Error(err) => handler(Err(err)),
-                _ => unreachable!(),
+        loop {
+            if !self.fail_outgoing() {
+                return Ok(false);
             }
-            return;
-        }
 
-        match cmd {
-            protocol::Command::Started(channel) => {
-                if state.playback_streams.contains_key(&channel) {
-                    log::debug!("stream started: {channel}");
-                } else {
-                    log::error!("unknown stream: {channel}");
-                }
-            }
-            protocol::Command::Request(protocol::Request { channel, length }) => {
-                if let Some(stream) = state.playback_streams.get_mut(&channel) {
-                    stream.requested_bytes += length as usize;
-                } else {
-                    log::error!("unknown stream: {channel}");
-                }
+            let now = Instant::now();
+            if now >= deadline {
+                return Ok(true);
             }
-            _ => log::debug!("ignoring unexpected command: {cmd:?}"),
+
+            self.poll.poll(&mut events, Some(deadline - now))?;
         }
     }
 
-    fn write_commands(&mut self) -> Result<(), ClientError> {
+    /// Runs the connector on another thread, since connecting blocks, and
+    /// fails any commands sent in the meantime. Returns `None` if the client
+    /// was closed before the attempt finished.
+    fn connect(&mut self) -> Result<Option<Result<Connection, ClientError>>, ClientError> {
+        let mut connector = self.connector.take().unwrap();
+        let waker = self.waker.clone();
+        let (tx, rx) = std::sync::mpsc::channel();
+        std::thread::spawn(move || {
+            let res = connector();
+            if tx.send((connector, res)).is_ok() {
+                let _ = waker.0.wake();
+            }
+        });
+
+        let mut events = mio::Events::with_capacity(16);
         loop {
-            // Drain the write buffer...
-            if !drain_buf(&mut self.write_buf, &mut self.socket)? {
-                return Ok(());
+            if !self.fail_outgoing() {
+                return Ok(None);
+            }
+
+            match rx.try_recv() {
+                Ok((connector, res)) => {
+                    self.connector = Some(connector);
+                    return Ok(Some(res));
+                }
+                Err(TryRecvError::Empty) => (),
+                // The connector panicked.
+                Err(TryRecvError::Disconnected) => return Err(ClientError::Disconnected),
+            }
+
+            self.poll.poll(&mut events, None)?;
+        }
+    }
+
+    /// Fails any commands queued while disconnected. Returns false if the
+    /// client was closed.
+    fn fail_outgoing(&mut self) -> bool {
+        loop {
+            match self.outgoing.try_recv() {
+                Ok(Outgoing::Command(seq, _)) => {
+                    let mut state = self.state.lock().unwrap();
+                    if let Some(handler) = state.remove_handler(seq) {
+                        handler(&mut state, Err(ClientError::Disconnected));
+                    }
+                }
+                Ok(Outgoing::Memblock(..)) => (),
+                Ok(Outgoing::Close) | Err(TryRecvError::Disconnected) => return false,
+                Err(TryRecvError::Empty) => return true,
+            }
+        }
+    }
+
+    /// Recreates streams and subscriptions after reconnecting. Listeners are
+    /// notified once the server has replied to all of it, even if some of it
+    /// failed; streams that couldn't be recreated are killed, and failures
+    /// are logged. The commands always use [DEFAULT_TIMEOUT], since the
+    /// request timeout is set per client handle.
+    fn restore_state(&mut self) -> Result<(), ClientError> {
+        let state = self.state.clone();
+        let mut state = state.lock().unwrap();
+        state.protocol_version = self.protocol_version;
+        state.restore_failures = 0;
+
+        // Anything still queued refers to the old connection.
+        state.queued_commands.clear();
+
+        let mut commands: Vec<(protocol::Command, ReplyHandler)> = Vec::new();
+
+        let playback_streams = std::mem::take(&mut state.playback_streams)
+            .into_values()
+            .chain(std::mem::take(&mut state.lost_playback_streams));
+        for stream in playback_streams {
+            // The remaining data was lost along with the server's buffer.
+            if stream.done {
+                stream.tracker.kill();
+                continue;
+            }
+
+            commands.push((
+                protocol::Command::CreatePlaybackStream(stream.params.clone()),
+                Box::new(move |state, res| playback_stream_recreated(state, stream, res)),
+            ));
+        }
+
+        let record_streams = std::mem::take(&mut state.record_streams)
+            .into_values()
+            .chain(std::mem::take(&mut state.lost_record_streams));
+        for stream in record_streams {
+            commands.push((
+                protocol::Command::CreateRecordStream(stream.params.clone()),
+                Box::new(move |state, res| record_stream_recreated(state, stream, res)),
+            ));
+        }
+
+        let mask = state.subscription_mask();
+        if !mask.is_empty() {
+            commands.push((
+                protocol::Command::Subscribe(mask),
+                resubscribed("subscription"),
+            ));
+        }
+
+        if !state.stream_restore_subscribers.is_empty() {
+            commands.push((
+                state.stream_restore_subscribe_command(),
+                resubscribed("stream-restore subscription"),
+            ));
+        }
+
+        if commands.is_empty() {
+            state.notify_reconnected();
+            return Ok(());
+        }
+
+        // Replies arrive in order, so the last one completes the restore. If
+        // the connection is lost again, the next reconnect starts over.
+        let last = commands.len() - 1;
+        for (i, (cmd, handler)) in commands.into_iter().enumerate() {
+            let handler: ReplyHandler = Box::new(move |state, res| {
+                let disconnected = matches!(res, Err(ClientError::Disconnected));
+                if res.is_err() && !disconnected {
+                    state.restore_failures += 1;
+                }
+
+                handler(state, res);
+                if i == last && !disconnected {
+                    if state.restore_failures > 0 {
+                        log::warn!(
+                            "reconnected, but {} streams or subscriptions couldn't be restored",
+                            state.restore_failures
+                        );
+                    }
+
+                    state.notify_reconnected();
+                }
+            });
+
+            let seq = self.next_seq.fetch_add(1, atomic::Ordering::Relaxed);
+            state.insert_handler(seq, handler, Some(DEFAULT_TIMEOUT));
+            self.encode_command(seq, &cmd)?;
+        }
+
+        Ok(())
+    }
+
+    fn encode_command(&mut self, seq: u32, cmd: &protocol::Command) -> Result<(), ClientError> {
+        log::debug!("CLIENT [{seq}]: {cmd:?}");
+        // Messages are encoded from the start of the buffer, so anything
+        // already queued has to be preserved.
+        if self.write_buf.is_empty() {
+            protocol::encode_command_message(&mut self.write_buf, seq, cmd, self.protocol_version)?;
+        } else {
+            let mut msg = Vec::new();
+            protocol::encode_command_message(&mut msg, seq, cmd, self.protocol_version)?;
+            self.write_buf.extend_from_slice(&msg);
+        }
+
+        Ok(())
+    }
+
+    pub(super) fn run(&mut self) -> Result<(), ClientError> {
+        let mut events = mio::Events::with_capacity(1024);
+
+        // Handle anything left over from the handshake.
+        self.handle_socket_messages()?;
+
+        loop {
+            // Wake up in time to expire the next pending reply.
+            let timeout = self
+                .state
+                .lock()
+                .unwrap()
+                .next_deadline()
+                .map(|deadline| deadline.saturating_duration_since(Instant::now()));
+
+            self.poll.poll(&mut events, timeout)?;
+            self.expire_handlers();
+            self.recv()?;
+            self.recv_srbchannel()?;
+
+            // Handle any requested writes.
+            self.write_streams()?;
+            self.write_commands()?;
+
+            if self.closing && self.write_buf.is_empty() {
+                log::debug!("reactor closed");
+                return Ok(());
+            }
+        }
+    }
+
+    fn expire_handlers(&mut self) {
+        let mut state = self.state.lock().unwrap();
+        for (seq, handler) in state.take_expired(Instant::now()) {
+            log::debug!("timed out waiting for reply to sequence {seq}");
+            handler(&mut state, Err(ClientError::Timeout));
+        }
+    }
+
+    fn recv(&mut self) -> Result<(), ClientError> {
+        loop {
+            let (socket, fds) = (&mut self.socket, &mut self.fds);
+            match self.read_buf.fill(|buf| socket.recv_with_fds(buf, fds)) {
+                Ok(0) => return Err(ClientError::Disconnected),
+                Ok(_) => self.handle_socket_messages()?,
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+                Err(err) => return Err(err.into()),
+            };
+        }
+    }
+
+    fn recv_srbchannel(&mut self) -> Result<(), ClientError> {
+        let Some(srb) = &mut self.srb else {
+            return Ok(());
+        };
+
+        srb.clear_wakeup();
+
+        loop {
+            let Some(srb) = &mut self.srb else {
+                return Ok(());
+            };
+
+            if self.srb_read_buf.fill(|buf| Ok(srb.read(buf)))? == 0 {
+                return Ok(());
+            }
+
+            let mut buf = std::mem::take(&mut self.srb_read_buf);
+            self.handle_messages(&mut buf)?;
+            self.srb_read_buf = buf;
+        }
+    }
+
+    fn handle_socket_messages(&mut self) -> Result<(), ClientError> {
+        let mut buf = std::mem::take(&mut self.read_buf);
+        self.handle_messages(&mut buf)?;
+        self.read_buf = buf;
+        Ok(())
+    }
+
+    /// Decodes and handles messages (there may be multiple).
+    fn handle_messages(&mut self, read_buf: &mut ReadBuffer) -> Result<(), ClientError> {
+        while let Some((desc, len)) = read_buf.next_message()? {
+            let payload = &read_buf.buf[protocol::DESCRIPTOR_SIZE..len];
+
+            if desc.flags == DescriptorFlags::FLAG_SHMRELEASE {
+                // The server is done with a block we sent.
+                let block_id = protocol::shm_control_block_id(&desc);
+                if let Some(pool) = &mut self.shm {
+                    pool.release(block_id);
+                }
+            } else if desc.flags == DescriptorFlags::FLAG_SHMREVOKE {
+                // We copy shm data out as soon as it arrives, so there's
+                // nothing to revoke.
+                let block_id = protocol::shm_control_block_id(&desc);
+                log::trace!("server revoked shm block {block_id}");
+            } else if desc.channel == u32::MAX {
+                self.handle_command(payload);
+            } else if let Some((tag, read_fd, write_fd)) = self.srb_setup.take() {
+                // The first memblock after the offer is the srbchannel
+                // itself, whatever its channel.
+                self.enable_srbchannel(&desc, payload, tag, read_fd, write_fd)?;
+            } else if desc.flags.contains(DescriptorFlags::FLAG_SHMDATA) {
+                self.handle_shm_memblock(&desc, payload)?;
+            } else {
+                // Stream data for a record stream.
+                let mut guard = self.state.lock().unwrap();
+                if let Some(RecordStreamState {
+                    sink, start_notify, ..
+                }) = guard.record_streams.get_mut(&desc.channel)
+                {
+                    log::trace!("reading {len} bytes from stream {}", desc.channel,);
+                    if let Some(start_notify) = start_notify.take() {
+                        let _ = start_notify.send(());
+                    }
+
+                    sink.write(payload)
+                } else {
+                    log::warn!("Received data for unknown record stream {}", desc.channel);
+                }
+            }
+
+            read_buf.buf.drain(..len);
+        }
+
+        Ok(())
+    }
+
+    /// Sets up the srbchannel from the memblock sent after
+    /// [Command::EnableSrbchannel](protocol::Command::EnableSrbchannel), and
+    /// acknowledges it.
+    fn enable_srbchannel(
+        &mut self,
+        desc: &protocol::Descriptor,
+        payload: &[u8],
+        tag: u32,
+        read_fd: OwnedFd,
+        write_fd: OwnedFd,
+    ) -> Result<(), ClientError> {
+        if !desc.flags.contains(DescriptorFlags::FLAG_SHMDATA) {
+            log::error!("srbchannel memblock not sent over shm");
+            return Ok(());
+        }
+
+        let shm_ref = protocol::read_shm_ref(&mut io::Cursor::new(payload))?;
+        let memfd = desc
+            .flags
+            .contains(DescriptorFlags::FLAG_SHMDATA_MEMFD_BLOCK);
+
+        let srb = self
+            .shm_imports
+            .attach_writable(&shm_ref, memfd)
+            .and_then(|block| Srbchannel::new(block, read_fd, write_fd))
+            .and_then(|srb| {
+                self.poll.registry().register(
+                    &mut mio::unix::SourceFd(&srb.read_fd()),
+                    SRBCHANNEL,
+                    mio::Interest::READABLE,
+                )?;
+
+                Ok(srb)
+            });
+
+        match srb {
+            Ok(srb) => {
+                log::debug!("using srbchannel in shm block {}", shm_ref.block_id);
+                log::debug!("CLIENT [{tag}]: EnableSrbchannel");
+                protocol::write_command_message(
+                    &mut self.write_buf,
+                    tag,
+                    &protocol::Command::EnableSrbchannel,
+                    self.protocol_version,
+                )?;
+
+                self.srb = Some(srb);
+            }
+            Err(err) => {
+                log::error!("failed to set up srbchannel: {err}");
+                protocol::write_shm_release(&mut self.write_buf, shm_ref.block_id)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Handles record stream data sent as a reference to the server's shm
+    /// pool. The data is copied to the sink, and the block is released right
+    /// away.
+    fn handle_shm_memblock(
+        &mut self,
+        desc: &protocol::Descriptor,
+        payload: &[u8],
+    ) -> Result<(), ClientError> {
+        let shm_ref = protocol::read_shm_ref(&mut io::Cursor::new(payload))?;
+
+        let memfd = desc
+            .flags
+            .contains(DescriptorFlags::FLAG_SHMDATA_MEMFD_BLOCK);
+
+        let mut guard = self.state.lock().unwrap();
+        if let Some(RecordStreamState {
+            sink, start_notify, ..
+        }) = guard.record_streams.get_mut(&desc.channel)
+        {
+            match self.shm_imports.get(&shm_ref, memfd) {
+                Ok(data) => {
+                    log::trace!(
+                        "reading {} bytes from stream {} (shm)",
+                        data.len(),
+                        desc.channel
+                    );
+
+                    if let Some(start_notify) = start_notify.take() {
+                        let _ = start_notify.send(());
+                    }
+
+                    sink.write(data)
+                }
+                Err(err) => log::error!("failed to read shm block: {err}"),
+            }
+        } else {
+            log::warn!("Received data for unknown record stream {}", desc.channel);
+        }
+
+        // The write buffer is always drained before new messages are encoded
+        // into it, so this is sent after anything already in progress.
+        protocol::write_shm_release(&mut self.write_buf, shm_ref.block_id)?;
+        Ok(())
+    }
+
+    fn handle_command(&mut self, payload: &[u8]) {
+        let mut cursor = io::Cursor::new(payload);
+        let (seq, cmd) =
+            match protocol::Command::read_tag_prefixed(&mut cursor, self.protocol_version) {
+                Ok((seq, cmd)) => (seq, cmd),
+                Err(err) => {
+                    log::error!("failed to read command message: {err}");
+                    return;
+                }
+            };
+
+        let mut state = self.state.lock().unwrap();
+
+        log::debug!("SERVER [{}]: {cmd:?}", seq as i32);
+        if matches!(cmd, protocol::Command::Reply | protocol::Command::Error(_)) {
+            // The handler is missing if the command timed out or its
+            // future was dropped.
+            let Some(handler) = state.remove_handler(seq) else {
+                log::debug!("no reply handler found for sequence {seq}");
+                return;
+            };
+
+            match cmd {
+                protocol::Command::Reply => handler(&mut state, Ok(&mut cursor)),
+                protocol::Command::Error(err) => {
+                    handler(&mut state, Err(ClientError::ServerError(err)))
+                }
+                _ => unreachable!(),
+            }
+            return;
+        }
+
+        match cmd {
+            protocol::Command::Started(channel) => {
+                if state.playback_streams.contains_key(&channel) {
+                    log::debug!("stream started: {channel}");
+                } else {
+                    log::error!("unknown stream: {channel}");
+                }
+            }
+            protocol::Command::Request(protocol::Request { channel, length }) => {
+                if let Some(stream) = state.playback_streams.get_mut(&channel) {
+                    stream.requested_bytes += length as usize;
+                } else {
+                    log::error!("unknown stream: {channel}");
+                }
+            }
+            protocol::Command::PlaybackStreamKilled(channel) => {
+                if let Some(stream) = state.playback_streams.remove(&channel) {
+                    log::debug!("stream killed: {channel}");
+                    stream.tracker.kill();
+                } else {
+                    log::error!("unknown stream: {channel}");
+                }
+            }
+            protocol::Command::RecordStreamKilled(channel) => {
+                if let Some(stream) = state.record_streams.remove(&channel) {
+                    log::debug!("stream killed: {channel}");
+                    stream.tracker.kill();
+                } else {
+                    log::error!("unknown stream: {channel}");
+                }
+            }
+            protocol::Command::Underflow(protocol::Underflow { channel, offset }) => {
+                if let Some(stream) = state.playback_streams.get_mut(&channel) {
+                    log::debug!("stream {channel} underflowed at offset {offset}");
+                    stream.tracker.apply(StreamEvent::Underflow { offset });
+                } else {
+                    log::error!("unknown stream: {channel}");
+                }
+            }
+            protocol::Command::Overflow(channel) => {
+                if let Some(stream) = state.playback_streams.get_mut(&channel) {
+                    log::debug!("stream {channel} overflowed");
+                    stream.tracker.apply(StreamEvent::Overflow);
+                } else {
+                    log::error!("unknown stream: {channel}");
+                }
+            }
+            protocol::Command::PlaybackStreamMoved(params) => {
+                if let Some(stream) = state.playback_streams.get_mut(&params.stream_index) {
+                    stream.tracker.apply(StreamEvent::Moved {
+                        device_index: params.device_index,
+                        device_name: params.device_name,
+                        device_suspended: params.device_suspended,
+                        buffer_attr: params.buffer_attr,
+                    });
+                } else {
+                    log::error!("unknown stream: {}", params.stream_index);
+                }
+            }
+            protocol::Command::RecordStreamMoved(params) => {
+                if let Some(stream) = state.record_streams.get_mut(&params.stream_index) {
+                    stream.tracker.apply(StreamEvent::Moved {
+                        device_index: params.device_index,
+                        device_name: params.device_name,
+                        device_suspended: params.device_suspended,
+                        buffer_attr: params.buffer_attr,
+                    });
+                } else {
+                    log::error!("unknown stream: {}", params.stream_index);
+                }
+            }
+            protocol::Command::PlaybackStreamSuspended(params) => {
+                if let Some(stream) = state.playback_streams.get_mut(&params.stream_index) {
+                    stream
+                        .tracker
+                        .apply(StreamEvent::Suspended(params.suspended));
+                } else {
+                    log::error!("unknown stream: {}", params.stream_index);
+                }
+            }
+            protocol::Command::RecordStreamSuspended(params) => {
+                if let Some(stream) = state.record_streams.get_mut(&params.stream_index) {
+                    stream
+                        .tracker
+                        .apply(StreamEvent::Suspended(params.suspended));
+                } else {
+                    log::error!("unknown stream: {}", params.stream_index);
+                }
+            }
+            protocol::Command::PlaybackBufferAttrChanged(params) => {
+                if let Some(stream) = state.playback_streams.get_mut(&params.stream_index) {
+                    stream
+                        .tracker
+                        .apply(StreamEvent::BufferAttrChanged(params.buffer_attr));
+                } else {
+                    log::error!("unknown stream: {}", params.stream_index);
+                }
+            }
+            protocol::Command::RecordBufferAttrChanged(params) => {
+                if let Some(stream) = state.record_streams.get_mut(&params.stream_index) {
+                    stream
+                        .tracker
+                        .apply(StreamEvent::BufferAttrChanged(params.buffer_attr));
+                } else {
+                    log::error!("unknown stream: {}", params.stream_index);
+                }
+            }
+            protocol::Command::SubscribeEvent(event) => {
+                let facility = event.event_facility.mask();
+                for subscriber in state.subscribers.values() {
+                    if subscriber.mask.contains(facility) {
+                        let _ = subscriber.events.unbounded_send(event);
+                    }
+                }
+            }
+            protocol::Command::Extension(protocol::ExtensionParams {
+                command:
+                    protocol::ExtensionCommand::StreamRestore(protocol::StreamRestoreCommand::Event),
+                ..
+            }) => {
+                for subscriber in state.stream_restore_subscribers.values() {
+                    let _ = subscriber.unbounded_send(());
+                }
+            }
+            protocol::Command::RegisterMemfdShmid(shm_id) => match self.fds.pop_front() {
+                Some(memfd) => {
+                    if let Err(err) = self.shm_imports.register_memfd(shm_id, memfd) {
+                        log::error!("failed to attach memfd segment {shm_id}: {err}");
+                    }
+                }
+                None => log::error!("no memfd passed for segment {shm_id}"),
+            },
+            protocol::Command::EnableSrbchannel => {
+                match (self.fds.pop_front(), self.fds.pop_front()) {
+                    (Some(read_fd), Some(write_fd)) => {
+                        self.srb_setup = Some((seq, read_fd, write_fd));
+                    }
+                    _ => log::error!("no eventfds passed for srbchannel"),
+                }
+            }
+            _ => log::debug!("ignoring unexpected command: {cmd:?}"),
+        }
+    }
+
+    /// Writes as much of the write buffer as possible, returning whether it
+    /// was drained completely.
+    fn drain_write_buf(&mut self) -> Result<bool, io::Error> {
+        match &mut self.srb {
+            Some(srb) if self.srb_writes => drain_buf(&mut self.write_buf, srb),
+            srb => {
+                let drained = drain_buf(&mut self.write_buf, &mut self.socket)?;
+                if drained && srb.is_some() {
+                    log::debug!("switching writes to srbchannel");
+                    self.srb_writes = true;
+                }
+
+                Ok(drained)
+            }
+        }
+    }
+
+    fn write_commands(&mut self) -> Result<(), ClientError> {
+        let queued = std::mem::take(&mut self.state.lock().unwrap().queued_commands);
+        for cmd in queued {
+            let seq = self.next_seq.fetch_add(1, atomic::Ordering::Relaxed);
+            self.encode_command(seq, &cmd)?;
+        }
+
+        loop {
+            // Drain the write buffer...
+            if !self.drain_write_buf()? || self.closing {
+                return Ok(());
             }
 
             // ...and encode new command messages into it.
             match self.outgoing.try_recv() {
-                Ok((seq, cmd)) => {
-                    log::debug!("CLIENT [{seq}]: {cmd:?}");
-                    protocol::encode_command_message(
-                        &mut self.write_buf,
-                        seq,
-                        &cmd,
-                        self.protocol_version,
-                    )?;
+                Ok(Outgoing::Command(seq, cmd)) => self.encode_command(seq, &cmd)?,
+                Ok(Outgoing::Memblock(channel, data)) => {
+                    log::trace!("writing {} bytes to stream {channel}", data.len());
+                    protocol::write_memblock(&mut self.write_buf, channel, &data, 0)?;
                 }
+                Ok(Outgoing::Close) => self.closing = true,
                 Err(TryRecvError::Empty) => return Ok(()),
-                Err(TryRecvError::Disconnected) => return Err(ClientError::Disconnected),
+                // Every handle was dropped, so there's nothing left to do.
+                Err(TryRecvError::Disconnected) => {
+                    self.closing = true;
+                    return Ok(());
+                }
             };
         }
     }
 
     fn write_streams(&mut self) -> Result<(), ClientError> {
-        if !drain_buf(&mut self.write_buf, &mut self.socket)? {
+        if !self.drain_write_buf()? {
             return Ok(());
         }
 
-        let mut state = self.state.lock().unwrap();
+        let state = self.state.clone();
+        let mut state = state.lock().unwrap();
         for stream in state.playback_streams.values_mut() {
             if stream.done {
                 continue;
@@ -482,12 +1629,24 @@ impl Reactor {
             while stream.requested_bytes > 0 {
                 let requested = stream.requested_bytes;
 
-                self.write_buf
-                    .resize(protocol::DESCRIPTOR_SIZE + requested, 0);
+                // If we have a free shm block, read directly into that.
+                // Otherwise, read into the write buffer after the descriptor.
+                let block = self.shm.as_mut().and_then(ShmPool::alloc);
+                let buf = match (block, &mut self.shm) {
+                    (Some(block_id), Some(pool)) => {
+                        let buf = pool.block_mut(block_id);
+                        let n = buf.len().min(requested);
+                        &mut buf[..n]
+                    }
+                    _ => {
+                        self.write_buf
+                            .resize(protocol::DESCRIPTOR_SIZE + requested, 0);
+                        &mut self.write_buf[protocol::DESCRIPTOR_SIZE..]
+                    }
+                };
 
                 let waker = futures::task::waker(self.waker.clone());
                 let mut cx = Context::from_waker(&waker);
-                let buf = &mut self.write_buf[protocol::DESCRIPTOR_SIZE..];
                 let len = match PlaybackSource::poll_read(stream.source.as_mut(), &mut cx, buf) {
                     Poll::Ready(0) => {
                         log::debug!(
@@ -497,22 +1656,16 @@ impl Reactor {
 
                         stream.done = true;
                         stream.eof_notify.take().map(|done| done.send(()));
-                        self.write_buf.clear();
-                        break;
+                        0
                     }
-                    Poll::Pending => {
-                        self.write_buf.clear();
-                        break;
-                    }
-                    Poll::Ready(n) => n,
+                    Poll::Pending => 0,
+                    Poll::Ready(n) => n.min(requested),
                 };
 
-                let len = len.min(requested);
                 if len == 0 {
-                    log::debug!(
-                        "callback for stream {} returned no data",
-                        stream.stream_info.channel
-                    );
+                    if let (Some(block_id), Some(pool)) = (block, &mut self.shm) {
+                        pool.release(block_id);
+                    }
 
                     self.write_buf.clear();
                     break;
@@ -524,24 +1677,35 @@ impl Reactor {
                     stream.requested_bytes
                 );
 
-                self.write_buf.truncate(protocol::DESCRIPTOR_SIZE + len);
                 stream.requested_bytes -= len;
 
-                let desc = protocol::Descriptor {
-                    length: len as u32,
-                    channel: stream.stream_info.channel,
-                    offset: 0,
-                    flags: DescriptorFlags::empty(),
-                };
-
-                protocol::encode_descriptor(
-                    (&mut self.write_buf[..protocol::DESCRIPTOR_SIZE])
-                        .try_into()
-                        .unwrap(),
-                    &desc,
-                );
+                if let (Some(block_id), Some(pool)) = (block, &self.shm) {
+                    protocol::write_shm_memblock(
+                        &mut self.write_buf,
+                        stream.stream_info.channel,
+                        &pool.shm_ref(block_id, len),
+                        0,
+                        pool.flags(),
+                    )?;
+                } else {
+                    self.write_buf.truncate(protocol::DESCRIPTOR_SIZE + len);
+
+                    let desc = protocol::Descriptor {
+                        length: len as u32,
+                        channel: stream.stream_info.channel,
+                        offset: 0,
+                        flags: DescriptorFlags::empty(),
+                    };
+
+                    protocol::encode_descriptor(
+                        (&mut self.write_buf[..protocol::DESCRIPTOR_SIZE])
+                            .try_into()
+                            .unwrap(),
+                        &desc,
+                    );
+                }
 
-                if !drain_buf(&mut self.write_buf, &mut self.socket)? {
+                if !self.drain_write_buf()? {
                     return Ok(());
                 }
             }
@@ -551,6 +1715,128 @@ impl Reactor {
     }
 }
 
+/// Handles the reply to recreating a playback stream after reconnecting.
+fn playback_stream_recreated(
+    state: &mut ReactorState,
+    mut stream: PlaybackStreamState,
+    res: ReplyResult<'_>,
+) {
+    let info: protocol::CreatePlaybackStreamReply =
+        match res.and_then(|buf| read_tagstruct(buf, state.protocol_version)) {
+            Ok(info) => info,
+            // Try again once the connection is back.
+            Err(ClientError::Disconnected) => {
+                state.lost_playback_streams.push(stream);
+                return;
+            }
+            Err(err) => {
+                log::error!("failed to recreate playback stream: {err}");
+                stream.tracker.kill();
+                return;
+            }
+        };
+
+    log::debug!("recreated playback stream as {}", info.channel);
+    stream.tracker.recreated(
+        info.channel,
+        info.stream_index,
+        info.sample_spec,
+        info.sink_index,
+        info.buffer_attr,
+        info.suspended,
+    );
+
+    stream.requested_bytes = info.requested_bytes as usize;
+    stream.stream_info = info;
+    state
+        .playback_streams
+        .insert(stream.stream_info.channel, stream);
+}
+
+/// Handles a reply to creating a playback stream that arrives after the
+/// request timed out. Nobody is waiting for the stream, so it's deleted again.
+fn delete_late_playback_stream(state: &mut ReactorState, res: ReplyResult<'_>) {
+    let res = res.and_then(|buf| {
+        read_tagstruct::<protocol::CreatePlaybackStreamReply>(buf, state.protocol_version)
+    });
+
+    if let Ok(info) = res {
+        log::debug!("deleting late playback stream {}", info.channel);
+        state.queue_command(protocol::Command::DeletePlaybackStream(info.channel));
+    }
+}
+
+/// Like [delete_late_playback_stream], but for record streams.
+fn delete_late_record_stream(state: &mut ReactorState, res: ReplyResult<'_>) {
+    let res = res.and_then(|buf| {
+        read_tagstruct::<protocol::CreateRecordStreamReply>(buf, state.protocol_version)
+    });
+
+    if let Ok(info) = res {
+        log::debug!("deleting late record stream {}", info.channel);
+        state.queue_command(protocol::Command::DeleteRecordStream(info.channel));
+    }
+}
+
+/// Handles the reply to renewing a subscription after reconnecting.
+fn resubscribed(what: &'static str) -> ReplyHandler {
+    Box::new(move |_, res| match res {
+        // Renewed again once the connection is back.
+        Ok(_) | Err(ClientError::Disconnected) => (),
+        Err(err) => log::error!("failed to renew {what}: {err}"),
+    })
+}
+
+/// Handles the reply to recreating a record stream after reconnecting.
+fn record_stream_recreated(
+    state: &mut ReactorState,
+    mut stream: RecordStreamState,
+    res: ReplyResult<'_>,
+) {
+    let info: protocol::CreateRecordStreamReply =
+        match res.and_then(|buf| read_tagstruct(buf, state.protocol_version)) {
+            Ok(info) => info,
+            Err(ClientError::Disconnected) => {
+                state.lost_record_streams.push(stream);
+                return;
+            }
+            Err(err) => {
+                log::error!("failed to recreate record stream: {err}");
+                stream.tracker.kill();
+                return;
+            }
+        };
+
+    log::debug!("recreated record stream as {}", info.channel);
+    stream.tracker.recreated(
+        info.channel,
+        info.stream_index,
+        info.sample_spec,
+        info.sink_index,
+        info.buffer_attr,
+        info.suspended,
+    );
+
+    state.record_streams.insert(info.channel, stream);
+}
+
+/// Exponential backoff between reconnection attempts.
+struct Backoff(Duration);
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Self(RECONNECT_MIN_DELAY)
+    }
+}
+
+impl Backoff {
+    fn next_delay(&mut self) -> Duration {
+        let delay = self.0;
+        self.0 = (delay * 2).min(RECONNECT_MAX_DELAY);
+        delay
+    }
+}
+
 fn drain_buf(buf: &mut Vec<u8>, w: &mut impl io::Write) -> Result<bool, io::Error> {
     while !buf.is_empty() {
         match w.write(buf) {
@@ -572,3 +1858,607 @@ fn read_tagstruct<R: protocol::CommandReply>(
         .read()
         .map_err(Into::into)
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        ffi::CString,
+        io::{BufReader, Read as _},
+        net::{Ipv4Addr, TcpListener, TcpStream},
+        thread::JoinHandle,
+        time::Duration,
+    };
+
+    use futures::{FutureExt as _, StreamExt as _, executor::block_on};
+
+    use super::{super::test_util, *};
+    use crate::Client;
+
+    type ServerConn = BufReader<TcpStream>;
+
+    /// Starts a server which handles the handshake, and then hands the
+    /// connection to `f`.
+    fn fake_server<T: Send + 'static>(
+        f: impl FnOnce(&mut ServerConn) -> anyhow::Result<T> + Send + 'static,
+    ) -> anyhow::Result<(TcpStream, JoinHandle<anyhow::Result<T>>)> {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0))?;
+        let addr = listener.local_addr()?;
+
+        let server = std::thread::spawn(move || -> anyhow::Result<T> {
+            let mut sock = accept_client(&listener)?;
+            f(&mut sock)
+        });
+
+        Ok((TcpStream::connect(addr)?, server))
+    }
+
+    /// Accepts a connection, and handles the handshake.
+    fn accept_client(listener: &TcpListener) -> anyhow::Result<ServerConn> {
+        let (sock, _) = listener.accept()?;
+        let mut sock = BufReader::new(sock);
+        test_util::serve_handshake(&mut sock, false, false, |_| Ok(()))?;
+
+        Ok(sock)
+    }
+
+    fn playback_params() -> protocol::PlaybackStreamParams {
+        protocol::PlaybackStreamParams {
+            sample_spec: protocol::SampleSpec {
+                format: protocol::SampleFormat::S16Le,
+                channels: 2,
+                sample_rate: 44100,
+            },
+            channel_map: protocol::ChannelMap::stereo(),
+            ..Default::default()
+        }
+    }
+
+    /// Replies to a [protocol::Command::CreatePlaybackStream].
+    fn create_playback_stream(
+        sock: &mut ServerConn,
+        channel: u32,
+        stream_index: u32,
+    ) -> anyhow::Result<protocol::PlaybackStreamParams> {
+        let version = protocol::MAX_VERSION;
+        let (seq, cmd) = protocol::read_command_message(sock, version)?;
+        let protocol::Command::CreatePlaybackStream(params) = cmd else {
+            anyhow::bail!("expected stream creation, got {cmd:?}");
+        };
+
+        let reply = protocol::CreatePlaybackStreamReply {
+            channel,
+            stream_index,
+            sample_spec: params.sample_spec,
+            channel_map: params.channel_map,
+            sink_name: Some(CString::new("fake")?),
+            ..Default::default()
+        };
+        protocol::write_reply_message(sock.get_mut(), seq, &reply, version)?;
+
+        Ok(params)
+    }
+
+    /// Acknowledges a [protocol::Command::Subscribe].
+    fn subscribe(sock: &mut ServerConn) -> anyhow::Result<protocol::SubscriptionMask> {
+        let (seq, cmd) = protocol::read_command_message(sock, protocol::MAX_VERSION)?;
+        let protocol::Command::Subscribe(mask) = cmd else {
+            anyhow::bail!("expected subscribe, got {cmd:?}");
+        };
+
+        protocol::write_ack_message(sock.get_mut(), seq)?;
+        Ok(mask)
+    }
+
+    /// A server which reads commands without ever replying.
+    fn silent_server() -> anyhow::Result<TcpStream> {
+        let (sock, _) = fake_server::<()>(|sock| {
+            loop {
+                protocol::read_command_message(sock, protocol::MAX_VERSION)?;
+            }
+        })?;
+
+        Ok(sock)
+    }
+
+    fn pending_handlers(client: &Client) -> usize {
+        let state = client.handle.state.upgrade().unwrap();
+        let state = state.lock().unwrap();
+        assert_eq!(state.handlers.len(), state.deadlines.len());
+        state.handlers.len()
+    }
+
+    #[test]
+    fn request_timeout() -> anyhow::Result<()> {
+        let mut client = Client::new_tcp(c"timeout-test", silent_server()?, None::<Vec<u8>>)?;
+        client.set_request_timeout(Some(Duration::from_millis(50)));
+
+        let res = block_on(client.server_info());
+        assert!(matches!(res, Err(ClientError::Timeout)), "{res:?}");
+        assert_eq!(pending_handlers(&client), 0);
+
+        Ok(())
+    }
+
+    #[test]
+    fn cancelled_request() -> anyhow::Result<()> {
+        let client = Client::new_tcp(c"cancel-test", silent_server()?, None::<Vec<u8>>)?;
+
+        // Poll once, so that the command is sent, and then drop the future.
+        assert!(client.server_info().now_or_never().is_none());
+        assert!(client.list_sinks().now_or_never().is_none());
+        assert_eq!(pending_handlers(&client), 0);
+
+        // Streams are deleted the same way when they're dropped, but the
+        // handler has to stick around to clean up the stream state.
+        assert!(
+            client
+                .handle
+                .delete_playback_stream(0)
+                .now_or_never()
+                .is_none()
+        );
+        assert_eq!(pending_handlers(&client), 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn cancelled_subscription() -> anyhow::Result<()> {
+        let client = Client::new_tcp(c"cancel-sub-test", silent_server()?, None::<Vec<u8>>)?;
+
+        let subscribe = client.subscribe(protocol::SubscriptionMask::SINK);
+        assert!(subscribe.now_or_never().is_none());
+        assert!(client.subscribe_stream_restore().now_or_never().is_none());
+
+        let state = client.handle.state.upgrade().unwrap();
+        let state = state.lock().unwrap();
+        assert!(state.subscription_mask().is_empty());
+        assert!(state.stream_restore_subscribers.is_empty());
+
+        Ok(())
+    }
+
+    /// A server which creates a playback stream once told to, and then
+    /// expects the client to delete it again.
+    fn late_stream_server(
+        create: Receiver<()>,
+        deleted: Sender<()>,
+    ) -> anyhow::Result<(TcpStream, JoinHandle<anyhow::Result<()>>)> {
+        fake_server(move |sock| {
+            let version = protocol::MAX_VERSION;
+
+            create.recv()?;
+            create_playback_stream(sock, 7, 0)?;
+
+            let (seq, cmd) = protocol::read_command_message(sock, version)?;
+            assert!(
+                matches!(cmd, protocol::Command::DeletePlaybackStream(7)),
+                "{cmd:?}"
+            );
+            protocol::write_ack_message(sock.get_mut(), seq)?;
+            deleted.send(())?;
+
+            test_util::read_until_hangup(sock);
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn cancelled_stream_creation() -> anyhow::Result<()> {
+        let (create_tx, create_rx) = std::sync::mpsc::channel();
+        let (deleted_tx, deleted_rx) = std::sync::mpsc::channel();
+        let (sock, server) = late_stream_server(create_rx, deleted_tx)?;
+        let client = Client::new_tcp(c"cancel-stream-test", sock, None::<Vec<u8>>)?;
+
+        // The server creates the stream after the future is dropped.
+        let create = client.create_playback_stream(playback_params(), &[][..]);
+        assert!(create.now_or_never().is_none());
+        create_tx.send(())?;
+        deleted_rx.recv_timeout(Duration::from_secs(5))?;
+
+        drop(client);
+        server.join().unwrap()
+    }
+
+    #[test]
+    fn timed_out_stream_creation() -> anyhow::Result<()> {
+        let (create_tx, create_rx) = std::sync::mpsc::channel();
+        let (deleted_tx, deleted_rx) = std::sync::mpsc::channel();
+        let (sock, server) = late_stream_server(create_rx, deleted_tx)?;
+        let mut client = Client::new_tcp(c"timeout-stream-test", sock, None::<Vec<u8>>)?;
+        client.set_request_timeout(Some(Duration::from_millis(50)));
+
+        let res = block_on(client.create_playback_stream(playback_params(), &[][..]));
+        assert!(matches!(res, Err(ClientError::Timeout)), "{res:?}");
+        create_tx.send(())?;
+        deleted_rx.recv_timeout(Duration::from_secs(5))?;
+
+        drop(client);
+        server.join().unwrap()
+    }
+
+    #[test]
+    fn upload_sample() -> anyhow::Result<()> {
+        let (sock, server) = fake_server(|sock| {
+            let version = protocol::MAX_VERSION;
+
+            let (seq, cmd) = protocol::read_command_message(sock, version)?;
+            let protocol::Command::CreateUploadStream(params) = cmd else {
+                anyhow::bail!("expected upload stream creation, got {cmd:?}");
+            };
+
+            let reply = protocol::CreateUploadStreamReply {
+                channel: 2,
+                length: params.length,
+            };
+            protocol::write_reply_message(sock.get_mut(), seq, &reply, version)?;
+
+            let mut chunk_sizes = Vec::new();
+            while chunk_sizes.iter().sum::<usize>() < params.length as usize {
+                let desc = protocol::read_descriptor(sock)?;
+                assert_eq!(desc.channel, 2);
+
+                let mut chunk = vec![0; desc.length as usize];
+                sock.read_exact(&mut chunk)?;
+                chunk_sizes.push(chunk.len());
+            }
+
+            let (seq, cmd) = protocol::read_command_message(sock, version)?;
+            assert!(
+                matches!(cmd, protocol::Command::FinishUploadStream(2)),
+                "{cmd:?}"
+            );
+            protocol::write_ack_message(sock.get_mut(), seq)?;
+
+            Ok((params.length, chunk_sizes))
+        })?;
+
+        let client = Client::new_tcp(c"upload-test", sock, None::<Vec<u8>>)?;
+        let data = vec![1; UPLOAD_CHUNK_SIZE * 2 + 100];
+        let params = playback_params();
+        block_on(client.upload_sample(
+            CString::new("sample")?,
+            params.sample_spec,
+            params.channel_map,
+            protocol::Props::new(),
+            futures::io::Cursor::new(data.clone()),
+        ))?;
+
+        let (length, chunk_sizes) = server.join().unwrap()?;
+        assert_eq!(length as usize, data.len());
+        assert_eq!(chunk_sizes, [UPLOAD_CHUNK_SIZE, UPLOAD_CHUNK_SIZE, 100]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn default_sink_removed() -> anyhow::Result<()> {
+        let (sock, server) = fake_server(|sock| {
+            let version = protocol::MAX_VERSION;
+            subscribe(sock)?;
+
+            let (seq, cmd) = protocol::read_command_message(sock, version)?;
+            assert!(matches!(cmd, protocol::Command::GetSinkInfo(_)), "{cmd:?}");
+            let reply = protocol::SinkInfo {
+                index: 1,
+                name: CString::new("fake")?,
+                ..Default::default()
+            };
+            protocol::write_reply_message(sock.get_mut(), seq, &reply, version)?;
+
+            let event = protocol::SubscriptionEvent {
+                event_facility: protocol::SubscriptionEventFacility::Sink,
+                event_type: protocol::SubscriptionEventType::Removed,
+                index: Some(1),
+            };
+            protocol::write_command_message(
+                sock.get_mut(),
+                u32::MAX,
+                &protocol::Command::SubscribeEvent(event),
+                version,
+            )?;
+
+            let (seq, cmd) = protocol::read_command_message(sock, version)?;
+            assert!(matches!(cmd, protocol::Command::GetSinkInfo(_)), "{cmd:?}");
+            protocol::write_error(sock.get_mut(), seq, &protocol::PulseError::NoEntity)?;
+
+            test_util::read_until_hangup(sock);
+            Ok(())
+        })?;
+
+        let client = Client::new_tcp(c"default-sink-test", sock, None::<Vec<u8>>)?;
+        let mut watch = block_on(client.watch_default_sink())?;
+
+        let sink = block_on(watch.next()).unwrap()?;
+        assert_eq!(sink.map(|sink| sink.index), Some(1));
+        assert_eq!(block_on(watch.next()).unwrap()?, None);
+
+        drop(watch);
+        drop(client);
+        server.join().unwrap()
+    }
+
+    #[test]
+    fn stream_restore_events() -> anyhow::Result<()> {
+        let (sock, server) = fake_server(|sock| {
+            let version = protocol::MAX_VERSION;
+
+            let (seq, cmd) = protocol::read_command_message(sock, version)?;
+            protocol::write_ack_message(sock.get_mut(), seq)?;
+            assert_eq!(
+                cmd,
+                protocol::Command::Extension(protocol::ExtensionParams::stream_restore(
+                    protocol::StreamRestoreCommand::Subscribe(true)
+                ))
+            );
+
+            protocol::write_command_message(
+                sock.get_mut(),
+                u32::MAX,
+                &protocol::Command::Extension(protocol::ExtensionParams::stream_restore(
+                    protocol::StreamRestoreCommand::Event,
+                )),
+                version,
+            )?;
+
+            // The client doesn't wait for the reply to unsubscribing.
+            let (_, cmd) = protocol::read_command_message(sock, version)?;
+            assert_eq!(
+                cmd,
+                protocol::Command::Extension(protocol::ExtensionParams::stream_restore(
+                    protocol::StreamRestoreCommand::Subscribe(false)
+                ))
+            );
+
+            test_util::read_until_hangup(sock);
+            Ok(())
+        })?;
+
+        let client = Client::new_tcp(c"stream-restore-test", sock, None::<Vec<u8>>)?;
+        let mut events = block_on(client.subscribe_stream_restore())?;
+        assert_eq!(block_on(events.next()), Some(()));
+
+        drop(events);
+        drop(client);
+        server.join().unwrap()
+    }
+
+    #[test]
+    fn close() -> anyhow::Result<()> {
+        let (sock, server) = fake_server(|sock| {
+            let version = protocol::MAX_VERSION;
+
+            create_playback_stream(sock, 7, 0)?;
+
+            // Read everything else until the client hangs up.
+            let mut commands = Vec::new();
+            while let Ok((seq, cmd)) = protocol::read_command_message(sock, version) {
+                if matches!(cmd, protocol::Command::DeletePlaybackStream(_)) {
+                    protocol::write_ack_message(sock.get_mut(), seq)?;
+                }
+
+                commands.push(cmd);
+            }
+
+            Ok(commands)
+        })?;
+
+        let client = Client::new_tcp(c"close-test", sock, None::<Vec<u8>>)?;
+        let _stream = block_on(client.create_playback_stream(playback_params(), &[][..]))?;
+        block_on(client.close()).unwrap();
+        assert!(block_on(client.closed()).is_ok());
+        assert!(matches!(
+            block_on(client.server_info()),
+            Err(ClientError::Disconnected)
+        ));
+
+        let commands = server.join().unwrap()?;
+        assert!(
+            matches!(&commands[..], [protocol::Command::DeletePlaybackStream(7)]),
+            "{commands:?}"
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn closed_with_error() -> anyhow::Result<()> {
+        let (sock, _) = fake_server(|sock| {
+            // An shm memblock without a reference to a block.
+            protocol::write_descriptor(
+                sock.get_mut(),
+                &protocol::Descriptor {
+                    length: 0,
+                    channel: 0,
+                    offset: 0,
+                    flags: DescriptorFlags::FLAG_SHMDATA,
+                },
+            )?;
+
+            Ok(())
+        })?;
+
+        let client = Client::new_tcp(c"closed-test", sock, None::<Vec<u8>>)?;
+        let err = block_on(client.closed()).unwrap_err();
+        assert!(matches!(*err, ClientError::Protocol(_)), "{err:?}");
+
+        let err = block_on(client.close()).unwrap_err();
+        assert!(matches!(*err, ClientError::Protocol(_)), "{err:?}");
+
+        Ok(())
+    }
+
+    #[test]
+    fn reconnect() -> anyhow::Result<()> {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0))?;
+        let addr = listener.local_addr()?;
+        let (hangup_tx, hangup_rx) = std::sync::mpsc::channel::<()>();
+
+        let server = std::thread::spawn(move || -> anyhow::Result<()> {
+            let version = protocol::MAX_VERSION;
+
+            let mut sock = accept_client(&listener)?;
+            let params = create_playback_stream(&mut sock, 3, 1)?;
+            subscribe(&mut sock)?;
+
+            // Restart the server.
+            hangup_rx.recv()?;
+            drop(sock);
+            drop(listener);
+            std::thread::sleep(Duration::from_millis(50));
+
+            let listener = TcpListener::bind(addr)?;
+            let mut sock = accept_client(&listener)?;
+            assert_eq!(create_playback_stream(&mut sock, 5, 9)?, params);
+            assert_eq!(subscribe(&mut sock)?, protocol::SubscriptionMask::SINK);
+
+            let (seq, cmd) = protocol::read_command_message(&mut sock, version)?;
+            assert!(matches!(cmd, protocol::Command::GetServerInfo), "{cmd:?}");
+            let reply = protocol::ServerInfo {
+                server_name: Some(CString::new("restarted")?),
+                ..Default::default()
+            };
+            protocol::write_reply_message(sock.get_mut(), seq, &reply, version)?;
+
+            // Acknowledge the stream deletion until the client hangs up.
+            while let Ok((seq, cmd)) = protocol::read_command_message(&mut sock, version) {
+                assert!(
+                    matches!(cmd, protocol::Command::DeletePlaybackStream(5)),
+                    "{cmd:?}"
+                );
+                protocol::write_ack_message(sock.get_mut(), seq)?;
+            }
+
+            Ok(())
+        });
+
+        let client = Client::connect_reconnecting(
+            c"reconnect-test",
+            crate::ServerAddress::Tcp {
+                host: addr.ip().to_string(),
+                port: addr.port(),
+                family: None,
+            },
+            None::<Vec<u8>>,
+        )?;
+
+        let mut reconnects = client.reconnects();
+        let stream = block_on(client.create_playback_stream(playback_params(), &[][..]))?;
+        let _subscription = block_on(client.subscribe(protocol::SubscriptionMask::SINK))?;
+        let mut events = stream.events();
+        assert_eq!(stream.channel(), 3);
+
+        hangup_tx.send(())?;
+        assert_eq!(block_on(reconnects.next()), Some(()));
+        assert_eq!(block_on(events.next()), Some(StreamEvent::Recreated));
+        assert_eq!(stream.channel(), 5);
+        assert_eq!(stream.stream_index(), 9);
+
+        let info = block_on(client.server_info())?;
+        assert_eq!(info.server_name.as_deref(), Some(c"restarted"));
+
+        drop(stream);
+        block_on(client.close()).unwrap();
+        server.join().unwrap()
+    }
+
+    #[test]
+    fn reconnect_failed_restore() -> anyhow::Result<()> {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0))?;
+        let addr = listener.local_addr()?;
+        let (hangup_tx, hangup_rx) = std::sync::mpsc::channel::<()>();
+
+        let server = std::thread::spawn(move || -> anyhow::Result<()> {
+            let version = protocol::MAX_VERSION;
+
+            let mut sock = accept_client(&listener)?;
+            subscribe(&mut sock)?;
+
+            hangup_rx.recv()?;
+            drop(sock);
+
+            // Reject the renewed subscription.
+            let mut sock = accept_client(&listener)?;
+            let (seq, cmd) = protocol::read_command_message(&mut sock, version)?;
+            assert!(matches!(cmd, protocol::Command::Subscribe(_)), "{cmd:?}");
+            protocol::write_error(sock.get_mut(), seq, &protocol::PulseError::AccessDenied)?;
+
+            let (seq, cmd) = protocol::read_command_message(&mut sock, version)?;
+            assert!(matches!(cmd, protocol::Command::GetServerInfo), "{cmd:?}");
+            protocol::write_reply_message(
+                sock.get_mut(),
+                seq,
+                &protocol::ServerInfo::default(),
+                version,
+            )?;
+
+            test_util::read_until_hangup(&mut sock);
+            Ok(())
+        });
+
+        let client = Client::connect_reconnecting(
+            c"failed-restore-test",
+            crate::ServerAddress::Tcp {
+                host: addr.ip().to_string(),
+                port: addr.port(),
+                family: None,
+            },
+            None::<Vec<u8>>,
+        )?;
+
+        let mut reconnects = client.reconnects();
+        let _subscription = block_on(client.subscribe(protocol::SubscriptionMask::SINK))?;
+        hangup_tx.send(())?;
+
+        // Commands fail until the connection is back.
+        let mut res = block_on(client.server_info());
+        while matches!(res, Err(ClientError::Disconnected)) {
+            std::thread::sleep(Duration::from_millis(10));
+            res = block_on(client.server_info());
+        }
+        res?;
+
+        // Replies arrive in order, so the restore has finished by now, and
+        // the failure doesn't hold up the event.
+        assert_eq!(reconnects.next().now_or_never(), Some(Some(())));
+
+        block_on(client.close()).unwrap();
+        server.join().unwrap()
+    }
+
+    #[test]
+    fn close_while_reconnecting() -> anyhow::Result<()> {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0))?;
+        let addr = listener.local_addr()?;
+        let (accepted_tx, accepted_rx) = std::sync::mpsc::channel();
+        let (closed_tx, closed_rx) = std::sync::mpsc::channel::<()>();
+
+        // Hang up right after the handshake, and then never finish the next
+        // one.
+        let server = std::thread::spawn(move || -> anyhow::Result<()> {
+            drop(accept_client(&listener)?);
+            let _sock = listener.accept()?;
+            accepted_tx.send(())?;
+            closed_rx.recv()?;
+            Ok(())
+        });
+
+        let client = Client::connect_reconnecting(
+            c"close-reconnecting-test",
+            crate::ServerAddress::Tcp {
+                host: addr.ip().to_string(),
+                port: addr.port(),
+                family: None,
+            },
+            None::<Vec<u8>>,
+        )?;
+
+        accepted_rx.recv_timeout(Duration::from_secs(5))?;
+        let start = Instant::now();
+        block_on(client.close()).unwrap();
+        assert!(start.elapsed() < Duration::from_secs(1));
+
+        closed_tx.send(())?;
+        server.join().unwrap()
+    }
+}
@@ -0,0 +1,32 @@
+use std::{
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use futures::{Stream, channel::mpsc};
+
+/// A stream of events, one for each time the client reconnected to the
+/// server, created with [Client::reconnects](super::Client::reconnects).
+///
+/// The stream ends once the client is closed or fails.
+pub struct Reconnects(mpsc::UnboundedReceiver<()>);
+
+impl std::fmt::Debug for Reconnects {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("Reconnects").finish()
+    }
+}
+
+impl Reconnects {
+    pub(super) fn new(events: mpsc::UnboundedReceiver<()>) -> Self {
+        Self(events)
+    }
+}
+
+impl Stream for Reconnects {
+    type Item = ();
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        Pin::new(&mut self.0).poll_next(cx)
+    }
+}
@@ -0,0 +1,351 @@
+use std::{
+    collections::BTreeMap,
+    pin::Pin,
+    task::{Context, Poll, ready},
+};
+
+use futures::{FutureExt as _, Stream, StreamExt as _, future::BoxFuture};
+
+use super::{Client, ClientError, Result as ClientResult, Subscription};
+use crate::protocol::{self, SubscriptionEventFacility as Facility};
+
+/// An object tracked by a [ServerState].
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum ServerObject {
+    /// The server itself, including the default sink and source.
+    Server(protocol::ServerInfo),
+    /// A sink.
+    Sink(protocol::SinkInfo),
+    /// A source.
+    Source(protocol::SourceInfo),
+    /// A sink input, i.e. a playback stream.
+    SinkInput(protocol::SinkInputInfo),
+    /// A source output, i.e. a record stream.
+    SourceOutput(protocol::SourceOutputInfo),
+    /// A connected client.
+    Client(protocol::ClientInfo),
+    /// A card.
+    Card(protocol::CardInfo),
+    /// A loaded module.
+    Module(protocol::ModuleInfo),
+}
+
+/// A change to a [ServerState], yielded by its [Stream] implementation.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ServerChange {
+    /// The kind of object that changed.
+    pub facility: protocol::SubscriptionEventFacility,
+    /// The index of the object that changed. This is `None` for changes to the
+    /// server info.
+    pub index: Option<u32>,
+    /// The previous value of the object, or `None` if it's new.
+    pub old: Option<ServerObject>,
+    /// The current value of the object, or `None` if it was removed.
+    pub new: Option<ServerObject>,
+}
+
+type PendingFetch = BoxFuture<
+    'static,
+    (
+        protocol::SubscriptionEvent,
+        ClientResult<Option<ServerObject>>,
+    ),
+>;
+
+/// A local mirror of the objects on the server, kept up to date using a
+/// [Subscription].
+///
+/// The state only changes while the [Stream] of [ServerChanges](ServerChange)
+/// is polled. Each event from the server causes the affected object to be
+/// fetched again by index, and removed objects are dropped. The stream ends
+/// if the client disconnects.
+///
+/// ```no_run
+/// # use pulseaudio::{Client, ServerState};
+/// # use futures::StreamExt as _;
+/// # async fn run(client: Client) -> Result<(), pulseaudio::ClientError> {
+/// let mut state = ServerState::new(client).await?;
+///
+/// while let Some(change) = state.next().await {
+///     let change = change?;
+///     println!("{:?}: {:?} -> {:?}", change.facility, change.old, change.new);
+///     println!("there are now {} sinks", state.sinks().len());
+/// }
+/// # Ok(())
+/// # }
+/// ```
+pub struct ServerState {
+    client: Client,
+    subscription: Subscription,
+    pending: Option<PendingFetch>,
+    server_info: protocol::ServerInfo,
+    sinks: BTreeMap<u32, protocol::SinkInfo>,
+    sources: BTreeMap<u32, protocol::SourceInfo>,
+    sink_inputs: BTreeMap<u32, protocol::SinkInputInfo>,
+    source_outputs: BTreeMap<u32, protocol::SourceOutputInfo>,
+    clients: BTreeMap<u32, protocol::ClientInfo>,
+    cards: BTreeMap<u32, protocol::CardInfo>,
+    modules: BTreeMap<u32, protocol::ModuleInfo>,
+}
+
+impl std::fmt::Debug for ServerState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ServerState")
+            .field("client", &self.client)
+            .field("server_info", &self.server_info)
+            .field("sinks", &self.sinks)
+            .field("sources", &self.sources)
+            .field("sink_inputs", &self.sink_inputs)
+            .field("source_outputs", &self.source_outputs)
+            .field("clients", &self.clients)
+            .field("cards", &self.cards)
+            .field("modules", &self.modules)
+            .finish_non_exhaustive()
+    }
+}
+
+impl ServerState {
+    /// Subscribes to changes, and then fetches the current state of the
+    /// server.
+    pub async fn new(client: Client) -> ClientResult<Self> {
+        // Subscribe first, so that no changes are missed between fetching
+        // and subscribing.
+        let subscription = client.subscribe(Self::mask()).await?;
+
+        let (server_info, sinks, sources, sink_inputs, source_outputs, clients, cards, modules) = futures::try_join!(
+            client.server_info(),
+            client.list_sinks(),
+            client.list_sources(),
+            client.list_sink_inputs(),
+            client.list_source_outputs(),
+            client.list_clients(),
+            client.list_cards(),
+            client.list_modules(),
+        )?;
+
+        Ok(Self {
+            client,
+            subscription,
+            pending: None,
+            server_info,
+            sinks: sinks.into_iter().map(|v| (v.index, v)).collect(),
+            sources: sources.into_iter().map(|v| (v.index, v)).collect(),
+            sink_inputs: sink_inputs.into_iter().map(|v| (v.index, v)).collect(),
+            source_outputs: source_outputs.into_iter().map(|v| (v.index, v)).collect(),
+            clients: clients.into_iter().map(|v| (v.index, v)).collect(),
+            cards: cards.into_iter().map(|v| (v.index, v)).collect(),
+            modules: modules.into_iter().map(|v| (v.index, v)).collect(),
+        })
+    }
+
+    /// The events that a [ServerState] subscribes to.
+    fn mask() -> protocol::SubscriptionMask {
+        protocol::SubscriptionMask::SERVER
+            | protocol::SubscriptionMask::SINK
+            | protocol::SubscriptionMask::SOURCE
+            | protocol::SubscriptionMask::SINK_INPUT
+            | protocol::SubscriptionMask::SOURCE_OUTPUT
+            | protocol::SubscriptionMask::CLIENT
+            | protocol::SubscriptionMask::CARD
+            | protocol::SubscriptionMask::MODULE
+    }
+
+    /// The client used to fetch updates.
+    pub fn client(&self) -> &Client {
+        &self.client
+    }
+
+    /// Basic information on the server.
+    pub fn server_info(&self) -> &protocol::ServerInfo {
+        &self.server_info
+    }
+
+    /// All sinks, by index.
+    pub fn sinks(&self) -> &BTreeMap<u32, protocol::SinkInfo> {
+        &self.sinks
+    }
+
+    /// All sources, by index.
+    pub fn sources(&self) -> &BTreeMap<u32, protocol::SourceInfo> {
+        &self.sources
+    }
+
+    /// All sink inputs, by index.
+    pub fn sink_inputs(&self) -> &BTreeMap<u32, protocol::SinkInputInfo> {
+        &self.sink_inputs
+    }
+
+    /// All source outputs, by index.
+    pub fn source_outputs(&self) -> &BTreeMap<u32, protocol::SourceOutputInfo> {
+        &self.source_outputs
+    }
+
+    /// All connected clients, by index.
+    pub fn clients(&self) -> &BTreeMap<u32, protocol::ClientInfo> {
+        &self.clients
+    }
+
+    /// All cards, by index.
+    pub fn cards(&self) -> &BTreeMap<u32, protocol::CardInfo> {
+        &self.cards
+    }
+
+    /// All loaded modules, by index.
+    pub fn modules(&self) -> &BTreeMap<u32, protocol::ModuleInfo> {
+        &self.modules
+    }
+
+    /// The default sink, if there is one.
+    pub fn default_sink(&self) -> Option<&protocol::SinkInfo> {
+        let name = self.server_info.default_sink_name.as_ref()?;
+        self.sinks.values().find(|sink| &sink.name == name)
+    }
+
+    /// The default source, if there is one.
+    pub fn default_source(&self) -> Option<&protocol::SourceInfo> {
+        let name = self.server_info.default_source_name.as_ref()?;
+        self.sources.values().find(|source| &source.name == name)
+    }
+
+    /// Replaces or removes an object, returning the change if there was one.
+    fn apply(
+        &mut self,
+        event: protocol::SubscriptionEvent,
+        new: Option<ServerObject>,
+    ) -> Option<ServerChange> {
+        let old = match (&new, event.index) {
+            (Some(ServerObject::Server(v)), _) => Some(ServerObject::Server(std::mem::replace(
+                &mut self.server_info,
+                v.clone(),
+            ))),
+            (Some(ServerObject::Sink(v)), _) => self
+                .sinks
+                .insert(v.index, v.clone())
+                .map(ServerObject::Sink),
+            (Some(ServerObject::Source(v)), _) => self
+                .sources
+                .insert(v.index, v.clone())
+                .map(ServerObject::Source),
+            (Some(ServerObject::SinkInput(v)), _) => self
+                .sink_inputs
+                .insert(v.index, v.clone())
+                .map(ServerObject::SinkInput),
+            (Some(ServerObject::SourceOutput(v)), _) => self
+                .source_outputs
+                .insert(v.index, v.clone())
+                .map(ServerObject::SourceOutput),
+            (Some(ServerObject::Client(v)), _) => self
+                .clients
+                .insert(v.index, v.clone())
+                .map(ServerObject::Client),
+            (Some(ServerObject::Card(v)), _) => self
+                .cards
+                .insert(v.index, v.clone())
+                .map(ServerObject::Card),
+            (Some(ServerObject::Module(v)), _) => self
+                .modules
+                .insert(v.index, v.clone())
+                .map(ServerObject::Module),
+            (None, None) => None,
+            (None, Some(index)) => match event.event_facility {
+                Facility::Sink => self.sinks.remove(&index).map(ServerObject::Sink),
+                Facility::Source => self.sources.remove(&index).map(ServerObject::Source),
+                Facility::SinkInput => self.sink_inputs.remove(&index).map(ServerObject::SinkInput),
+                Facility::SourceOutput => self
+                    .source_outputs
+                    .remove(&index)
+                    .map(ServerObject::SourceOutput),
+                Facility::Client => self.clients.remove(&index).map(ServerObject::Client),
+                Facility::Card => self.cards.remove(&index).map(ServerObject::Card),
+                Facility::Module => self.modules.remove(&index).map(ServerObject::Module),
+                Facility::Server | Facility::SampleCache | Facility::Autoload => None,
+            },
+        };
+
+        if old == new {
+            return None;
+        }
+
+        Some(ServerChange {
+            facility: event.event_facility,
+            index: event.index,
+            old,
+            new,
+        })
+    }
+}
+
+/// Fetches the current value of the object an event refers to. Returns `None`
+/// if the object doesn't exist (anymore).
+async fn fetch(
+    client: Client,
+    event: protocol::SubscriptionEvent,
+) -> ClientResult<Option<ServerObject>> {
+    let res = match (event.event_facility, event.index) {
+        (Facility::Server, _) => client.server_info().await.map(ServerObject::Server),
+        (Facility::Sink, Some(index)) => client.sink_info(index).await.map(ServerObject::Sink),
+        (Facility::Source, Some(index)) => {
+            client.source_info(index).await.map(ServerObject::Source)
+        }
+        (Facility::SinkInput, Some(index)) => client
+            .sink_input_info(index)
+            .await
+            .map(ServerObject::SinkInput),
+        (Facility::SourceOutput, Some(index)) => client
+            .source_output_info(index)
+            .await
+            .map(ServerObject::SourceOutput),
+        (Facility::Client, Some(index)) => {
+            client.client_info(index).await.map(ServerObject::Client)
+        }
+        (Facility::Card, Some(index)) => client.card_info(index).await.map(ServerObject::Card),
+        (Facility::Module, Some(index)) => {
+            client.module_info(index).await.map(ServerObject::Module)
+        }
+        _ => return Ok(None),
+    };
+
+    match res {
+        Ok(obj) => Ok(Some(obj)),
+        Err(ClientError::ServerError(protocol::PulseError::NoEntity)) => Ok(None),
+        Err(err) => Err(err),
+    }
+}
+
+impl Stream for ServerState {
+    type Item = ClientResult<ServerChange>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        loop {
+            if let Some(pending) = &mut self.pending {
+                let (event, res) = ready!(pending.poll_unpin(cx));
+                self.pending = None;
+
+                match res {
+                    Ok(new) => {
+                        if let Some(change) = self.apply(event, new) {
+                            return Poll::Ready(Some(Ok(change)));
+                        }
+                    }
+                    Err(err) => return Poll::Ready(Some(Err(err))),
+                }
+            }
+
+            let Some(event) = ready!(self.subscription.poll_next_unpin(cx)) else {
+                return Poll::Ready(None);
+            };
+
+            if event.event_type == protocol::SubscriptionEventType::Removed {
+                if let Some(change) = self.apply(event, None) {
+                    return Poll::Ready(Some(Ok(change)));
+                }
+
+                continue;
+            }
+
+            let client = self.client.clone();
+            self.pending = Some(fetch(client, event).map(move |res| (event, res)).boxed());
+        }
+    }
+}
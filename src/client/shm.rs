@@ -0,0 +1,610 @@
+//! Shared memory pools, used to pass stream data to and from the server
+//! without copying it through the socket. Pools are either POSIX shared memory
+//! segments, which the peer opens by name, or memfds, which are passed to the
+//! peer over the socket.
+
+use std::{
+    collections::BTreeMap,
+    ffi::CString,
+    io,
+    os::fd::{AsFd as _, AsRawFd as _, BorrowedFd, OwnedFd},
+    ptr::NonNull,
+};
+
+use crate::protocol::{self, DescriptorFlags};
+
+/// The size of each block in a [ShmPool].
+const SLOT_SIZE: usize = 64 * 1024;
+
+/// The number of blocks in a [ShmPool].
+const SLOT_COUNT: usize = 64;
+
+/// The size of the marker PulseAudio places at the end of each segment, used to
+/// clean up segments left behind by dead processes.
+const MARKER_SIZE: usize = 40;
+const MARKER_MAGIC: u32 = 0xbeefcafe;
+
+/// Picks a random segment ID.
+fn random_id(attempt: u32) -> u32 {
+    use std::hash::BuildHasher as _;
+
+    std::collections::hash_map::RandomState::new().hash_one((std::process::id(), attempt)) as u32
+}
+
+/// The name of a POSIX shared memory segment, as understood by PulseAudio.
+fn segment_name(id: u32) -> CString {
+    CString::new(format!("/pulse-shm-{id}")).unwrap()
+}
+
+/// A mapping of a shared memory segment into our address space.
+#[derive(Debug)]
+struct Mapping {
+    ptr: NonNull<u8>,
+    len: usize,
+}
+
+// SAFETY: the mapping is just memory, and access is mediated by &self and
+// &mut self.
+unsafe impl Send for Mapping {}
+
+impl Mapping {
+    /// Opens and maps a segment by name.
+    fn open(name: &CString, oflag: libc::c_int, len: Option<usize>) -> io::Result<Self> {
+        let writable = oflag & libc::O_ACCMODE == libc::O_RDWR;
+
+        // SAFETY: the name is a valid C string, and the descriptor is closed
+        // below on all paths.
+        let fd = unsafe { libc::shm_open(name.as_ptr(), oflag, 0o400) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let res = Self::map_fd(fd, writable, len);
+
+        // SAFETY: the fd is valid, and the mapping (if any) keeps its own
+        // reference to the segment.
+        unsafe { libc::close(fd) };
+
+        res
+    }
+
+    fn map_fd(fd: libc::c_int, writable: bool, len: Option<usize>) -> io::Result<Self> {
+        let len = match len {
+            Some(len) => {
+                // SAFETY: the fd is valid.
+                if unsafe { libc::ftruncate(fd, len as libc::off_t) } < 0 {
+                    return Err(io::Error::last_os_error());
+                }
+
+                len
+            }
+            None => {
+                // SAFETY: the fd is valid, and fstat initializes the struct.
+                let mut stat: libc::stat = unsafe { std::mem::zeroed() };
+                if unsafe { libc::fstat(fd, &mut stat) } < 0 {
+                    return Err(io::Error::last_os_error());
+                }
+
+                stat.st_size as usize
+            }
+        };
+
+        if len == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "empty shared memory segment",
+            ));
+        }
+
+        let prot = if writable {
+            libc::PROT_READ | libc::PROT_WRITE
+        } else {
+            libc::PROT_READ
+        };
+
+        // SAFETY: we map a fresh region, which we own until it's unmapped in
+        // Drop.
+        let ptr = unsafe { libc::mmap(std::ptr::null_mut(), len, prot, libc::MAP_SHARED, fd, 0) };
+
+        if ptr == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(Self {
+            ptr: NonNull::new(ptr as *mut u8).unwrap(),
+            len,
+        })
+    }
+
+    fn as_slice(&self) -> &[u8] {
+        // SAFETY: the region is mapped for the lifetime of self.
+        unsafe { std::slice::from_raw_parts(self.ptr.as_ptr(), self.len) }
+    }
+
+    fn as_mut_slice(&mut self) -> &mut [u8] {
+        // SAFETY: the region is mapped writable for the lifetime of self (only
+        // writable mappings are used mutably).
+        unsafe { std::slice::from_raw_parts_mut(self.ptr.as_ptr(), self.len) }
+    }
+}
+
+impl Drop for Mapping {
+    fn drop(&mut self) {
+        // SAFETY: the region was mapped by us, and no references to it
+        // outlive self.
+        unsafe { libc::munmap(self.ptr.as_ptr() as *mut libc::c_void, self.len) };
+    }
+}
+
+/// A pool of fixed-size blocks in a shared memory segment, which the server
+/// attaches to read-only. Playback data is written directly into a block, and
+/// then a reference to the block is sent to the server. The server releases
+/// the block with a SHMRELEASE message once it's done with it.
+#[derive(Debug)]
+pub(super) struct ShmPool {
+    id: u32,
+    mapping: Mapping,
+    free: Vec<u32>,
+    // Only set for memfd pools.
+    memfd: Option<OwnedFd>,
+}
+
+impl ShmPool {
+    /// Creates a new POSIX shared memory segment with a random ID.
+    pub(super) fn new() -> io::Result<Self> {
+        let len = SLOT_SIZE * SLOT_COUNT + MARKER_SIZE;
+
+        for attempt in 0..16_u32 {
+            let id = random_id(attempt);
+            let name = segment_name(id);
+
+            let mut mapping = match Mapping::open(
+                &name,
+                libc::O_RDWR | libc::O_CREAT | libc::O_EXCL,
+                Some(len),
+            ) {
+                Ok(mapping) => mapping,
+                Err(err) if err.kind() == io::ErrorKind::AlreadyExists => continue,
+                Err(err) => {
+                    // SAFETY: the name is a valid C string.
+                    unsafe { libc::shm_unlink(name.as_ptr()) };
+                    return Err(err);
+                }
+            };
+
+            // Write the marker, so that PulseAudio can clean up the segment
+            // if we crash.
+            let marker = &mut mapping.as_mut_slice()[len - MARKER_SIZE..];
+            marker[0..4].copy_from_slice(&MARKER_MAGIC.to_ne_bytes());
+            marker[4..8].copy_from_slice(&(std::process::id() as i32).to_ne_bytes());
+
+            return Ok(Self {
+                id,
+                mapping,
+                free: (0..SLOT_COUNT as u32).rev().collect(),
+                memfd: None,
+            });
+        }
+
+        Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            "failed to find a free shared memory segment id",
+        ))
+    }
+
+    /// Creates a new memfd-backed pool with a random ID. The memfd must be
+    /// registered with the server using
+    /// [Command::RegisterMemfdShmid](protocol::Command::RegisterMemfdShmid)
+    /// before any blocks are sent.
+    #[cfg(target_os = "linux")]
+    pub(super) fn new_memfd() -> io::Result<Self> {
+        use std::os::fd::FromRawFd as _;
+
+        // SAFETY: the name is a valid C string.
+        let fd = unsafe { libc::memfd_create(c"pulseaudio".as_ptr(), libc::MFD_CLOEXEC) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        // SAFETY: the fd was just created, and is owned by us.
+        let memfd = unsafe { OwnedFd::from_raw_fd(fd) };
+        let mapping = Mapping::map_fd(memfd.as_raw_fd(), true, Some(SLOT_SIZE * SLOT_COUNT))?;
+
+        Ok(Self {
+            id: random_id(0),
+            mapping,
+            free: (0..SLOT_COUNT as u32).rev().collect(),
+            memfd: Some(memfd),
+        })
+    }
+
+    /// Creates a new memfd-backed pool. memfds are only supported on Linux.
+    #[cfg(not(target_os = "linux"))]
+    pub(super) fn new_memfd() -> io::Result<Self> {
+        Err(io::ErrorKind::Unsupported.into())
+    }
+
+    /// The ID of the segment, which the server uses to attach to it.
+    pub(super) fn id(&self) -> u32 {
+        self.id
+    }
+
+    /// The memfd backing the pool, if it's a memfd pool.
+    pub(super) fn memfd(&self) -> Option<BorrowedFd<'_>> {
+        self.memfd.as_ref().map(|fd| fd.as_fd())
+    }
+
+    /// Whether the pool is backed by a memfd, rather than a POSIX shared
+    /// memory segment.
+    pub(super) fn is_memfd(&self) -> bool {
+        self.memfd.is_some()
+    }
+
+    /// The flags to send with references to blocks in the pool.
+    pub(super) fn flags(&self) -> DescriptorFlags {
+        if self.is_memfd() {
+            DescriptorFlags::FLAG_SHMDATA_MEMFD_BLOCK
+        } else {
+            DescriptorFlags::empty()
+        }
+    }
+
+    /// Reserves a free block, returning its ID, or `None` if all blocks are in
+    /// use.
+    pub(super) fn alloc(&mut self) -> Option<u32> {
+        self.free.pop()
+    }
+
+    /// Returns a block to the pool. Unknown or already-free blocks are
+    /// ignored.
+    pub(super) fn release(&mut self, block_id: u32) {
+        if (block_id as usize) < SLOT_COUNT && !self.free.contains(&block_id) {
+            self.free.push(block_id);
+        } else {
+            log::warn!("ignoring release of unknown shm block {block_id}");
+        }
+    }
+
+    /// The memory for a reserved block.
+    pub(super) fn block_mut(&mut self, block_id: u32) -> &mut [u8] {
+        let off = block_id as usize * SLOT_SIZE;
+        &mut self.mapping.as_mut_slice()[off..off + SLOT_SIZE]
+    }
+
+    /// A reference to the first `len` bytes of a reserved block, to be sent to
+    /// the server.
+    pub(super) fn shm_ref(&self, block_id: u32, len: usize) -> protocol::ShmRef {
+        protocol::ShmRef {
+            block_id,
+            shm_id: self.id,
+            offset: block_id * SLOT_SIZE as u32,
+            length: len.min(SLOT_SIZE) as u32,
+        }
+    }
+}
+
+impl Drop for ShmPool {
+    fn drop(&mut self) {
+        // The server keeps its own mapping, if it has one. memfds disappear
+        // on their own once the last reference is closed.
+        if self.memfd.is_none() {
+            // SAFETY: the name is a valid C string.
+            unsafe { libc::shm_unlink(segment_name(self.id).as_ptr()) };
+        }
+    }
+}
+
+/// Segments created by the server, attached read-only as the server references
+/// them. memfd segments must be registered up front, since they can't be
+/// opened by name.
+#[derive(Debug, Default)]
+pub(super) struct ShmImports {
+    segments: BTreeMap<u32, Mapping>,
+    memfd_segments: BTreeMap<u32, (OwnedFd, Mapping)>,
+}
+
+impl ShmImports {
+    /// Attaches a memfd segment registered by the server with
+    /// [Command::RegisterMemfdShmid](protocol::Command::RegisterMemfdShmid).
+    pub(super) fn register_memfd(&mut self, shm_id: u32, memfd: OwnedFd) -> io::Result<()> {
+        // The fd is kept in case a block needs to be mapped writable later.
+        let mapping = Mapping::map_fd(memfd.as_raw_fd(), false, None)?;
+        self.memfd_segments.insert(shm_id, (memfd, mapping));
+        Ok(())
+    }
+
+    /// Maps the block referenced by `shm_ref` again, but writable. This is
+    /// only allowed if the server sent the reference with
+    /// [DescriptorFlags::FLAG_SHMWRITABLE].
+    pub(super) fn attach_writable(
+        &self,
+        shm_ref: &protocol::ShmRef,
+        memfd: bool,
+    ) -> io::Result<SharedBlock> {
+        let mapping = if memfd {
+            let (fd, _) = self.memfd_segments.get(&shm_ref.shm_id).ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("unknown memfd segment {}", shm_ref.shm_id),
+                )
+            })?;
+
+            Mapping::map_fd(fd.as_raw_fd(), true, None)?
+        } else {
+            Mapping::open(&segment_name(shm_ref.shm_id), libc::O_RDWR, None)?
+        };
+
+        let offset = shm_ref.offset as usize;
+        let len = shm_ref.length as usize;
+        if offset.saturating_add(len) > mapping.len {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("shm block out of range for segment {}", shm_ref.shm_id),
+            ));
+        }
+
+        Ok(SharedBlock {
+            mapping,
+            offset,
+            len,
+        })
+    }
+
+    /// Returns the data referenced by `shm_ref`, attaching to its segment if
+    /// necessary. If `memfd` is set, the reference is to a registered memfd
+    /// segment.
+    pub(super) fn get(&mut self, shm_ref: &protocol::ShmRef, memfd: bool) -> io::Result<&[u8]> {
+        let mapping = if memfd {
+            let (_, mapping) = self.memfd_segments.get(&shm_ref.shm_id).ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("unknown memfd segment {}", shm_ref.shm_id),
+                )
+            })?;
+
+            mapping
+        } else {
+            match self.segments.entry(shm_ref.shm_id) {
+                std::collections::btree_map::Entry::Occupied(e) => e.into_mut(),
+                std::collections::btree_map::Entry::Vacant(e) => {
+                    let mapping =
+                        Mapping::open(&segment_name(shm_ref.shm_id), libc::O_RDONLY, None)?;
+                    e.insert(mapping)
+                }
+            }
+        };
+
+        let start = shm_ref.offset as usize;
+        let end = start.saturating_add(shm_ref.length as usize);
+        mapping.as_slice().get(start..end).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("shm block out of range for segment {}", shm_ref.shm_id),
+            )
+        })
+    }
+}
+
+/// A writable block in a segment created by the server, which is shared with
+/// the server for as long as it's mapped.
+#[derive(Debug)]
+pub(super) struct SharedBlock {
+    mapping: Mapping,
+    offset: usize,
+    len: usize,
+}
+
+impl SharedBlock {
+    /// A pointer to the start of the block. The memory is concurrently
+    /// accessed by the server, so it can't be safely referenced as a slice.
+    pub(super) fn as_ptr(&self) -> *mut u8 {
+        // SAFETY: the offset is within the mapping.
+        unsafe { self.mapping.ptr.as_ptr().add(self.offset) }
+    }
+
+    /// The length of the block, in bytes.
+    pub(super) fn len(&self) -> usize {
+        self.len
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{ffi::CString, io::BufReader, os::unix::net::UnixStream};
+
+    use futures::executor::block_on;
+
+    use super::{
+        super::{socket, test_util},
+        *,
+    };
+    use crate::{AsPlaybackSource as _, Client, client::socket::TakeFds as _};
+
+    fn segment_path(id: u32) -> std::path::PathBuf {
+        format!("/dev/shm/pulse-shm-{id}").into()
+    }
+
+    #[test]
+    fn pool_alloc_release() -> anyhow::Result<()> {
+        let mut pool = ShmPool::new()?;
+
+        let blocks = std::iter::from_fn(|| pool.alloc()).collect::<Vec<_>>();
+        assert_eq!(blocks.len(), SLOT_COUNT);
+        assert_eq!(pool.alloc(), None);
+
+        pool.release(blocks[3]);
+        pool.release(blocks[3]);
+        pool.release(SLOT_COUNT as u32);
+        assert_eq!(pool.alloc(), Some(blocks[3]));
+        assert_eq!(pool.alloc(), None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn pool_segment() -> anyhow::Result<()> {
+        let pool = ShmPool::new()?;
+        let path = segment_path(pool.id());
+
+        let contents = std::fs::read(&path)?;
+        assert_eq!(contents.len(), SLOT_SIZE * SLOT_COUNT + MARKER_SIZE);
+        assert_eq!(contents.len() % 8, 0);
+
+        let marker = &contents[contents.len() - MARKER_SIZE..];
+        assert_eq!(marker[0..4], MARKER_MAGIC.to_ne_bytes());
+
+        drop(pool);
+        assert!(!path.exists());
+
+        Ok(())
+    }
+
+    #[test]
+    fn import_own_segment() -> anyhow::Result<()> {
+        let mut pool = ShmPool::new()?;
+        let block_id = pool.alloc().unwrap();
+        pool.block_mut(block_id)[..4].copy_from_slice(b"test");
+
+        let shm_ref = pool.shm_ref(block_id, 4);
+        let mut imports = ShmImports::default();
+        assert_eq!(imports.get(&shm_ref, false)?, b"test");
+
+        let out_of_range = protocol::ShmRef {
+            offset: u32::MAX - 1,
+            ..shm_ref
+        };
+        assert!(imports.get(&out_of_range, false).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn import_memfd() -> anyhow::Result<()> {
+        let mut pool = ShmPool::new_memfd()?;
+        assert!(pool.is_memfd());
+        assert_eq!(pool.flags(), DescriptorFlags::FLAG_SHMDATA_MEMFD_BLOCK);
+
+        let block_id = pool.alloc().unwrap();
+        pool.block_mut(block_id)[..4].copy_from_slice(b"test");
+        let shm_ref = pool.shm_ref(block_id, 4);
+
+        let mut imports = ShmImports::default();
+        assert!(imports.get(&shm_ref, true).is_err());
+
+        let memfd = pool.memfd().unwrap().try_clone_to_owned()?;
+        imports.register_memfd(pool.id(), memfd)?;
+        assert_eq!(imports.get(&shm_ref, true)?, b"test");
+
+        Ok(())
+    }
+
+    /// A minimal server, which accepts shm (and optionally memfd) and then
+    /// reads a single chunk of playback data out of the client's pool.
+    fn fake_playback_server(stream: UnixStream, use_memfd: bool) -> anyhow::Result<Vec<u8>> {
+        let mut sock = BufReader::new(socket::FdStream::new(&stream));
+        let mut imports = ShmImports::default();
+        let version = protocol::MAX_VERSION;
+
+        // Like PulseAudio, register our own pool before the connection is
+        // ready.
+        let server_pool = ShmPool::new_memfd()?;
+        let auth = test_util::serve_handshake(&mut sock, true, use_memfd, |sock| {
+            if use_memfd {
+                let cmd = protocol::Command::RegisterMemfdShmid(server_pool.id());
+                test_util::send_command_with_fds(
+                    sock,
+                    u32::MAX,
+                    &cmd,
+                    &[server_pool.memfd().unwrap()],
+                )?;
+            }
+
+            Ok(())
+        })?;
+
+        assert!(auth.supports_shm);
+        assert!(auth.supports_memfd);
+
+        if use_memfd {
+            let (_, cmd) = protocol::read_command_message(&mut sock, version)?;
+            let protocol::Command::RegisterMemfdShmid(shm_id) = cmd else {
+                anyhow::bail!("expected memfd registration, got {cmd:?}");
+            };
+
+            let memfd = sock
+                .get_mut()
+                .take_fds()
+                .pop_front()
+                .expect("no memfd passed");
+            imports.register_memfd(shm_id, memfd)?;
+        }
+
+        let (seq, cmd) = protocol::read_command_message(&mut sock, version)?;
+        let protocol::Command::CreatePlaybackStream(params) = cmd else {
+            anyhow::bail!("expected stream creation, got {cmd:?}");
+        };
+
+        let reply = protocol::CreatePlaybackStreamReply {
+            channel: 7,
+            requested_bytes: 8,
+            sample_spec: params.sample_spec,
+            channel_map: params.channel_map,
+            sink_name: Some(CString::new("fake")?),
+            ..Default::default()
+        };
+        protocol::write_reply_message(sock.get_mut(), seq, &reply, version)?;
+
+        let desc = protocol::read_descriptor(&mut sock)?;
+        assert_eq!(desc.channel, 7);
+        if use_memfd {
+            assert_eq!(
+                desc.flags,
+                DescriptorFlags::FLAG_SHMDATA | DescriptorFlags::FLAG_SHMDATA_MEMFD_BLOCK
+            );
+        } else {
+            assert_eq!(desc.flags, DescriptorFlags::FLAG_SHMDATA);
+        }
+
+        let shm_ref = protocol::read_shm_ref(&mut sock)?;
+        let data = imports.get(&shm_ref, use_memfd)?.to_vec();
+        protocol::write_shm_release(sock.get_mut(), shm_ref.block_id)?;
+
+        Ok(data)
+    }
+
+    fn play_one_chunk(use_memfd: bool) -> anyhow::Result<()> {
+        let (client_sock, server_sock) = UnixStream::pair()?;
+        let server = std::thread::spawn(move || fake_playback_server(server_sock, use_memfd));
+
+        let client = Client::new_unix(c"shm-test", client_sock, None::<Vec<u8>>)?;
+        let params = protocol::PlaybackStreamParams {
+            sample_spec: protocol::SampleSpec {
+                format: protocol::SampleFormat::S16Le,
+                channels: 2,
+                sample_rate: 44100,
+            },
+            channel_map: protocol::ChannelMap::stereo(),
+            ..Default::default()
+        };
+
+        let source = |buf: &mut [u8]| {
+            buf.fill(0xab);
+            buf.len()
+        };
+
+        let _stream = block_on(client.create_playback_stream(params, source.as_playback_source()))?;
+        assert_eq!(server.join().unwrap()?, vec![0xab; 8]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn playback_over_shm() -> anyhow::Result<()> {
+        play_one_chunk(false)
+    }
+
+    #[test]
+    fn playback_over_memfd() -> anyhow::Result<()> {
+        play_one_chunk(true)
+    }
+}
@@ -0,0 +1,339 @@
+use std::{
+    collections::VecDeque,
+    io::{self, Read as _},
+    os::fd::{AsFd, AsRawFd, BorrowedFd, FromRawFd as _, OwnedFd, RawFd},
+    time::Duration,
+};
+
+use mio::net::{TcpStream, UnixStream};
+
+/// The maximum number of file descriptors accepted with a single read.
+const MAX_FDS: usize = 8;
+
+#[cfg(target_os = "linux")]
+const RECV_FLAGS: libc::c_int = libc::MSG_CMSG_CLOEXEC;
+#[cfg(not(target_os = "linux"))]
+const RECV_FLAGS: libc::c_int = 0;
+
+/// The connection between the reactor and the server.
+#[derive(Debug)]
+pub(super) enum Socket {
+    Unix(UnixStream),
+    Tcp(TcpStream),
+}
+
+impl Socket {
+    /// Reads from the socket, appending any file descriptors passed by the
+    /// server to `fds`.
+    pub(super) fn recv_with_fds(
+        &mut self,
+        buf: &mut [u8],
+        fds: &mut VecDeque<OwnedFd>,
+    ) -> io::Result<usize> {
+        match self {
+            Socket::Unix(s) => recv_with_fds(s, buf, fds),
+            Socket::Tcp(s) => s.read(buf),
+        }
+    }
+}
+
+/// A blocking unix socket, used for the handshake, which keeps any file
+/// descriptors passed by the server.
+#[derive(Debug)]
+pub(super) struct FdStream<'a> {
+    socket: &'a std::os::unix::net::UnixStream,
+    fds: VecDeque<OwnedFd>,
+}
+
+impl<'a> FdStream<'a> {
+    pub(super) fn new(socket: &'a std::os::unix::net::UnixStream) -> Self {
+        Self {
+            socket,
+            fds: VecDeque::new(),
+        }
+    }
+}
+
+impl io::Read for FdStream<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        recv_with_fds(self.socket, buf, &mut self.fds)
+    }
+}
+
+impl io::Write for FdStream<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.socket.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.socket.flush()
+    }
+}
+
+/// A socket over which the server may pass file descriptors. They are
+/// received in order, along with the messages they belong to.
+pub(super) trait TakeFds {
+    /// Takes all the file descriptors received so far, oldest first.
+    fn take_fds(&mut self) -> VecDeque<OwnedFd>;
+}
+
+impl TakeFds for FdStream<'_> {
+    fn take_fds(&mut self) -> VecDeque<OwnedFd> {
+        std::mem::take(&mut self.fds)
+    }
+}
+
+impl AsFd for FdStream<'_> {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.socket.as_fd()
+    }
+}
+
+impl<T: TakeFds> TakeFds for &mut T {
+    fn take_fds(&mut self) -> VecDeque<OwnedFd> {
+        (**self).take_fds()
+    }
+}
+
+impl TakeFds for std::net::TcpStream {
+    fn take_fds(&mut self) -> VecDeque<OwnedFd> {
+        VecDeque::new()
+    }
+}
+
+/// Sets the receive and send timeouts of a blocking socket of any type.
+// The types of the timeval fields vary by platform.
+#[allow(trivial_numeric_casts)]
+pub(super) fn set_timeouts(socket: BorrowedFd<'_>, timeout: Duration) -> io::Result<()> {
+    let tv = libc::timeval {
+        tv_sec: timeout.as_secs() as libc::time_t,
+        tv_usec: timeout.subsec_micros() as libc::suseconds_t,
+    };
+
+    for opt in [libc::SO_RCVTIMEO, libc::SO_SNDTIMEO] {
+        // SAFETY: the fd is valid, and the option value is a timeval.
+        let res = unsafe {
+            libc::setsockopt(
+                socket.as_raw_fd(),
+                libc::SOL_SOCKET,
+                opt,
+                std::ptr::from_ref(&tv).cast(),
+                size_of::<libc::timeval>() as libc::socklen_t,
+            )
+        };
+
+        if res < 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+
+    Ok(())
+}
+
+/// Writes to a unix socket, passing file descriptors along with the data
+/// (using `SCM_RIGHTS`). Returns the number of bytes written; the file
+/// descriptors are always sent with the first byte.
+// The types of the msghdr and cmsghdr fields vary by platform.
+#[allow(trivial_numeric_casts)]
+pub(super) fn send_with_fds(
+    socket: &impl AsRawFd,
+    buf: &[u8],
+    fds: &[BorrowedFd<'_>],
+) -> io::Result<usize> {
+    assert!(fds.len() <= MAX_FDS);
+
+    let mut iov = libc::iovec {
+        iov_base: buf.as_ptr() as *mut libc::c_void,
+        iov_len: buf.len(),
+    };
+
+    let mut cmsg_buf = [0_u64; 16];
+    let data_len = size_of_val(fds);
+
+    // SAFETY: the msghdr points to valid buffers for the duration of the
+    // call, and the control buffer is large enough and aligned for a single
+    // cmsghdr with MAX_FDS descriptors.
+    let n = unsafe {
+        let mut msg: libc::msghdr = std::mem::zeroed();
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+        msg.msg_control = cmsg_buf.as_mut_ptr().cast();
+        msg.msg_controllen = libc::CMSG_SPACE(data_len as _) as _;
+
+        let cmsg = libc::CMSG_FIRSTHDR(&msg);
+        (*cmsg).cmsg_level = libc::SOL_SOCKET;
+        (*cmsg).cmsg_type = libc::SCM_RIGHTS;
+        (*cmsg).cmsg_len = libc::CMSG_LEN(data_len as _) as _;
+
+        let data = libc::CMSG_DATA(cmsg) as *mut RawFd;
+        for (i, fd) in fds.iter().enumerate() {
+            data.add(i).write_unaligned(fd.as_raw_fd());
+        }
+
+        libc::sendmsg(socket.as_raw_fd(), &msg, 0)
+    };
+
+    if n < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(n as usize)
+}
+
+/// Reads from a unix socket, appending any file descriptors passed along
+/// with the data to `fds`.
+#[allow(trivial_numeric_casts)]
+fn recv_with_fds(
+    socket: &impl AsRawFd,
+    buf: &mut [u8],
+    fds: &mut VecDeque<OwnedFd>,
+) -> io::Result<usize> {
+    let mut iov = libc::iovec {
+        iov_base: buf.as_mut_ptr().cast(),
+        iov_len: buf.len(),
+    };
+
+    let mut cmsg_buf = [0_u64; 16];
+
+    // SAFETY: the msghdr points to valid buffers for the duration of the
+    // call.
+    let mut msg: libc::msghdr = unsafe { std::mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = cmsg_buf.as_mut_ptr().cast();
+    msg.msg_controllen = size_of_val(&cmsg_buf) as _;
+
+    let n = unsafe { libc::recvmsg(socket.as_raw_fd(), &mut msg, RECV_FLAGS) };
+    if n < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    if msg.msg_flags & libc::MSG_CTRUNC != 0 {
+        log::warn!("file descriptors passed by the server were truncated");
+    }
+
+    // SAFETY: the kernel filled in the control messages, and any file
+    // descriptors passed are now owned by us.
+    unsafe {
+        let mut cmsg = libc::CMSG_FIRSTHDR(&msg);
+        while !cmsg.is_null() {
+            if (*cmsg).cmsg_level == libc::SOL_SOCKET && (*cmsg).cmsg_type == libc::SCM_RIGHTS {
+                let data = libc::CMSG_DATA(cmsg) as *const RawFd;
+                let len = (*cmsg).cmsg_len as usize - libc::CMSG_LEN(0) as usize;
+                for i in 0..len / size_of::<RawFd>() {
+                    fds.push_back(OwnedFd::from_raw_fd(data.add(i).read_unaligned()));
+                }
+            }
+
+            cmsg = libc::CMSG_NXTHDR(&msg, cmsg);
+        }
+    }
+
+    Ok(n as usize)
+}
+
+impl io::Read for Socket {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Socket::Unix(s) => s.read(buf),
+            Socket::Tcp(s) => s.read(buf),
+        }
+    }
+}
+
+impl io::Write for Socket {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Socket::Unix(s) => s.write(buf),
+            Socket::Tcp(s) => s.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Socket::Unix(s) => s.flush(),
+            Socket::Tcp(s) => s.flush(),
+        }
+    }
+}
+
+impl mio::event::Source for Socket {
+    fn register(
+        &mut self,
+        registry: &mio::Registry,
+        token: mio::Token,
+        interests: mio::Interest,
+    ) -> io::Result<()> {
+        match self {
+            Socket::Unix(s) => s.register(registry, token, interests),
+            Socket::Tcp(s) => s.register(registry, token, interests),
+        }
+    }
+
+    fn reregister(
+        &mut self,
+        registry: &mio::Registry,
+        token: mio::Token,
+        interests: mio::Interest,
+    ) -> io::Result<()> {
+        match self {
+            Socket::Unix(s) => s.reregister(registry, token, interests),
+            Socket::Tcp(s) => s.reregister(registry, token, interests),
+        }
+    }
+
+    fn deregister(&mut self, registry: &mio::Registry) -> io::Result<()> {
+        match self {
+            Socket::Unix(s) => s.deregister(registry),
+            Socket::Tcp(s) => s.deregister(registry),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        ffi::CString,
+        io::BufReader,
+        net::{Ipv4Addr, TcpListener},
+    };
+
+    use futures::executor::block_on;
+
+    use super::super::test_util;
+    use crate::{Client, protocol};
+
+    #[test]
+    fn client_over_tcp() -> anyhow::Result<()> {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0))?;
+        let addr = listener.local_addr()?;
+
+        // A minimal server, which handles the handshake and a single
+        // GetServerInfo command.
+        let server = std::thread::spawn(move || -> anyhow::Result<()> {
+            let (sock, _) = listener.accept()?;
+            let mut sock = BufReader::new(sock);
+            let version = protocol::MAX_VERSION;
+
+            let auth = test_util::serve_handshake(&mut sock, false, false, |_| Ok(()))?;
+            assert!(!auth.supports_shm);
+
+            let (seq, cmd) = protocol::read_command_message(&mut sock, version)?;
+            assert!(matches!(cmd, protocol::Command::GetServerInfo));
+            let reply = protocol::ServerInfo {
+                server_name: Some(CString::new("tcp-test")?),
+                ..Default::default()
+            };
+            protocol::write_reply_message(sock.get_mut(), seq, &reply, version)?;
+
+            Ok(())
+        });
+
+        let socket = std::net::TcpStream::connect(addr)?;
+        let client = Client::new_tcp(c"tcp-test", socket, None::<Vec<u8>>)?;
+        let info = block_on(client.server_info())?;
+        assert_eq!(info.server_name.as_deref(), Some(c"tcp-test"));
+
+        server.join().unwrap()
+    }
+}
@@ -0,0 +1,530 @@
+//! The shared ringbuffer channel ("srbchannel"), which the server offers once
+//! shm is negotiated. It carries the same frames as the socket, through a pair
+//! of ringbuffers in a shared memory block, and each side wakes up the other
+//! using an eventfd.
+
+use std::{
+    io,
+    os::fd::{AsRawFd as _, OwnedFd, RawFd},
+    sync::atomic::{AtomicI32, Ordering},
+};
+
+use super::shm::SharedBlock;
+
+// The layout of the header at the start of the block, which is followed by the
+// two ringbuffers. Offsets are from the server's point of view.
+const READ_COUNT: usize = 0;
+const WRITE_COUNT: usize = 4;
+const READ_SEMDATA: usize = 8;
+const WRITE_SEMDATA: usize = 20;
+const CAPACITY: usize = 32;
+const READBUF_OFFSET: usize = 36;
+const WRITEBUF_OFFSET: usize = 40;
+const HEADER_SIZE: usize = 44;
+
+// The layout of the shared state of each semaphore.
+const SEM_WAITING: usize = 0;
+const SEM_SIGNALLED: usize = 4;
+const SEM_IN_PIPE: usize = 8;
+
+/// One direction of the channel. The indices are private to each side; only
+/// the count of bytes in the ring is shared.
+#[derive(Debug)]
+struct Ring {
+    count: usize,
+    memory: usize,
+    index: usize,
+}
+
+/// A semaphore, consisting of some shared state and an eventfd. The eventfd
+/// is only written to if the other side is waiting on it.
+#[derive(Debug)]
+struct FdSem {
+    data: usize,
+    fd: OwnedFd,
+}
+
+/// A shared ringbuffer channel, set up from a block and pair of eventfds sent
+/// by the server with
+/// [Command::EnableSrbchannel](crate::protocol::Command::EnableSrbchannel).
+#[derive(Debug)]
+pub(super) struct Srbchannel {
+    block: SharedBlock,
+    capacity: usize,
+    read_ring: Ring,
+    write_ring: Ring,
+    // Signalled by the server when there's data to read, or when it drained
+    // the write ring after it was full.
+    read_sem: FdSem,
+    write_sem: FdSem,
+}
+
+impl Srbchannel {
+    /// Sets up the channel from the client side, given the eventfds in the
+    /// order the server sent them.
+    pub(super) fn new(block: SharedBlock, read_fd: OwnedFd, write_fd: OwnedFd) -> io::Result<Self> {
+        // The server's read side is our write side, and vice versa.
+        Self::with_roles(block, write_fd, read_fd, true)
+    }
+
+    fn with_roles(
+        block: SharedBlock,
+        read_fd: OwnedFd,
+        write_fd: OwnedFd,
+        client: bool,
+    ) -> io::Result<Self> {
+        let invalid = |msg: &str| io::Error::new(io::ErrorKind::InvalidData, msg);
+
+        if block.len() < HEADER_SIZE || block.as_ptr().align_offset(8) != 0 {
+            return Err(invalid("invalid srbchannel block"));
+        }
+
+        let mut channel = Self {
+            block,
+            capacity: 0,
+            read_ring: Ring {
+                count: READ_COUNT,
+                memory: 0,
+                index: 0,
+            },
+            write_ring: Ring {
+                count: WRITE_COUNT,
+                memory: 0,
+                index: 0,
+            },
+            read_sem: FdSem {
+                data: READ_SEMDATA,
+                fd: read_fd,
+            },
+            write_sem: FdSem {
+                data: WRITE_SEMDATA,
+                fd: write_fd,
+            },
+        };
+
+        let capacity = channel.header(CAPACITY);
+        let read_offset = channel.header(READBUF_OFFSET);
+        let write_offset = channel.header(WRITEBUF_OFFSET);
+        if capacity == 0
+            || read_offset < HEADER_SIZE
+            || write_offset < HEADER_SIZE
+            || read_offset.max(write_offset) + capacity > channel.block.len()
+        {
+            return Err(invalid("invalid srbchannel header"));
+        }
+
+        channel.capacity = capacity;
+        channel.read_ring.memory = read_offset;
+        channel.write_ring.memory = write_offset;
+
+        if client {
+            std::mem::swap(&mut channel.read_ring, &mut channel.write_ring);
+            std::mem::swap(&mut channel.read_sem.data, &mut channel.write_sem.data);
+        }
+
+        // Rather than marking ourselves as waiting before each poll, like
+        // PulseAudio does, we're always waiting. That means the server
+        // always writes to the eventfd when it signals us.
+        channel
+            .atomic(channel.read_sem.data + SEM_WAITING)
+            .fetch_add(1, Ordering::AcqRel);
+
+        Ok(channel)
+    }
+
+    /// The eventfd signalled by the server, which should be polled for
+    /// readability.
+    pub(super) fn read_fd(&self) -> RawFd {
+        self.read_sem.fd.as_raw_fd()
+    }
+
+    /// Clears a wakeup from the server. This must be called after polling
+    /// [read_fd](Self::read_fd), before reading from the channel.
+    pub(super) fn clear_wakeup(&mut self) {
+        let in_pipe = self.atomic(self.read_sem.data + SEM_IN_PIPE);
+        if in_pipe.load(Ordering::Acquire) > 0 {
+            loop {
+                let mut buf = [0; 8];
+
+                // SAFETY: the buffer is valid for 8 bytes.
+                let n = unsafe { libc::read(self.read_fd(), buf.as_mut_ptr().cast(), 8) };
+                if n != 8 {
+                    let err = io::Error::last_os_error();
+                    if err.kind() == io::ErrorKind::Interrupted {
+                        continue;
+                    }
+
+                    log::error!("failed to read from srbchannel eventfd: {err}");
+                    break;
+                }
+
+                let n = u64::from_ne_bytes(buf) as i32;
+                if in_pipe.fetch_sub(n, Ordering::AcqRel) <= n {
+                    break;
+                }
+            }
+        }
+
+        let _ = self
+            .atomic(self.read_sem.data + SEM_SIGNALLED)
+            .compare_exchange(1, 0, Ordering::AcqRel, Ordering::Acquire);
+    }
+
+    /// Reads as much as possible from the channel, returning the number of
+    /// bytes read.
+    pub(super) fn read(&mut self, buf: &mut [u8]) -> usize {
+        let mut n = 0;
+
+        while n < buf.len() {
+            let count = self.atomic(self.read_ring.count).load(Ordering::Acquire) as usize;
+            let len = count
+                .min(self.capacity - self.read_ring.index)
+                .min(buf.len() - n);
+            if len == 0 {
+                break;
+            }
+
+            // SAFETY: the range is within the ring, and the server won't
+            // write to it until we decrement the count.
+            unsafe {
+                let src = self
+                    .block
+                    .as_ptr()
+                    .add(self.read_ring.memory + self.read_ring.index);
+                std::ptr::copy_nonoverlapping(src, buf[n..].as_mut_ptr(), len);
+            }
+
+            let prev = self
+                .atomic(self.read_ring.count)
+                .fetch_sub(len as i32, Ordering::AcqRel);
+            self.read_ring.index = (self.read_ring.index + len) % self.capacity;
+            n += len;
+
+            // If the ring was full, the server is waiting for space.
+            if prev as usize >= self.capacity {
+                self.post();
+            }
+        }
+
+        n
+    }
+
+    /// Writes as much as possible to the channel, returning the number of
+    /// bytes written.
+    pub(super) fn write(&mut self, buf: &[u8]) -> usize {
+        let mut n = 0;
+
+        while n < buf.len() {
+            let count = self.atomic(self.write_ring.count).load(Ordering::Acquire) as usize;
+            let len = (self.capacity - self.write_ring.index)
+                .min(self.capacity.saturating_sub(count))
+                .min(buf.len() - n);
+            if len == 0 {
+                break;
+            }
+
+            // SAFETY: the range is within the ring, and the server won't read
+            // from it until we increment the count.
+            unsafe {
+                let dst = self
+                    .block
+                    .as_ptr()
+                    .add(self.write_ring.memory + self.write_ring.index);
+                std::ptr::copy_nonoverlapping(buf[n..].as_ptr(), dst, len);
+            }
+
+            self.atomic(self.write_ring.count)
+                .fetch_add(len as i32, Ordering::AcqRel);
+            self.write_ring.index = (self.write_ring.index + len) % self.capacity;
+            n += len;
+        }
+
+        self.post();
+        n
+    }
+
+    /// Signals the server, if it's waiting.
+    fn post(&self) {
+        let signalled = self.atomic(self.write_sem.data + SEM_SIGNALLED);
+        if signalled
+            .compare_exchange(0, 1, Ordering::AcqRel, Ordering::Acquire)
+            .is_err()
+        {
+            return;
+        }
+
+        if self
+            .atomic(self.write_sem.data + SEM_WAITING)
+            .load(Ordering::Acquire)
+            <= 0
+        {
+            return;
+        }
+
+        self.atomic(self.write_sem.data + SEM_IN_PIPE)
+            .fetch_add(1, Ordering::AcqRel);
+
+        let buf = 1_u64.to_ne_bytes();
+        loop {
+            // SAFETY: the buffer is valid for 8 bytes.
+            let n = unsafe { libc::write(self.write_sem.fd.as_raw_fd(), buf.as_ptr().cast(), 8) };
+            if n == 8 {
+                return;
+            }
+
+            let err = io::Error::last_os_error();
+            if err.kind() != io::ErrorKind::Interrupted {
+                log::error!("failed to write to srbchannel eventfd: {err}");
+                return;
+            }
+        }
+    }
+
+    /// Reads an integer from the header.
+    fn header(&self, offset: usize) -> usize {
+        self.atomic(offset).load(Ordering::Acquire).max(0) as usize
+    }
+
+    fn atomic(&self, offset: usize) -> &AtomicI32 {
+        // SAFETY: all offsets are within the header, which was checked to be
+        // in bounds and aligned. The server only accesses these atomically.
+        unsafe { &*(self.block.as_ptr().add(offset) as *const AtomicI32) }
+    }
+}
+
+impl io::Write for Srbchannel {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match Srbchannel::write(self, buf) {
+            0 if !buf.is_empty() => Err(io::ErrorKind::WouldBlock.into()),
+            n => Ok(n),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        ffi::CString,
+        io::BufReader,
+        os::{
+            fd::{AsFd as _, FromRawFd as _},
+            unix::net::UnixStream,
+        },
+        time::Duration,
+    };
+
+    use futures::executor::block_on;
+
+    use super::{
+        super::{
+            shm::{ShmImports, ShmPool},
+            socket, test_util,
+        },
+        *,
+    };
+    use crate::{
+        Client,
+        protocol::{self, DescriptorFlags},
+    };
+
+    fn eventfd() -> OwnedFd {
+        // SAFETY: the fd is checked, and owned by us.
+        unsafe {
+            let fd = libc::eventfd(0, libc::EFD_CLOEXEC);
+            assert!(fd >= 0);
+            OwnedFd::from_raw_fd(fd)
+        }
+    }
+
+    /// Allocates a block for a channel and writes the header, like
+    /// pa_srbchannel_new.
+    fn init_block(pool: &mut ShmPool) -> protocol::ShmRef {
+        let block_id = pool.alloc().unwrap();
+        let shm_ref = pool.shm_ref(block_id, usize::MAX);
+
+        let header = &mut pool.block_mut(block_id)[..HEADER_SIZE];
+        let read_offset = HEADER_SIZE.next_multiple_of(8);
+        let capacity = (shm_ref.length as usize - read_offset) / 2;
+        let write_offset = (read_offset + capacity).next_multiple_of(8);
+        let capacity = capacity.min(write_offset - read_offset);
+        header[CAPACITY..][..4].copy_from_slice(&(capacity as i32).to_ne_bytes());
+        header[READBUF_OFFSET..][..4].copy_from_slice(&(read_offset as i32).to_ne_bytes());
+        header[WRITEBUF_OFFSET..][..4].copy_from_slice(&(write_offset as i32).to_ne_bytes());
+
+        shm_ref
+    }
+
+    /// Sets up both sides of a channel. The block is in a memfd pool, since
+    /// POSIX segments are created read-only for anyone but their creator.
+    fn channel_pair() -> anyhow::Result<(ShmPool, Srbchannel, Srbchannel)> {
+        let mut pool = ShmPool::new_memfd()?;
+        let shm_ref = init_block(&mut pool);
+
+        let mut imports = ShmImports::default();
+        imports.register_memfd(pool.id(), pool.memfd().unwrap().try_clone_to_owned()?)?;
+
+        let (server_read, server_write) = (eventfd(), eventfd());
+        let client = Srbchannel::new(
+            imports.attach_writable(&shm_ref, true)?,
+            server_read.try_clone()?,
+            server_write.try_clone()?,
+        )?;
+
+        let server = Srbchannel::with_roles(
+            imports.attach_writable(&shm_ref, true)?,
+            server_read,
+            server_write,
+            false,
+        )?;
+
+        Ok((pool, client, server))
+    }
+
+    #[test]
+    fn roundtrip() -> anyhow::Result<()> {
+        let (_pool, mut client, mut server) = channel_pair()?;
+        let mut buf = [0; 16];
+
+        assert_eq!(server.write(b"hello"), 5);
+        client.clear_wakeup();
+        assert_eq!(client.read(&mut buf), 5);
+        assert_eq!(&buf[..5], b"hello");
+        assert_eq!(client.read(&mut buf), 0);
+
+        assert_eq!(client.write(b"world"), 5);
+        server.clear_wakeup();
+        assert_eq!(server.read(&mut buf), 5);
+        assert_eq!(&buf[..5], b"world");
+
+        Ok(())
+    }
+
+    #[test]
+    fn full_ring() -> anyhow::Result<()> {
+        let (_pool, mut client, mut server) = channel_pair()?;
+        let capacity = client.capacity;
+
+        // Fill the ring, wrapping around.
+        let data = (0..capacity + 100).map(|i| i as u8).collect::<Vec<_>>();
+        assert_eq!(client.write(&data[..100]), 100);
+        let mut buf = vec![0; capacity + 100];
+        assert_eq!(server.read(&mut buf), 100);
+
+        assert_eq!(client.write(&data[100..]), capacity);
+        assert_eq!(client.write(&data[100..]), 0);
+
+        // Draining the full ring signals the client.
+        server.clear_wakeup();
+        assert_eq!(server.read(&mut buf), capacity);
+        assert_eq!(&buf[..capacity], &data[100..capacity + 100]);
+
+        let signalled = |c: &Srbchannel| {
+            c.atomic(c.read_sem.data + SEM_SIGNALLED)
+                .load(Ordering::Acquire)
+        };
+
+        assert_eq!(signalled(&client), 1);
+        client.clear_wakeup();
+        assert_eq!(signalled(&client), 0);
+
+        Ok(())
+    }
+
+    /// Reads a single message from the channel, polling until it arrives.
+    fn read_message(srb: &mut Srbchannel) -> anyhow::Result<Vec<u8>> {
+        let mut msg = Vec::new();
+        let mut buf = [0; 1024];
+
+        for _ in 0..5000 {
+            srb.clear_wakeup();
+            let n = srb.read(&mut buf);
+            msg.extend_from_slice(&buf[..n]);
+
+            if msg.len() >= protocol::DESCRIPTOR_SIZE {
+                let desc = protocol::read_descriptor(&mut io::Cursor::new(&msg))?;
+                if msg.len() >= protocol::DESCRIPTOR_SIZE + desc.length as usize {
+                    return Ok(msg);
+                }
+            }
+
+            std::thread::sleep(Duration::from_millis(1));
+        }
+
+        anyhow::bail!("timed out waiting for a message")
+    }
+
+    /// A minimal server, which offers an srbchannel in a memfd pool during the
+    /// handshake, like PulseAudio, and then answers a single GetServerInfo
+    /// command over it.
+    fn fake_srbchannel_server(stream: UnixStream) -> anyhow::Result<UnixStream> {
+        let mut sock = BufReader::new(socket::FdStream::new(&stream));
+        let version = protocol::MAX_VERSION;
+
+        let mut pool = ShmPool::new_memfd()?;
+        let shm_ref = init_block(&mut pool);
+        let (read_fd, write_fd) = (eventfd(), eventfd());
+
+        test_util::serve_handshake(&mut sock, true, true, |sock| {
+            let cmd = protocol::Command::RegisterMemfdShmid(pool.id());
+            test_util::send_command_with_fds(sock, u32::MAX, &cmd, &[pool.memfd().unwrap()])?;
+
+            let cmd = protocol::Command::EnableSrbchannel;
+            let fds = [read_fd.as_fd(), write_fd.as_fd()];
+            test_util::send_command_with_fds(sock, 42, &cmd, &fds)?;
+            protocol::write_shm_memblock(
+                sock.get_mut(),
+                0,
+                &shm_ref,
+                0,
+                DescriptorFlags::FLAG_SHMWRITABLE | DescriptorFlags::FLAG_SHMDATA_MEMFD_BLOCK,
+            )?;
+
+            Ok(())
+        })?;
+
+        // The client registers its own pool, then acknowledges over the
+        // socket, and then switches over.
+        let (_, cmd) = protocol::read_command_message(&mut sock, version)?;
+        assert!(matches!(cmd, protocol::Command::RegisterMemfdShmid(_)));
+
+        let (seq, cmd) = protocol::read_command_message(&mut sock, version)?;
+        assert_eq!(seq, 42);
+        assert!(matches!(cmd, protocol::Command::EnableSrbchannel));
+
+        let mut imports = ShmImports::default();
+        imports.register_memfd(pool.id(), pool.memfd().unwrap().try_clone_to_owned()?)?;
+        let block = imports.attach_writable(&shm_ref, true)?;
+        let mut srb = Srbchannel::with_roles(block, read_fd, write_fd, false)?;
+
+        let msg = read_message(&mut srb)?;
+        let (seq, cmd) = protocol::read_command_message(&mut io::Cursor::new(msg), version)?;
+        assert!(matches!(cmd, protocol::Command::GetServerInfo));
+
+        let reply = protocol::ServerInfo {
+            server_name: Some(CString::new("srb-test")?),
+            ..Default::default()
+        };
+        protocol::write_reply_message(&mut srb, seq, &reply, version)?;
+
+        // Keep the socket open until the client has the reply.
+        drop(sock);
+        Ok(stream)
+    }
+
+    #[test]
+    fn client_over_srbchannel() -> anyhow::Result<()> {
+        let (client_sock, server_sock) = UnixStream::pair()?;
+        let server = std::thread::spawn(move || fake_srbchannel_server(server_sock));
+
+        let client = Client::new_unix(c"srb-test", client_sock, None::<Vec<u8>>)?;
+        let info = block_on(client.server_info())?;
+        assert_eq!(info.server_name.as_deref(), Some(c"srb-test"));
+
+        server.join().unwrap()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,188 @@
+use std::{
+    ffi::CString,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+};
+
+use futures::{Stream, channel::mpsc};
+
+use crate::protocol;
+
+/// A change to a playback or record stream, initiated by the server. See
+/// [PlaybackStream::events](super::PlaybackStream::events) and
+/// [RecordStream::events](super::RecordStream::events).
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum StreamEvent {
+    /// The stream was moved to a different device, for example by the user
+    /// in a mixer application.
+    Moved {
+        /// The index of the new sink or source.
+        device_index: u32,
+        /// The name of the new sink or source.
+        device_name: CString,
+        /// Whether the new device is suspended.
+        device_suspended: bool,
+        /// The attributes of the stream's buffer on the new device.
+        buffer_attr: protocol::stream::BufferAttr,
+    },
+    /// The device the stream is connected to was suspended or resumed.
+    Suspended(bool),
+    /// The server changed the attributes of the stream's buffer.
+    BufferAttrChanged(protocol::stream::BufferAttr),
+    /// The server ran out of data to play (playback streams only).
+    Underflow {
+        /// The offset in the stream, in bytes, at which the underflow occurred.
+        offset: i64,
+    },
+    /// The client sent more data than fits in the server-side buffer
+    /// (playback streams only).
+    Overflow,
+    /// The client reconnected to the server, and the stream was recreated
+    /// with its original parameters. The stream's channel, index and device
+    /// may have changed, and any data buffered by the server was lost.
+    Recreated,
+}
+
+/// A [Stream] of [StreamEvents](StreamEvent) for a single playback or record
+/// stream. The stream ends if the stream is deleted or killed, or the client
+/// disconnects.
+#[derive(Debug)]
+pub struct StreamEvents(mpsc::UnboundedReceiver<StreamEvent>);
+
+impl StreamEvents {
+    /// Returns a stream that has already ended.
+    pub(super) fn ended() -> Self {
+        Self(mpsc::unbounded().1)
+    }
+}
+
+impl Stream for StreamEvents {
+    type Item = StreamEvent;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        Pin::new(&mut self.0).poll_next(cx)
+    }
+}
+
+/// The parts of a stream's state that can change after it's created. This is
+/// shared between the stream handle and the reactor, which updates it when
+/// the server sends an event for the stream.
+pub(super) type SharedStreamState = Arc<Mutex<StreamState>>;
+
+pub(super) struct StreamState {
+    pub(super) channel: u32,
+    pub(super) stream_index: u32,
+    pub(super) sample_spec: protocol::SampleSpec,
+    pub(super) device_index: u32,
+    pub(super) buffer_attr: protocol::stream::BufferAttr,
+    pub(super) suspended: bool,
+    pub(super) underflows: u64,
+    pub(super) overflows: u64,
+    pub(super) killed: bool,
+}
+
+impl StreamState {
+    pub(super) fn new_shared(
+        channel: u32,
+        stream_index: u32,
+        sample_spec: protocol::SampleSpec,
+        device_index: u32,
+        buffer_attr: protocol::stream::BufferAttr,
+        suspended: bool,
+    ) -> SharedStreamState {
+        Arc::new(Mutex::new(Self {
+            channel,
+            stream_index,
+            sample_spec,
+            device_index,
+            buffer_attr,
+            suspended,
+            underflows: 0,
+            overflows: 0,
+            killed: false,
+        }))
+    }
+}
+
+/// Held by the reactor for each stream, to apply events to the shared state
+/// and forward them to listeners. Dropping the tracker ends any
+/// [StreamEvents] streams.
+pub(super) struct StreamTracker {
+    pub(super) state: SharedStreamState,
+    listeners: Vec<mpsc::UnboundedSender<StreamEvent>>,
+}
+
+impl StreamTracker {
+    pub(super) fn new(state: SharedStreamState) -> Self {
+        Self {
+            state,
+            listeners: Vec::new(),
+        }
+    }
+
+    pub(super) fn listen(&mut self) -> StreamEvents {
+        let (tx, rx) = mpsc::unbounded();
+        self.listeners.push(tx);
+        StreamEvents(rx)
+    }
+
+    /// Updates the shared state to reflect the event, and then forwards it
+    /// to any listeners.
+    pub(super) fn apply(&mut self, event: StreamEvent) {
+        {
+            let mut state = self.state.lock().unwrap();
+            match &event {
+                StreamEvent::Moved {
+                    device_index,
+                    device_suspended,
+                    buffer_attr,
+                    ..
+                } => {
+                    state.device_index = *device_index;
+                    state.suspended = *device_suspended;
+                    state.buffer_attr = *buffer_attr;
+                }
+                StreamEvent::Suspended(suspended) => state.suspended = *suspended,
+                StreamEvent::BufferAttrChanged(attr) => state.buffer_attr = *attr,
+                StreamEvent::Underflow { .. } => state.underflows += 1,
+                StreamEvent::Overflow => state.overflows += 1,
+                StreamEvent::Recreated => (),
+            }
+        }
+
+        self.listeners
+            .retain(|tx| tx.unbounded_send(event.clone()).is_ok());
+    }
+
+    /// Updates the shared state after the stream was recreated on a new
+    /// connection, and notifies listeners.
+    pub(super) fn recreated(
+        &mut self,
+        channel: u32,
+        stream_index: u32,
+        sample_spec: protocol::SampleSpec,
+        device_index: u32,
+        buffer_attr: protocol::stream::BufferAttr,
+        suspended: bool,
+    ) {
+        {
+            let mut state = self.state.lock().unwrap();
+            state.channel = channel;
+            state.stream_index = stream_index;
+            state.sample_spec = sample_spec;
+            state.device_index = device_index;
+            state.buffer_attr = buffer_attr;
+            state.suspended = suspended;
+        }
+
+        self.apply(StreamEvent::Recreated);
+    }
+
+    /// Marks the stream as killed by the server. This should be called just
+    /// before the tracker is dropped.
+    pub(super) fn kill(&self) {
+        self.state.lock().unwrap().killed = true;
+    }
+}
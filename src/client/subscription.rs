@@ -0,0 +1,108 @@
+use std::{
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use futures::{Stream, channel::mpsc};
+
+use super::{ClientError, reactor::ReactorHandle};
+use crate::protocol;
+
+/// A stream of [SubscriptionEvents](protocol::SubscriptionEvent) from the
+/// server, created with [Client::subscribe](super::Client::subscribe).
+///
+/// Any number of subscriptions can share a single client. Dropping the
+/// subscription unsubscribes from any events that no other subscription is
+/// interested in. The stream ends if the client disconnects, unless it
+/// reconnects automatically (see
+/// [Client::connect_reconnecting](super::Client::connect_reconnecting)), in
+/// which case the subscription is renewed.
+pub struct Subscription {
+    handle: ReactorHandle,
+    id: u64,
+    mask: protocol::SubscriptionMask,
+    events: mpsc::UnboundedReceiver<protocol::SubscriptionEvent>,
+}
+
+impl std::fmt::Debug for Subscription {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("Subscription").field(&self.mask).finish()
+    }
+}
+
+impl Subscription {
+    pub(super) async fn new(
+        handle: ReactorHandle,
+        mask: protocol::SubscriptionMask,
+    ) -> Result<Self, ClientError> {
+        let (id, events) = handle.insert_subscriber(mask).await?;
+
+        Ok(Self {
+            handle,
+            id,
+            mask,
+            events,
+        })
+    }
+
+    /// The mask of events delivered by this subscription.
+    pub fn mask(&self) -> protocol::SubscriptionMask {
+        self.mask
+    }
+}
+
+impl Stream for Subscription {
+    type Item = protocol::SubscriptionEvent;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        Pin::new(&mut self.events).poll_next(cx)
+    }
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        self.handle.remove_subscriber(self.id);
+    }
+}
+
+/// A stream that yields each time the entries stored by module-stream-restore
+/// change, created with
+/// [Client::subscribe_stream_restore](super::Client::subscribe_stream_restore).
+/// The server doesn't say which entries changed, so they have to be fetched
+/// again with
+/// [Client::stream_restore_entries](super::Client::stream_restore_entries).
+///
+/// Like a [Subscription], the stream ends if the client disconnects, unless it
+/// reconnects automatically.
+pub struct StreamRestoreSubscription {
+    handle: ReactorHandle,
+    id: u64,
+    events: mpsc::UnboundedReceiver<()>,
+}
+
+impl std::fmt::Debug for StreamRestoreSubscription {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("StreamRestoreSubscription").finish()
+    }
+}
+
+impl StreamRestoreSubscription {
+    pub(super) async fn new(handle: ReactorHandle) -> Result<Self, ClientError> {
+        let (id, events) = handle.insert_stream_restore_subscriber().await?;
+        Ok(Self { handle, id, events })
+    }
+}
+
+impl Stream for StreamRestoreSubscription {
+    type Item = ();
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        Pin::new(&mut self.events).poll_next(cx)
+    }
+}
+
+impl Drop for StreamRestoreSubscription {
+    fn drop(&mut self) {
+        self.handle.remove_stream_restore_subscriber(self.id);
+    }
+}
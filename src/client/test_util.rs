@@ -0,0 +1,66 @@
+//! Helpers for tests that run a client against a fake server.
+
+use std::{
+    io::{BufRead, BufReader, Read, Write},
+    os::fd::{AsFd as _, BorrowedFd},
+};
+
+use super::socket;
+use crate::protocol;
+
+/// Handles the server side of the handshake, returning the client's auth
+/// parameters. `before_reply` is called before the reply to SetClientName,
+/// which is where PulseAudio sends anything the client has to handle before
+/// the connection is ready, like memfd registrations.
+pub(super) fn serve_handshake<S: Read + Write>(
+    sock: &mut BufReader<S>,
+    use_shm: bool,
+    use_memfd: bool,
+    before_reply: impl FnOnce(&mut BufReader<S>) -> anyhow::Result<()>,
+) -> anyhow::Result<protocol::AuthParams> {
+    let version = protocol::MAX_VERSION;
+
+    let (seq, cmd) = protocol::read_command_message(sock, version)?;
+    let protocol::Command::Auth(auth) = cmd else {
+        anyhow::bail!("expected auth, got {cmd:?}");
+    };
+
+    let reply = protocol::AuthReply {
+        version,
+        use_shm,
+        use_memfd,
+    };
+    protocol::write_reply_message(sock.get_mut(), seq, &reply, version)?;
+
+    let (seq, cmd) = protocol::read_command_message(sock, version)?;
+    if !matches!(cmd, protocol::Command::SetClientName(_)) {
+        anyhow::bail!("expected client name, got {cmd:?}");
+    }
+
+    before_reply(sock)?;
+
+    let reply = protocol::SetClientNameReply { client_id: 0 };
+    protocol::write_reply_message(sock.get_mut(), seq, &reply, version)?;
+
+    Ok(auth)
+}
+
+/// Sends a command to the client, passing file descriptors along with it.
+pub(super) fn send_command_with_fds(
+    sock: &mut BufReader<socket::FdStream<'_>>,
+    seq: u32,
+    cmd: &protocol::Command,
+    fds: &[BorrowedFd<'_>],
+) -> anyhow::Result<()> {
+    let mut buf = Vec::new();
+    protocol::write_command_message(&mut buf, seq, cmd, protocol::MAX_VERSION)?;
+    let n = socket::send_with_fds(&sock.get_ref().as_fd(), &buf, fds)?;
+    sock.get_mut().write_all(&buf[n..])?;
+
+    Ok(())
+}
+
+/// Reads and ignores commands until the client hangs up.
+pub(super) fn read_until_hangup(sock: &mut impl BufRead) {
+    while protocol::read_command_message(sock, protocol::MAX_VERSION).is_ok() {}
+}
@@ -0,0 +1,512 @@
+use std::{
+    fmt, io,
+    net::{SocketAddr, ToSocketAddrs as _},
+    path::PathBuf,
+    str::FromStr,
+};
+
+use crate::protocol::ProtocolError;
+
+/// The port used by `module-native-protocol-tcp` if none is specified.
+pub const DEFAULT_TCP_PORT: u16 = 4713;
+
+/// The socket used by a system-wide PulseAudio instance.
+const SYSTEM_SOCKET_PATH: &str = "/var/run/pulse/native";
+
+/// The address family to use when connecting to a TCP server.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum IpFamily {
+    /// Only connect over IPv4 (`tcp4:`).
+    V4,
+    /// Only connect over IPv6 (`tcp6:`).
+    V6,
+}
+
+/// The address of a PulseAudio server, as found in `$PULSE_SERVER` or the
+/// `default-server` option in `client.conf`.
+///
+/// The following forms are accepted, matching libpulse:
+///
+///  - `unix:/path/to/socket` or just `/path/to/socket`
+///  - `tcp:host`, `tcp:host:port` or `tcp:[v6addr]:port`
+///  - `tcp4:` and `tcp6:`, which restrict the address family
+///  - `host` or `host:port`, which is equivalent to `tcp:`
+///
+/// ```
+/// # use pulseaudio::{IpFamily, ServerAddress};
+/// let addr: ServerAddress = "tcp6:[::1]:4713".parse().unwrap();
+/// assert_eq!(
+///     addr,
+///     ServerAddress::Tcp {
+///         host: "::1".into(),
+///         port: 4713,
+///         family: Some(IpFamily::V6),
+///     }
+/// );
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ServerAddress {
+    /// A unix domain socket.
+    Unix(PathBuf),
+    /// A TCP server, for example one using `module-native-protocol-tcp`.
+    Tcp {
+        /// The hostname or IP address of the server.
+        host: String,
+        /// The port of the server.
+        port: u16,
+        /// The address family to restrict the connection to, if any.
+        family: Option<IpFamily>,
+    },
+}
+
+impl ServerAddress {
+    /// Parses a whitespace-separated list of server addresses, as used in
+    /// `$PULSE_SERVER`.
+    ///
+    /// Entries may be prefixed with `{machine-id}`, in which case they are
+    /// only included if the ID matches the local machine. Invalid entries are
+    /// skipped.
+    pub fn parse_list(s: &str) -> Vec<Self> {
+        parse_list_for_machine(s, machine_id().as_deref())
+    }
+
+    /// Resolves a TCP address to a list of socket addresses. For unix
+    /// addresses, this returns an empty list.
+    pub fn to_socket_addrs(&self) -> io::Result<Vec<SocketAddr>> {
+        let ServerAddress::Tcp { host, port, family } = self else {
+            return Ok(Vec::new());
+        };
+
+        Ok((host.as_str(), *port)
+            .to_socket_addrs()?
+            .filter(|addr| match family {
+                Some(IpFamily::V4) => addr.is_ipv4(),
+                Some(IpFamily::V6) => addr.is_ipv6(),
+                None => true,
+            })
+            .collect())
+    }
+}
+
+impl FromStr for ServerAddress {
+    type Err = ProtocolError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || ProtocolError::Invalid(format!("invalid server address: {s}"));
+
+        if let Some(path) = s.strip_prefix("unix:") {
+            if path.is_empty() {
+                return Err(invalid());
+            }
+
+            return Ok(Self::Unix(path.into()));
+        } else if s.starts_with('/') {
+            return Ok(Self::Unix(s.into()));
+        }
+
+        let (rest, family) = if let Some(rest) = s.strip_prefix("tcp4:") {
+            (rest, Some(IpFamily::V4))
+        } else if let Some(rest) = s.strip_prefix("tcp6:") {
+            (rest, Some(IpFamily::V6))
+        } else if let Some(rest) = s.strip_prefix("tcp:") {
+            (rest, None)
+        } else {
+            (s, None)
+        };
+
+        let (host, port) = split_host_port(rest).ok_or_else(invalid)?;
+        Ok(Self::Tcp {
+            host: host.to_owned(),
+            port,
+            family,
+        })
+    }
+}
+
+impl fmt::Display for ServerAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ServerAddress::Unix(path) => write!(f, "unix:{}", path.display()),
+            ServerAddress::Tcp { host, port, family } => {
+                let scheme = match family {
+                    None => "tcp",
+                    Some(IpFamily::V4) => "tcp4",
+                    Some(IpFamily::V6) => "tcp6",
+                };
+
+                if host.contains(':') {
+                    write!(f, "{scheme}:[{host}]:{port}")
+                } else {
+                    write!(f, "{scheme}:{host}:{port}")
+                }
+            }
+        }
+    }
+}
+
+/// Splits `host`, `host:port`, `[v6addr]` or `[v6addr]:port`. A bare IPv6
+/// address without brackets is also accepted, and uses the default port.
+fn split_host_port(s: &str) -> Option<(&str, u16)> {
+    let (host, port) = if let Some(rest) = s.strip_prefix('[') {
+        let (host, rest) = rest.split_once(']')?;
+        match rest.strip_prefix(':') {
+            Some(port) => (host, Some(port)),
+            None if rest.is_empty() => (host, None),
+            None => return None,
+        }
+    } else {
+        match s.split_once(':') {
+            Some((host, port)) if !port.contains(':') => (host, Some(port)),
+            _ => (s, None),
+        }
+    };
+
+    if host.is_empty() {
+        return None;
+    }
+
+    let port = match port {
+        Some(port) => port.parse().ok()?,
+        None => DEFAULT_TCP_PORT,
+    };
+
+    Some((host, port))
+}
+
+fn parse_list_for_machine(s: &str, machine_id: Option<&str>) -> Vec<ServerAddress> {
+    let mut addrs = Vec::new();
+    for entry in s.split_ascii_whitespace() {
+        let entry = if let Some(rest) = entry.strip_prefix('{') {
+            let Some((id, rest)) = rest.split_once('}') else {
+                log::warn!("invalid server address: {entry}");
+                continue;
+            };
+
+            if Some(id) != machine_id {
+                log::debug!("skipping server address for another machine: {entry}");
+                continue;
+            }
+
+            rest
+        } else {
+            entry
+        };
+
+        match entry.parse() {
+            Ok(addr) => addrs.push(addr),
+            Err(err) => log::warn!("{err}"),
+        }
+    }
+
+    addrs
+}
+
+/// Returns the local machine ID, falling back to the hostname as libpulse
+/// does.
+fn machine_id() -> Option<String> {
+    [
+        "/etc/machine-id",
+        "/var/lib/dbus/machine-id",
+        "/proc/sys/kernel/hostname",
+    ]
+    .into_iter()
+    .filter_map(|path| std::fs::read_to_string(path).ok())
+    .map(|s| s.trim().to_owned())
+    .find(|s| !s.is_empty())
+}
+
+/// The subset of libpulse's `client.conf` that's relevant to connecting to
+/// a server.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ClientConfig {
+    /// The `default-server` option, a list of servers in the format
+    /// understood by [ServerAddress::parse_list].
+    pub default_server: Option<String>,
+    /// The `cookie-file` option.
+    pub cookie_file: Option<PathBuf>,
+    /// The `auto-connect-localhost` option, which adds TCP connections to
+    /// localhost to the default list of servers.
+    pub auto_connect_localhost: bool,
+}
+
+impl ClientConfig {
+    /// Loads `client.conf` from the first of the following locations that
+    /// exists:
+    ///
+    ///   - $PULSE_CLIENTCONFIG
+    ///   - $XDG_CONFIG_HOME/pulse/client.conf
+    ///   - $HOME/.config/pulse/client.conf
+    ///   - /etc/pulse/client.conf
+    ///
+    /// If `$PULSE_CLIENTCONFIG` is set, the other locations aren't checked.
+    /// Returns the default configuration if no file can be read.
+    pub fn from_env() -> Self {
+        let paths: Vec<PathBuf> = if let Ok(path) = std::env::var("PULSE_CLIENTCONFIG") {
+            vec![path.into()]
+        } else {
+            #[allow(deprecated)]
+            let home = std::env::home_dir();
+
+            std::env::var("XDG_CONFIG_HOME")
+                .ok()
+                .map(|s| PathBuf::from(s).join("pulse/client.conf"))
+                .into_iter()
+                .chain(home.map(|home| home.join(".config/pulse/client.conf")))
+                .chain(std::iter::once("/etc/pulse/client.conf".into()))
+                .collect()
+        };
+
+        for path in paths {
+            if let Ok(s) = std::fs::read_to_string(&path) {
+                log::debug!("loading client config from {}", path.display());
+                return Self::parse(&s);
+            }
+        }
+
+        Self::default()
+    }
+
+    /// Parses the contents of a `client.conf` file. Unknown options and
+    /// invalid lines are ignored.
+    pub fn parse(s: &str) -> Self {
+        let mut config = Self::default();
+
+        for line in s.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with(['#', ';', '[']) {
+                continue;
+            }
+
+            let Some((key, value)) = line.split_once('=') else {
+                log::warn!("invalid line in client config: {line}");
+                continue;
+            };
+
+            let value = value.trim();
+            match key.trim() {
+                "default-server" if !value.is_empty() => {
+                    config.default_server = Some(value.to_owned())
+                }
+                "cookie-file" if !value.is_empty() => config.cookie_file = Some(value.into()),
+                "auto-connect-localhost" => match parse_bool(value) {
+                    Some(v) => config.auto_connect_localhost = v,
+                    None => log::warn!("invalid value for auto-connect-localhost: {value}"),
+                },
+                _ => (),
+            }
+        }
+
+        config
+    }
+
+    /// Returns the servers to try connecting to, in order.
+    ///
+    /// If `$PULSE_SERVER` is set, it overrides the `default-server` option. If
+    /// neither is set, the candidates are the per-user socket, then the
+    /// system-wide socket, then (if `auto-connect-localhost` is set) TCP on
+    /// localhost.
+    pub fn server_candidates(&self) -> Vec<ServerAddress> {
+        let server = std::env::var("PULSE_SERVER")
+            .ok()
+            .filter(|s| !s.trim().is_empty())
+            .or_else(|| self.default_server.clone());
+
+        match server {
+            Some(server) => ServerAddress::parse_list(&server),
+            None => self.default_candidates(user_socket_path()),
+        }
+    }
+
+    fn default_candidates(&self, user_socket_path: Option<PathBuf>) -> Vec<ServerAddress> {
+        let mut candidates: Vec<_> = user_socket_path
+            .into_iter()
+            .chain(std::iter::once(SYSTEM_SOCKET_PATH.into()))
+            .map(ServerAddress::Unix)
+            .collect();
+
+        if self.auto_connect_localhost {
+            candidates.push(ServerAddress::Tcp {
+                host: "127.0.0.1".into(),
+                port: DEFAULT_TCP_PORT,
+                family: Some(IpFamily::V4),
+            });
+            candidates.push(ServerAddress::Tcp {
+                host: "::1".into(),
+                port: DEFAULT_TCP_PORT,
+                family: Some(IpFamily::V6),
+            });
+        }
+
+        candidates
+    }
+}
+
+/// The per-user socket, in $PULSE_RUNTIME_PATH or $XDG_RUNTIME_DIR/pulse.
+fn user_socket_path() -> Option<PathBuf> {
+    std::env::var("PULSE_RUNTIME_PATH")
+        .ok()
+        .map(|s| PathBuf::from(s).join("native"))
+        .or_else(|| {
+            std::env::var("XDG_RUNTIME_DIR")
+                .ok()
+                .map(|s| PathBuf::from(s).join("pulse/native"))
+        })
+}
+
+/// Parses a boolean the way libpulse's `pa_parse_boolean` does.
+fn parse_bool(s: &str) -> Option<bool> {
+    match s.to_ascii_lowercase().as_str() {
+        "1" | "y" | "yes" | "t" | "true" | "on" => Some(true),
+        "0" | "n" | "no" | "f" | "false" | "off" => Some(false),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    use super::*;
+
+    fn tcp(host: &str, port: u16, family: Option<IpFamily>) -> ServerAddress {
+        ServerAddress::Tcp {
+            host: host.into(),
+            port,
+            family,
+        }
+    }
+
+    #[test]
+    fn parse_server_address() {
+        let cases = [
+            (
+                "unix:/run/pulse/native",
+                ServerAddress::Unix("/run/pulse/native".into()),
+            ),
+            (
+                "/run/pulse/native",
+                ServerAddress::Unix("/run/pulse/native".into()),
+            ),
+            ("tcp:localhost", tcp("localhost", DEFAULT_TCP_PORT, None)),
+            ("tcp:localhost:1234", tcp("localhost", 1234, None)),
+            (
+                "tcp4:127.0.0.1",
+                tcp("127.0.0.1", DEFAULT_TCP_PORT, Some(IpFamily::V4)),
+            ),
+            ("tcp6:[::1]:1234", tcp("::1", 1234, Some(IpFamily::V6))),
+            (
+                "tcp6:fe80::1",
+                tcp("fe80::1", DEFAULT_TCP_PORT, Some(IpFamily::V6)),
+            ),
+            ("myhost:1234", tcp("myhost", 1234, None)),
+        ];
+
+        for (s, expected) in cases {
+            assert_eq!(s.parse::<ServerAddress>().unwrap(), expected, "{s:?}");
+        }
+
+        for s in ["", "unix:", "tcp:", "tcp::1234", "host:port", "[::1]1234"] {
+            assert!(
+                s.parse::<ServerAddress>().is_err(),
+                "{s:?} should not parse"
+            );
+        }
+    }
+
+    #[test]
+    fn server_address_display_roundtrip() {
+        for s in [
+            "unix:/run/pulse/native",
+            "tcp:localhost:4713",
+            "tcp4:127.0.0.1:1234",
+            "tcp6:[::1]:4713",
+        ] {
+            let addr: ServerAddress = s.parse().unwrap();
+            assert_eq!(addr.to_string(), s);
+        }
+    }
+
+    #[test]
+    fn server_address_to_socket_addrs() -> anyhow::Result<()> {
+        assert_eq!(
+            tcp("127.0.0.1", 1234, None).to_socket_addrs()?,
+            vec![SocketAddr::from((Ipv4Addr::LOCALHOST, 1234))]
+        );
+        assert_eq!(
+            tcp("::1", 1234, Some(IpFamily::V6)).to_socket_addrs()?,
+            vec![SocketAddr::from((Ipv6Addr::LOCALHOST, 1234))]
+        );
+        assert!(
+            tcp("::1", 1234, Some(IpFamily::V4))
+                .to_socket_addrs()?
+                .is_empty()
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn parse_server_list() {
+        let list = "{abc}unix:/a {def}unix:/b  /c\ttcp:host {abc}tcp4:other:1 {broken tcp:";
+        assert_eq!(
+            parse_list_for_machine(list, Some("abc")),
+            vec![
+                ServerAddress::Unix("/a".into()),
+                ServerAddress::Unix("/c".into()),
+                tcp("host", DEFAULT_TCP_PORT, None),
+                tcp("other", 1, Some(IpFamily::V4)),
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_client_config() {
+        let config = ClientConfig::parse(
+            "
+            ; a comment
+            # another comment
+            [section]
+            default-server = tcp:host unix:/a
+            cookie-file=/tmp/cookie
+            auto-connect-localhost = yes
+            autospawn = no
+            garbage
+            ",
+        );
+
+        assert_eq!(
+            config,
+            ClientConfig {
+                default_server: Some("tcp:host unix:/a".into()),
+                cookie_file: Some("/tmp/cookie".into()),
+                auto_connect_localhost: true,
+            }
+        );
+
+        assert_eq!(ClientConfig::parse(""), ClientConfig::default());
+    }
+
+    #[test]
+    fn default_candidates() {
+        let config = ClientConfig::default();
+        assert_eq!(
+            config.default_candidates(Some("/run/user/1000/pulse/native".into())),
+            vec![
+                ServerAddress::Unix("/run/user/1000/pulse/native".into()),
+                ServerAddress::Unix(SYSTEM_SOCKET_PATH.into()),
+            ]
+        );
+
+        let config = ClientConfig {
+            auto_connect_localhost: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            config.default_candidates(None),
+            vec![
+                ServerAddress::Unix(SYSTEM_SOCKET_PATH.into()),
+                tcp("127.0.0.1", DEFAULT_TCP_PORT, Some(IpFamily::V4)),
+                tcp("::1", DEFAULT_TCP_PORT, Some(IpFamily::V6)),
+            ]
+        );
+    }
+}
@@ -20,60 +20,54 @@
 use std::path::PathBuf;
 
 mod client;
+mod discovery;
 pub mod protocol;
 pub use client::*;
+pub use discovery::*;
 
-/// Attempts to determine the socket path from the runtime environment, checking
-/// the following locations in order:
-///   - $PULSE_SERVER
-///   - $PULSE_RUNTIME_PATH/pulse/native
+/// Attempts to determine the socket path from the runtime environment. This
+/// returns the first unix socket in the list of candidates from
+/// [ClientConfig::server_candidates] that exists, which by default means
+/// checking the following locations in order:
+///   - $PULSE_RUNTIME_PATH/native
 ///   - $XDG_RUNTIME_DIR/pulse/native
+///   - /var/run/pulse/native
 ///
-/// Returns None if no socket can be found or if $PULSE_SERVER points to a
-/// remote server, i.e. starts with a prefix other than 'unix:'.
+/// If $PULSE_SERVER or the `default-server` option in `client.conf` is set,
+/// only the unix sockets listed there are considered. Returns None if no
+/// socket can be found.
 pub fn socket_path_from_env() -> Option<PathBuf> {
-    let paths = std::env::var("PULSE_SERVER")
-        .ok()
-        .filter(|s| s.starts_with("unix:"))
-        .map(|s| PathBuf::from(&s[5..]))
+    ClientConfig::from_env()
+        .server_candidates()
         .into_iter()
-        .chain(
-            std::env::var("PULSE_RUNTIME_PATH")
-                .ok()
-                .map(|s| PathBuf::from(s).join("pulse/native")),
-        )
-        .chain(
-            std::env::var("XDG_RUNTIME_DIR")
-                .ok()
-                .map(|s| PathBuf::from(s).join("pulse/native")),
-        );
-
-    for path in paths {
-        let stat = std::fs::metadata(&path).ok()?;
-        if !stat.permissions().readonly() {
-            return Some(path);
-        }
-    }
-
-    None
+        .find_map(|addr| match addr {
+            ServerAddress::Unix(path) if path.exists() => Some(path),
+            _ => None,
+        })
 }
 
 /// Attempts to find the authentication cookie from the environment, checking
 /// the following locations in order:
 ///
 ///   - $PULSE_COOKIE
+///   - The `cookie-file` option in `client.conf`
 ///   - $HOME/.config/pulse/cookie
 ///   - $HOME/.pulse-cookie
 pub fn cookie_path_from_env() -> Option<PathBuf> {
+    cookie_path(&ClientConfig::from_env())
+}
+
+fn cookie_path(config: &ClientConfig) -> Option<PathBuf> {
     #[allow(deprecated)]
-    let home = std::env::home_dir()?;
+    let home = std::env::home_dir();
 
     let mut paths = std::env::var("PULSE_COOKIE")
         .ok()
         .map(PathBuf::from)
         .into_iter()
-        .chain(std::iter::once(home.join(".config/pulse/cookie")))
-        .chain(std::iter::once(home.join(".pulse-cookie")));
+        .chain(config.cookie_file.clone())
+        .chain(home.iter().map(|home| home.join(".config/pulse/cookie")))
+        .chain(home.iter().map(|home| home.join(".pulse-cookie")));
 
     paths.find(|path| path.exists())
 }
@@ -42,14 +42,57 @@ bitflags! {
     /// Special message types.
     #[derive(Default, Debug, Copy, Clone, PartialEq, Eq)]
     pub struct DescriptorFlags: u32 {
+        /// Indicates a memblock packet whose payload is a [`ShmRef`] to a
+        /// block in a shared memory pool, rather than the data itself.
+        const FLAG_SHMDATA = 0x80000000;
+
         /// Indicates a SHMRELEASE message.
         const FLAG_SHMRELEASE = 0x40000000; // 0b0100
 
         /// Indicates a SHMREVOKE message.
         const FLAG_SHMREVOKE = 0xC0000000; // 0b1100 FIXME 2 bits set?
+
+        /// Set along with `FLAG_SHMDATA` if the referenced pool is a memfd
+        /// pool, rather than a POSIX shared memory segment.
+        const FLAG_SHMDATA_MEMFD_BLOCK = 0x20000000;
+
+        /// Set along with `FLAG_SHMDATA` if the receiver may write to the
+        /// referenced block.
+        const FLAG_SHMWRITABLE = 0x00800000;
     }
 }
 
+/// The size of a [`ShmRef`] on the wire.
+pub const SHM_REF_SIZE: usize = 4 * 4;
+
+/// A reference to a block in a shared memory pool, sent as the payload of a
+/// memblock packet with [`DescriptorFlags::FLAG_SHMDATA`] set.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ShmRef {
+    /// An ID for the block chosen by the sender, which the receiver uses to
+    /// release the block with a SHMRELEASE message.
+    pub block_id: u32,
+    /// The ID of the shared memory pool. For POSIX shared memory, the pool is
+    /// named `/pulse-shm-{shm_id}`.
+    pub shm_id: u32,
+    /// The offset of the block in the pool, in bytes.
+    pub offset: u32,
+    /// The length of the block, in bytes.
+    pub length: u32,
+}
+
+/// Reads a [`ShmRef`] from a memblock payload.
+pub fn read_shm_ref<R: Read>(r: &mut R) -> Result<ShmRef, ProtocolError> {
+    use byteorder::ReadBytesExt;
+
+    Ok(ShmRef {
+        block_id: r.read_u32::<NetworkEndian>()?,
+        shm_id: r.read_u32::<NetworkEndian>()?,
+        offset: r.read_u32::<NetworkEndian>()?,
+        length: r.read_u32::<NetworkEndian>()?,
+    })
+}
+
 /// Packet descriptor / header.
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct Descriptor {
@@ -379,6 +422,69 @@ pub fn write_memblock<W: Write>(
     Ok(())
 }
 
+/// Writes a stream chunk that references a block in a shared memory pool.
+/// Extra flags, like [`DescriptorFlags::FLAG_SHMDATA_MEMFD_BLOCK`], may be
+/// passed in `flags`.
+pub fn write_shm_memblock<W: Write>(
+    w: &mut W,
+    channel: u32,
+    shm_ref: &ShmRef,
+    offset: u64,
+    flags: DescriptorFlags,
+) -> Result<(), ProtocolError> {
+    use byteorder::WriteBytesExt;
+
+    let desc = Descriptor {
+        length: SHM_REF_SIZE as u32,
+        channel,
+        offset,
+        flags: flags | DescriptorFlags::FLAG_SHMDATA,
+    };
+
+    write_descriptor(w, &desc)?;
+    w.write_u32::<NetworkEndian>(shm_ref.block_id)?;
+    w.write_u32::<NetworkEndian>(shm_ref.shm_id)?;
+    w.write_u32::<NetworkEndian>(shm_ref.offset)?;
+    w.write_u32::<NetworkEndian>(shm_ref.length)?;
+
+    Ok(())
+}
+
+/// Writes a SHMRELEASE message, which tells the peer that a block it sent
+/// with [`write_shm_memblock`] is no longer in use.
+pub fn write_shm_release<W: Write>(w: &mut W, block_id: u32) -> Result<(), ProtocolError> {
+    write_shm_control(w, block_id, DescriptorFlags::FLAG_SHMRELEASE)
+}
+
+/// Writes a SHMREVOKE message, which tells the peer that a block previously
+/// sent with [`write_shm_memblock`] must no longer be accessed.
+pub fn write_shm_revoke<W: Write>(w: &mut W, block_id: u32) -> Result<(), ProtocolError> {
+    write_shm_control(w, block_id, DescriptorFlags::FLAG_SHMREVOKE)
+}
+
+fn write_shm_control<W: Write>(
+    w: &mut W,
+    block_id: u32,
+    flags: DescriptorFlags,
+) -> Result<(), ProtocolError> {
+    // The block ID is sent in the upper half of the offset.
+    write_descriptor(
+        w,
+        &Descriptor {
+            length: 0,
+            channel: u32::MAX,
+            offset: (block_id as u64) << 32,
+            flags,
+        },
+    )
+}
+
+/// Returns the block ID from the descriptor of a SHMRELEASE or SHMREVOKE
+/// message.
+pub fn shm_control_block_id(desc: &Descriptor) -> u32 {
+    (desc.offset >> 32) as u32
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -407,6 +513,39 @@ mod tests {
         assert_eq!(expected, descriptor);
     }
 
+    #[test]
+    fn roundtrip_shm_memblock() {
+        let expected = ShmRef {
+            block_id: 1,
+            shm_id: 2,
+            offset: 3,
+            length: 4,
+        };
+
+        let mut buf = Vec::new();
+        write_shm_memblock(&mut buf, 5, &expected, 0, DescriptorFlags::empty()).unwrap();
+        assert_eq!(buf.len(), DESCRIPTOR_SIZE + SHM_REF_SIZE);
+
+        let mut cursor = Cursor::new(&buf);
+        let desc = read_descriptor(&mut cursor).unwrap();
+        assert_eq!(desc.channel, 5);
+        assert_eq!(desc.length as usize, SHM_REF_SIZE);
+        assert_eq!(desc.flags, DescriptorFlags::FLAG_SHMDATA);
+        assert_eq!(read_shm_ref(&mut cursor).unwrap(), expected);
+    }
+
+    #[test]
+    fn roundtrip_shm_release() {
+        let mut buf = Vec::new();
+        write_shm_release(&mut buf, 42).unwrap();
+        assert_eq!(buf.len(), DESCRIPTOR_SIZE);
+
+        let desc = read_descriptor(&mut Cursor::new(&buf)).unwrap();
+        assert_eq!(desc.flags, DescriptorFlags::FLAG_SHMRELEASE);
+        assert_eq!(desc.channel, u32::MAX);
+        assert_eq!(shm_control_block_id(&desc), 42);
+    }
+
     #[test]
     fn roundtrip_command_message() {
         let expected = Command::Auth(AuthParams {
@@ -14,6 +14,7 @@ mod load_module;
 mod lookup;
 mod module_info;
 mod move_stream;
+mod object_message;
 mod playback_stream;
 mod record_stream;
 mod sample;
@@ -46,6 +47,7 @@ pub use load_module::*;
 pub use lookup::*;
 pub use module_info::*;
 pub use move_stream::*;
+pub use object_message::*;
 pub use playback_stream::*;
 pub use record_stream::*;
 pub use sample::*;
@@ -229,8 +231,8 @@ pub enum Command {
     SetRecordStreamBufferAttr(SetRecordStreamBufferAttrParams),
     UpdatePlaybackStreamProplist(UpdatePropsParams),
     UpdateRecordStreamProplist(UpdatePropsParams),
-    RemovePlaybackStreamProplist(u32),
-    RemoveRecordStreamProplist(u32),
+    RemovePlaybackStreamProplist(RemovePropsParams),
+    RemoveRecordStreamProplist(RemovePropsParams),
     UpdatePlaybackStreamSampleRate(UpdateSampleRateParams),
     UpdateRecordStreamSampleRate(UpdateSampleRateParams),
 
@@ -271,7 +273,7 @@ pub enum Command {
     SuspendSink(SuspendParams),
     SuspendSource(SuspendParams),
     UpdateClientProplist(UpdateClientProplistParams),
-    RemoveClientProplist,
+    RemoveClientProplist(RemoveClientProplistParams),
     SetPortLatencyOffset(SetPortLatencyOffsetParams),
 
     // Manage samples.
@@ -283,6 +285,9 @@ pub enum Command {
     UnloadModule(u32),
     Extension(ExtensionParams),
 
+    // Object messages (protocol version 35 and later).
+    SendObjectMessage(SendObjectMessageParams),
+
     // Set volume and mute.
     SetSinkVolume(SetDeviceVolumeParams),
     SetSinkInputVolume(SetStreamVolumeParams),
@@ -293,6 +298,19 @@ pub enum Command {
     SetSourceMute(SetDeviceMuteParams),
     SetSourceOutputMute(SetStreamMuteParams),
 
+    // Shared memory transport.
+    /// Registers a memfd-backed memory pool with the peer, by its shm ID. The
+    /// memfd itself is passed alongside the message over the unix socket, as
+    /// ancillary data (`SCM_RIGHTS`).
+    RegisterMemfdShmid(u32),
+    /// Offers a shared ringbuffer channel to the client. The two eventfds used
+    /// for signalling are passed alongside the message, and the ringbuffer
+    /// itself follows as a writable shm memblock. The client acknowledges by
+    /// sending the same command back, with the tag of the original message.
+    EnableSrbchannel,
+    /// Disables the shared ringbuffer channel.
+    DisableSrbchannel,
+
     // Events from the server to the client.
     Started(u32),
     Request(Request),
@@ -426,12 +444,12 @@ impl Command {
             }
             CommandTag::UpdateClientProplist => Ok(Command::UpdateClientProplist(ts.read()?)),
             CommandTag::RemoveRecordStreamProplist => {
-                Ok(Command::RemoveRecordStreamProplist(ts.read_u32()?))
+                Ok(Command::RemoveRecordStreamProplist(ts.read()?))
             }
             CommandTag::RemovePlaybackStreamProplist => {
-                Ok(Command::RemovePlaybackStreamProplist(ts.read_u32()?))
+                Ok(Command::RemovePlaybackStreamProplist(ts.read()?))
             }
-            CommandTag::RemoveClientProplist => Ok(Command::RemoveClientProplist),
+            CommandTag::RemoveClientProplist => Ok(Command::RemoveClientProplist(ts.read()?)),
             CommandTag::Extension => Ok(Command::Extension(ts.read()?)),
             CommandTag::GetCardInfo => Ok(Command::GetCardInfo(ts.read()?)),
             CommandTag::GetCardInfoList => Ok(Command::GetCardInfoList),
@@ -447,10 +465,10 @@ impl Command {
             CommandTag::SetSourceOutputVolume => Ok(Command::SetSourceOutputVolume(ts.read()?)),
             CommandTag::SetSourceOutputMute => Ok(Command::SetSourceOutputMute(ts.read()?)),
             CommandTag::SetPortLatencyOffset => Ok(Command::SetPortLatencyOffset(ts.read()?)),
-            CommandTag::EnableSrbchannel => Err(ProtocolError::Unimplemented(seq, command)),
-            CommandTag::DisableSrbchannel => Err(ProtocolError::Unimplemented(seq, command)),
-            CommandTag::RegisterMemfdShmid => Err(ProtocolError::Unimplemented(seq, command)),
-            CommandTag::SendObjectMessage => Err(ProtocolError::Unimplemented(seq, command)),
+            CommandTag::EnableSrbchannel => Ok(Command::EnableSrbchannel),
+            CommandTag::DisableSrbchannel => Ok(Command::DisableSrbchannel),
+            CommandTag::RegisterMemfdShmid => Ok(Command::RegisterMemfdShmid(ts.read_u32()?)),
+            CommandTag::SendObjectMessage => Ok(Command::SendObjectMessage(ts.read()?)),
         }?;
 
         Ok((seq, cmd))
@@ -563,7 +581,7 @@ impl Command {
             Command::UpdateClientProplist(_) => CommandTag::UpdateClientProplist,
             Command::RemoveRecordStreamProplist(_) => CommandTag::RemoveRecordStreamProplist,
             Command::RemovePlaybackStreamProplist(_) => CommandTag::RemovePlaybackStreamProplist,
-            Command::RemoveClientProplist => CommandTag::RemoveClientProplist,
+            Command::RemoveClientProplist(_) => CommandTag::RemoveClientProplist,
             Command::Started(_) => CommandTag::Started,
             Command::Extension(_) => CommandTag::Extension,
             Command::GetCardInfo(_) => CommandTag::GetCardInfo,
@@ -579,10 +597,10 @@ impl Command {
             Command::SetSourceOutputVolume(_) => CommandTag::SetSourceOutputVolume,
             Command::SetSourceOutputMute(_) => CommandTag::SetSourceOutputMute,
             Command::SetPortLatencyOffset(_) => CommandTag::SetPortLatencyOffset,
-            // Command::EnableSrbchannel(_) => CommandTag::EnableSrbchannel,
-            // Command::DisableSrbchannel(_) => CommandTag::DisableSrbchannel,
-            // Command::RegisterMemfdShmid(_) => CommandTag::RegisterMemfdShmid,
-            // Command::SendObjectMessage(_) => CommandTag::SendObjectMessage,
+            Command::RegisterMemfdShmid(_) => CommandTag::RegisterMemfdShmid,
+            Command::EnableSrbchannel => CommandTag::EnableSrbchannel,
+            Command::DisableSrbchannel => CommandTag::DisableSrbchannel,
+            Command::SendObjectMessage(_) => CommandTag::SendObjectMessage,
         }
     }
 }
@@ -622,8 +640,8 @@ impl TagStructWrite for Command {
             Command::SetRecordStreamBufferAttr(p) => w.write(p),
             Command::UpdatePlaybackStreamProplist(p) => w.write(p),
             Command::UpdateRecordStreamProplist(p) => w.write(p),
-            Command::RemovePlaybackStreamProplist(id) => w.write_u32(*id),
-            Command::RemoveRecordStreamProplist(id) => w.write_u32(*id),
+            Command::RemovePlaybackStreamProplist(p) => w.write(p),
+            Command::RemoveRecordStreamProplist(p) => w.write(p),
             Command::UpdatePlaybackStreamSampleRate(p) => w.write(p),
             Command::UpdateRecordStreamSampleRate(p) => w.write(p),
             Command::Stat => Ok(()),
@@ -660,13 +678,14 @@ impl TagStructWrite for Command {
             Command::SuspendSink(p) => w.write(p),
             Command::SuspendSource(p) => w.write(p),
             Command::UpdateClientProplist(p) => w.write(p),
-            Command::RemoveClientProplist => Ok(()),
+            Command::RemoveClientProplist(p) => w.write(p),
             Command::SetPortLatencyOffset(p) => w.write(p),
             Command::PlaySample(p) => w.write(p),
             Command::RemoveSample(p) => w.write_string(Some(p)),
             Command::LoadModule(p) => w.write(p),
             Command::UnloadModule(id) => w.write_u32(*id),
             Command::Extension(p) => w.write(p),
+            Command::SendObjectMessage(p) => w.write(p),
             Command::SetSinkVolume(p) => w.write(p),
             Command::SetSinkInputVolume(p) => w.write(p),
             Command::SetSourceVolume(p) => w.write(p),
@@ -675,6 +694,9 @@ impl TagStructWrite for Command {
             Command::SetSinkInputMute(p) => w.write(p),
             Command::SetSourceMute(p) => w.write(p),
             Command::SetSourceOutputMute(p) => w.write(p),
+            Command::RegisterMemfdShmid(id) => w.write_u32(*id),
+            Command::EnableSrbchannel => Ok(()),
+            Command::DisableSrbchannel => Ok(()),
             Command::Started(id) => w.write_u32(*id),
             Command::Request(p) => w.write(p),
             Command::Overflow(id) => w.write_u32(*id),
@@ -1,4 +1,4 @@
-use std::ffi::CString;
+use std::ffi::{CStr, CString};
 
 use super::*;
 
@@ -86,6 +86,63 @@ pub struct CardInfo {
     pub active_profile: Option<CString>,
 }
 
+impl CardProfileInfo {
+    /// Whether the profile is available, i.e. whether the hardware it needs is
+    /// present.
+    pub fn is_available(&self) -> bool {
+        self.available != 0
+    }
+}
+
+impl CardInfo {
+    /// Finds a profile by name.
+    pub fn profile(&self, name: &CStr) -> Option<&CardProfileInfo> {
+        self.profiles.iter().find(|p| p.name.as_c_str() == name)
+    }
+
+    /// Finds a port by name.
+    pub fn port(&self, name: &CStr) -> Option<&CardPortInfo> {
+        self.ports.iter().find(|p| p.name.as_c_str() == name)
+    }
+
+    /// Returns the available profiles, ordered from highest to lowest
+    /// priority.
+    pub fn available_profiles(&self) -> Vec<&CardProfileInfo> {
+        let mut profiles: Vec<_> = self.profiles.iter().filter(|p| p.is_available()).collect();
+        profiles.sort_by_key(|p| std::cmp::Reverse(p.priority));
+        profiles
+    }
+
+    /// Returns the available profile with the highest priority.
+    pub fn best_profile(&self) -> Option<&CardProfileInfo> {
+        self.available_profiles().into_iter().next()
+    }
+
+    /// Returns the ports in the given direction that aren't known to be
+    /// unavailable. Ports that are known to be available come first, and
+    /// then ports are ordered from highest to lowest priority.
+    pub fn available_ports(&self, dir: port_info::PortDirection) -> Vec<&CardPortInfo> {
+        let mut ports: Vec<_> = self
+            .ports
+            .iter()
+            .filter(|p| p.dir == dir && p.available != port_info::PortAvailable::No)
+            .collect();
+        ports.sort_by_key(|p| {
+            (
+                p.available != port_info::PortAvailable::Yes,
+                std::cmp::Reverse(p.priority),
+            )
+        });
+        ports
+    }
+
+    /// Returns the best port in the given direction, according to
+    /// [available_ports](Self::available_ports).
+    pub fn best_port(&self, dir: port_info::PortDirection) -> Option<&CardPortInfo> {
+        self.available_ports(dir).into_iter().next()
+    }
+}
+
 /// The parameters for [`Command::GetCardInfo`]. Either the card index or the
 /// card name should be specified.
 #[derive(Debug, Default, Clone, PartialEq)]
@@ -345,6 +402,76 @@ mod tests {
 
         test_serde_version(&info, protocol::MAX_VERSION)
     }
+
+    #[test]
+    fn card_info_helpers() -> anyhow::Result<()> {
+        let port = |name: &CStr, priority, available| CardPortInfo {
+            name: name.to_owned(),
+            description: None,
+            priority,
+            available,
+            dir: port_info::PortDirection::Output,
+            props: Props::new(),
+            profiles: Vec::new(),
+            port_type: port_info::PortType::Unknown,
+            availability_group: None,
+            latency_offset: 0,
+        };
+
+        let profile = |name: &CStr, priority, available| CardProfileInfo {
+            name: name.to_owned(),
+            description: None,
+            priority,
+            available,
+            num_sinks: 1,
+            num_sources: 0,
+        };
+
+        let info = CardInfo {
+            index: 0,
+            name: c"card".to_owned(),
+            props: Props::new(),
+            owner_module_index: None,
+            driver: None,
+            ports: vec![
+                port(c"speaker", 100, port_info::PortAvailable::Unknown),
+                port(c"headphones", 200, port_info::PortAvailable::No),
+                port(c"hdmi", 50, port_info::PortAvailable::Yes),
+                port(c"line", 10, port_info::PortAvailable::Yes),
+            ],
+            profiles: vec![
+                profile(c"off", 0, 1),
+                profile(c"stereo", 100, 1),
+                profile(c"surround", 200, 0),
+            ],
+            active_profile: Some(c"off".to_owned()),
+        };
+
+        assert_eq!(
+            info.best_profile().map(|p| p.name.as_c_str()),
+            Some(c"stereo")
+        );
+        assert_eq!(
+            info.available_profiles()
+                .iter()
+                .map(|p| p.name.as_c_str())
+                .collect::<Vec<_>>(),
+            vec![c"stereo", c"off"]
+        );
+        assert_eq!(info.profile(c"surround").map(|p| p.priority), Some(200));
+
+        assert_eq!(
+            info.available_ports(port_info::PortDirection::Output)
+                .iter()
+                .map(|p| p.name.as_c_str())
+                .collect::<Vec<_>>(),
+            vec![c"hdmi", c"line", c"speaker"]
+        );
+        assert!(info.best_port(port_info::PortDirection::Input).is_none());
+        assert!(info.port(c"headphones").is_some());
+
+        Ok(())
+    }
 }
 
 #[cfg(test)]
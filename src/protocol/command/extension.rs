@@ -1,22 +1,82 @@
-use std::ffi::CString;
+use std::ffi::{CStr, CString};
 
 use super::*;
 
+mod device_manager;
+mod device_restore;
+mod stream_restore;
+
+pub use device_manager::*;
+pub use device_restore::*;
+pub use stream_restore::*;
+
+const STREAM_RESTORE: &CStr = c"module-stream-restore";
+const DEVICE_RESTORE: &CStr = c"module-device-restore";
+const DEVICE_MANAGER: &CStr = c"module-device-manager";
+
 /// Parameters for [`super::Command::Extension`].
-#[derive(Debug, Clone, Eq, PartialEq)]
+///
+/// Extension commands are addressed to a module, and carry a subcommand
+/// specific to that module. The server also uses them to send events to
+/// subscribed clients.
+#[derive(Default, Debug, Clone, Eq, PartialEq)]
 pub struct ExtensionParams {
     /// The index of the module.
     pub index: Option<u32>,
 
     /// The name of the module.
     pub name: Option<CString>,
+
+    /// The subcommand. Only subcommands for modules addressed by name can be
+    /// typed. Defaults to an empty [`ExtensionCommand::Other`].
+    pub command: ExtensionCommand,
+}
+
+impl ExtensionParams {
+    /// Creates parameters for a module-stream-restore subcommand.
+    pub fn stream_restore(command: StreamRestoreCommand) -> Self {
+        Self {
+            index: None,
+            name: Some(STREAM_RESTORE.to_owned()),
+            command: ExtensionCommand::StreamRestore(command),
+        }
+    }
+
+    /// Creates parameters for a module-device-restore subcommand.
+    pub fn device_restore(command: DeviceRestoreCommand) -> Self {
+        Self {
+            index: None,
+            name: Some(DEVICE_RESTORE.to_owned()),
+            command: ExtensionCommand::DeviceRestore(command),
+        }
+    }
+
+    /// Creates parameters for a module-device-manager subcommand.
+    pub fn device_manager(command: DeviceManagerCommand) -> Self {
+        Self {
+            index: None,
+            name: Some(DEVICE_MANAGER.to_owned()),
+            command: ExtensionCommand::DeviceManager(command),
+        }
+    }
 }
 
 impl TagStructRead for ExtensionParams {
     fn read(ts: &mut TagStructReader<'_>, _protocol_version: u16) -> Result<Self, ProtocolError> {
+        let index = ts.read_index()?;
+        let name = ts.read_string()?;
+
+        let command = match name.as_deref() {
+            Some(n) if n == STREAM_RESTORE => ExtensionCommand::StreamRestore(ts.read()?),
+            Some(n) if n == DEVICE_RESTORE => ExtensionCommand::DeviceRestore(ts.read()?),
+            Some(n) if n == DEVICE_MANAGER => ExtensionCommand::DeviceManager(ts.read()?),
+            _ => ExtensionCommand::Other(ts.read_raw_remaining()?),
+        };
+
         Ok(Self {
-            index: ts.read_index()?,
-            name: ts.read_string()?,
+            index,
+            name,
+            command,
         })
     }
 }
@@ -29,6 +89,56 @@ impl TagStructWrite for ExtensionParams {
     ) -> Result<(), ProtocolError> {
         ts.write_index(self.index)?;
         ts.write_string(self.name.as_ref())?;
+        match &self.command {
+            ExtensionCommand::StreamRestore(cmd) => ts.write(cmd)?,
+            ExtensionCommand::DeviceRestore(cmd) => ts.write(cmd)?,
+            ExtensionCommand::DeviceManager(cmd) => ts.write(cmd)?,
+            ExtensionCommand::Other(data) => ts.write_raw(data)?,
+        }
+        Ok(())
+    }
+}
+
+/// The subcommand of an [`ExtensionParams`].
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum ExtensionCommand {
+    /// A subcommand for module-stream-restore.
+    StreamRestore(StreamRestoreCommand),
+    /// A subcommand for module-device-restore.
+    DeviceRestore(DeviceRestoreCommand),
+    /// A subcommand for module-device-manager.
+    DeviceManager(DeviceManagerCommand),
+    /// A subcommand for any other module, as raw tagstruct data (starting
+    /// with the subcommand itself).
+    Other(Vec<u8>),
+}
+
+impl Default for ExtensionCommand {
+    fn default() -> Self {
+        Self::Other(Vec::new())
+    }
+}
+
+/// The server response to the `Test` subcommand of any extension, containing
+/// the version of the extension.
+#[derive(Default, Debug, Copy, Clone, Eq, PartialEq)]
+pub struct ExtensionVersionReply(pub u32);
+
+impl CommandReply for ExtensionVersionReply {}
+
+impl TagStructRead for ExtensionVersionReply {
+    fn read(ts: &mut TagStructReader<'_>, _protocol_version: u16) -> Result<Self, ProtocolError> {
+        Ok(Self(ts.read_u32()?))
+    }
+}
+
+impl TagStructWrite for ExtensionVersionReply {
+    fn write(
+        &self,
+        w: &mut TagStructWriter<'_>,
+        _protocol_version: u16,
+    ) -> Result<(), ProtocolError> {
+        w.write_u32(self.0)?;
         Ok(())
     }
 }
@@ -43,8 +153,32 @@ mod tests {
         let params = ExtensionParams {
             index: None,
             name: Some(CString::new("name").unwrap()),
+            command: ExtensionCommand::Other(vec![b'L', 0, 0, 0, 1]),
         };
 
         test_serde(&params)
     }
+
+    #[test]
+    fn test_typed_extension_params_serde() -> anyhow::Result<()> {
+        test_serde(&ExtensionParams::stream_restore(
+            StreamRestoreCommand::Subscribe(true),
+        ))?;
+        test_serde(&ExtensionParams::device_restore(
+            DeviceRestoreCommand::ReadFormatsAll,
+        ))?;
+        test_serde(&ExtensionParams::device_manager(DeviceManagerCommand::Test))?;
+
+        // Events are addressed by index and name.
+        let event = ExtensionParams {
+            index: Some(7),
+            ..ExtensionParams::stream_restore(StreamRestoreCommand::Event)
+        };
+        test_serde(&event)
+    }
+
+    #[test]
+    fn test_extension_version_reply_serde() -> anyhow::Result<()> {
+        test_serde(&ExtensionVersionReply(1))
+    }
 }
@@ -0,0 +1,280 @@
+use super::*;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Primitive)]
+enum Subcommand {
+    Test = 0,
+    Read = 1,
+    Rename = 2,
+    Delete = 3,
+    RoleDevicePriorityRouting = 4,
+    Reorder = 5,
+    Subscribe = 6,
+    Event = 7,
+}
+
+/// A subcommand for module-device-manager, which keeps track of devices and
+/// their priority for each stream role.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum DeviceManagerCommand {
+    /// Queries the version of the extension. The reply is an
+    /// [`ExtensionVersionReply`].
+    Test,
+    /// Reads all known devices. The reply is a [`DeviceManagerEntryList`].
+    Read,
+    /// Sets the description of a device.
+    Rename {
+        /// The name of the device entry, like `sink:foo`.
+        device: CString,
+        /// The new description.
+        description: CString,
+    },
+    /// Deletes devices by name.
+    Delete(Vec<CString>),
+    /// Enables or disables routing streams by role, using the device
+    /// priorities.
+    RoleDevicePriorityRouting(bool),
+    /// Sets the priority of devices for a role, highest first.
+    Reorder {
+        /// The role, like `music`.
+        role: CString,
+        /// The names of the device entries.
+        devices: Vec<CString>,
+    },
+    /// Enables or disables [`DeviceManagerCommand::Event`] for the client.
+    Subscribe(bool),
+    /// Sent by the server to subscribed clients when the devices change. The
+    /// [Client](crate::Client) doesn't deliver these events; they can
+    /// only be decoded.
+    Event,
+}
+
+impl TagStructRead for DeviceManagerCommand {
+    fn read(ts: &mut TagStructReader<'_>, _protocol_version: u16) -> Result<Self, ProtocolError> {
+        Ok(match ts.read_enum()? {
+            Subcommand::Test => Self::Test,
+            Subcommand::Read => Self::Read,
+            Subcommand::Rename => Self::Rename {
+                device: ts.read_string_non_null()?,
+                description: ts.read_string_non_null()?,
+            },
+            Subcommand::Delete => {
+                let mut names = Vec::new();
+                while ts.has_data_left()? {
+                    names.push(ts.read_string_non_null()?);
+                }
+
+                Self::Delete(names)
+            }
+            Subcommand::RoleDevicePriorityRouting => {
+                Self::RoleDevicePriorityRouting(ts.read_bool()?)
+            }
+            Subcommand::Reorder => {
+                let role = ts.read_string_non_null()?;
+                let n_devices = ts.read_u32()?;
+
+                let mut devices = Vec::new();
+                for _ in 0..n_devices {
+                    devices.push(ts.read_string_non_null()?);
+                }
+
+                Self::Reorder { role, devices }
+            }
+            Subcommand::Subscribe => Self::Subscribe(ts.read_bool()?),
+            Subcommand::Event => Self::Event,
+        })
+    }
+}
+
+impl TagStructWrite for DeviceManagerCommand {
+    fn write(
+        &self,
+        w: &mut TagStructWriter<'_>,
+        _protocol_version: u16,
+    ) -> Result<(), ProtocolError> {
+        match self {
+            Self::Test => w.write_u32(Subcommand::Test as u32)?,
+            Self::Read => w.write_u32(Subcommand::Read as u32)?,
+            Self::Rename {
+                device,
+                description,
+            } => {
+                w.write_u32(Subcommand::Rename as u32)?;
+                w.write_string(Some(device))?;
+                w.write_string(Some(description))?;
+            }
+            Self::Delete(names) => {
+                w.write_u32(Subcommand::Delete as u32)?;
+                for name in names {
+                    w.write_string(Some(name))?;
+                }
+            }
+            Self::RoleDevicePriorityRouting(enable) => {
+                w.write_u32(Subcommand::RoleDevicePriorityRouting as u32)?;
+                w.write_bool(*enable)?;
+            }
+            Self::Reorder { role, devices } => {
+                w.write_u32(Subcommand::Reorder as u32)?;
+                w.write_string(Some(role))?;
+                w.write_u32(devices.len() as u32)?;
+                for device in devices {
+                    w.write_string(Some(device))?;
+                }
+            }
+            Self::Subscribe(enable) => {
+                w.write_u32(Subcommand::Subscribe as u32)?;
+                w.write_bool(*enable)?;
+            }
+            Self::Event => w.write_u32(Subcommand::Event as u32)?,
+        }
+
+        Ok(())
+    }
+}
+
+/// A device known to module-device-manager.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct DeviceManagerEntry {
+    /// The name of the entry, like `sink:foo`.
+    pub name: CString,
+
+    /// The description of the device.
+    pub description: Option<CString>,
+
+    /// The name of the device's icon.
+    pub icon: Option<CString>,
+
+    /// The index of the device, if it's currently available.
+    pub index: Option<u32>,
+
+    /// The priority of the device for each role.
+    pub role_priorities: Vec<RolePriority>,
+}
+
+/// The priority of a device for a role, in a [`DeviceManagerEntry`].
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct RolePriority {
+    /// The role, like `music`.
+    pub role: CString,
+
+    /// The priority. Lower values have higher priority.
+    pub priority: u32,
+}
+
+impl TagStructRead for DeviceManagerEntry {
+    fn read(ts: &mut TagStructReader<'_>, _protocol_version: u16) -> Result<Self, ProtocolError> {
+        let name = ts.read_string_non_null()?;
+        let description = ts.read_string()?;
+        let icon = ts.read_string()?;
+        let index = ts.read_index()?;
+
+        let n_role_priorities = ts.read_u32()?;
+        let mut role_priorities = Vec::new();
+        for _ in 0..n_role_priorities {
+            role_priorities.push(RolePriority {
+                role: ts.read_string_non_null()?,
+                priority: ts.read_u32()?,
+            });
+        }
+
+        Ok(Self {
+            name,
+            description,
+            icon,
+            index,
+            role_priorities,
+        })
+    }
+}
+
+impl TagStructWrite for DeviceManagerEntry {
+    fn write(
+        &self,
+        w: &mut TagStructWriter<'_>,
+        _protocol_version: u16,
+    ) -> Result<(), ProtocolError> {
+        w.write_string(Some(&self.name))?;
+        w.write_string(self.description.as_ref())?;
+        w.write_string(self.icon.as_ref())?;
+        w.write_index(self.index)?;
+        w.write_u32(self.role_priorities.len() as u32)?;
+        for p in &self.role_priorities {
+            w.write_string(Some(&p.role))?;
+            w.write_u32(p.priority)?;
+        }
+        Ok(())
+    }
+}
+
+/// The server reply to [`DeviceManagerCommand::Read`].
+pub type DeviceManagerEntryList = Vec<DeviceManagerEntry>;
+
+impl CommandReply for DeviceManagerEntryList {}
+
+impl TagStructRead for DeviceManagerEntryList {
+    fn read(ts: &mut TagStructReader<'_>, _protocol_version: u16) -> Result<Self, ProtocolError> {
+        let mut entries = Vec::new();
+        while ts.has_data_left()? {
+            entries.push(ts.read()?);
+        }
+
+        Ok(entries)
+    }
+}
+
+impl TagStructWrite for DeviceManagerEntryList {
+    fn write(
+        &self,
+        w: &mut TagStructWriter<'_>,
+        _protocol_version: u16,
+    ) -> Result<(), ProtocolError> {
+        for entry in self {
+            w.write(entry)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::test_util::test_serde;
+
+    #[test]
+    fn test_device_manager_command_serde() -> anyhow::Result<()> {
+        test_serde(&DeviceManagerCommand::Test)?;
+        test_serde(&DeviceManagerCommand::Read)?;
+        test_serde(&DeviceManagerCommand::Rename {
+            device: CString::new("sink:foo").unwrap(),
+            description: CString::new("Foo").unwrap(),
+        })?;
+        test_serde(&DeviceManagerCommand::Delete(vec![
+            CString::new("sink:foo").unwrap(),
+        ]))?;
+        test_serde(&DeviceManagerCommand::RoleDevicePriorityRouting(true))?;
+        test_serde(&DeviceManagerCommand::Reorder {
+            role: CString::new("music").unwrap(),
+            devices: vec![
+                CString::new("sink:foo").unwrap(),
+                CString::new("sink:bar").unwrap(),
+            ],
+        })?;
+        test_serde(&DeviceManagerCommand::Subscribe(false))?;
+        test_serde(&DeviceManagerCommand::Event)
+    }
+
+    #[test]
+    fn test_device_manager_entry_list_serde() -> anyhow::Result<()> {
+        let list = vec![DeviceManagerEntry {
+            name: CString::new("sink:foo").unwrap(),
+            description: Some(CString::new("Foo").unwrap()),
+            icon: None,
+            index: Some(2),
+            role_priorities: vec![RolePriority {
+                role: CString::new("music").unwrap(),
+                priority: 1,
+            }],
+        }];
+
+        test_serde(&list)
+    }
+}
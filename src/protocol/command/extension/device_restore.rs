@@ -0,0 +1,235 @@
+use super::*;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Primitive)]
+enum Subcommand {
+    Test = 0,
+    Subscribe = 1,
+    Event = 2,
+    ReadFormatsAll = 3,
+    ReadFormats = 4,
+    SaveFormats = 5,
+}
+
+/// The type of device referenced by a [`DeviceRestoreCommand`].
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Primitive)]
+pub enum DeviceType {
+    /// A sink.
+    Sink = 0,
+    /// A source.
+    Source = 1,
+}
+
+/// A subcommand for module-device-restore, which stores the volume, mute
+/// state and supported formats of devices.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum DeviceRestoreCommand {
+    /// Queries the version of the extension. The reply is an
+    /// [`ExtensionVersionReply`].
+    Test,
+    /// Enables or disables [`DeviceRestoreCommand::Event`] for the client.
+    Subscribe(bool),
+    /// Sent by the server to subscribed clients when the stored state of a
+    /// device changes. The [Client](crate::Client) doesn't deliver
+    /// these events; they can only be decoded.
+    Event {
+        /// The type of the device.
+        device_type: DeviceType,
+        /// The index of the device.
+        index: u32,
+    },
+    /// Reads the formats of all sinks. The reply is a [`DeviceFormatsList`].
+    ReadFormatsAll,
+    /// Reads the formats of a single device. The reply is a
+    /// [`DeviceFormatsList`] with one entry.
+    ReadFormats {
+        /// The type of the device.
+        device_type: DeviceType,
+        /// The index of the device.
+        index: u32,
+    },
+    /// Saves the formats of a device.
+    SaveFormats(DeviceFormats),
+}
+
+impl TagStructRead for DeviceRestoreCommand {
+    fn read(ts: &mut TagStructReader<'_>, _protocol_version: u16) -> Result<Self, ProtocolError> {
+        Ok(match ts.read_enum()? {
+            Subcommand::Test => Self::Test,
+            Subcommand::Subscribe => Self::Subscribe(ts.read_bool()?),
+            Subcommand::Event => Self::Event {
+                device_type: ts.read_enum()?,
+                index: ts.read_u32()?,
+            },
+            Subcommand::ReadFormatsAll => Self::ReadFormatsAll,
+            Subcommand::ReadFormats => Self::ReadFormats {
+                device_type: ts.read_enum()?,
+                index: ts.read_u32()?,
+            },
+            Subcommand::SaveFormats => Self::SaveFormats(ts.read()?),
+        })
+    }
+}
+
+impl TagStructWrite for DeviceRestoreCommand {
+    fn write(
+        &self,
+        w: &mut TagStructWriter<'_>,
+        _protocol_version: u16,
+    ) -> Result<(), ProtocolError> {
+        match self {
+            Self::Test => w.write_u32(Subcommand::Test as u32)?,
+            Self::Subscribe(enable) => {
+                w.write_u32(Subcommand::Subscribe as u32)?;
+                w.write_bool(*enable)?;
+            }
+            Self::Event { device_type, index } => {
+                w.write_u32(Subcommand::Event as u32)?;
+                w.write_u32(*device_type as u32)?;
+                w.write_u32(*index)?;
+            }
+            Self::ReadFormatsAll => w.write_u32(Subcommand::ReadFormatsAll as u32)?,
+            Self::ReadFormats { device_type, index } => {
+                w.write_u32(Subcommand::ReadFormats as u32)?;
+                w.write_u32(*device_type as u32)?;
+                w.write_u32(*index)?;
+            }
+            Self::SaveFormats(formats) => {
+                w.write_u32(Subcommand::SaveFormats as u32)?;
+                w.write(formats)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// The formats stored for a device.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct DeviceFormats {
+    /// The type of the device.
+    pub device_type: DeviceType,
+
+    /// The index of the device.
+    pub index: u32,
+
+    /// The formats supported by the device.
+    pub formats: Vec<FormatInfo>,
+}
+
+impl TagStructRead for DeviceFormats {
+    fn read(ts: &mut TagStructReader<'_>, _protocol_version: u16) -> Result<Self, ProtocolError> {
+        let device_type = ts.read_enum()?;
+        let index = ts.read_u32()?;
+
+        let n_formats = ts.read_u8()?;
+        let mut formats = Vec::with_capacity(n_formats as usize);
+        for _ in 0..n_formats {
+            formats.push(ts.read()?);
+        }
+
+        Ok(Self {
+            device_type,
+            index,
+            formats,
+        })
+    }
+}
+
+impl TagStructWrite for DeviceFormats {
+    fn write(
+        &self,
+        w: &mut TagStructWriter<'_>,
+        _protocol_version: u16,
+    ) -> Result<(), ProtocolError> {
+        let n_formats: u8 = self
+            .formats
+            .len()
+            .try_into()
+            .map_err(|_| ProtocolError::Invalid("too many formats".into()))?;
+
+        w.write_u32(self.device_type as u32)?;
+        w.write_u32(self.index)?;
+        w.write_u8(n_formats)?;
+        for format in &self.formats {
+            w.write(format)?;
+        }
+        Ok(())
+    }
+}
+
+/// The server reply to [`DeviceRestoreCommand::ReadFormatsAll`] and
+/// [`DeviceRestoreCommand::ReadFormats`].
+pub type DeviceFormatsList = Vec<DeviceFormats>;
+
+impl CommandReply for DeviceFormatsList {}
+
+impl TagStructRead for DeviceFormatsList {
+    fn read(ts: &mut TagStructReader<'_>, _protocol_version: u16) -> Result<Self, ProtocolError> {
+        let mut devices = Vec::new();
+        while ts.has_data_left()? {
+            devices.push(ts.read()?);
+        }
+
+        Ok(devices)
+    }
+}
+
+impl TagStructWrite for DeviceFormatsList {
+    fn write(
+        &self,
+        w: &mut TagStructWriter<'_>,
+        _protocol_version: u16,
+    ) -> Result<(), ProtocolError> {
+        for device in self {
+            w.write(device)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::test_util::test_serde;
+
+    #[test]
+    fn test_device_restore_command_serde() -> anyhow::Result<()> {
+        test_serde(&DeviceRestoreCommand::Test)?;
+        test_serde(&DeviceRestoreCommand::Subscribe(true))?;
+        test_serde(&DeviceRestoreCommand::Event {
+            device_type: DeviceType::Source,
+            index: 3,
+        })?;
+        test_serde(&DeviceRestoreCommand::ReadFormatsAll)?;
+        test_serde(&DeviceRestoreCommand::ReadFormats {
+            device_type: DeviceType::Sink,
+            index: 1,
+        })?;
+        test_serde(&DeviceRestoreCommand::SaveFormats(DeviceFormats {
+            device_type: DeviceType::Sink,
+            index: 1,
+            formats: vec![FormatInfo::new(FormatEncoding::Pcm)],
+        }))
+    }
+
+    #[test]
+    fn test_device_formats_list_serde() -> anyhow::Result<()> {
+        let list = vec![
+            DeviceFormats {
+                device_type: DeviceType::Sink,
+                index: 0,
+                formats: vec![
+                    FormatInfo::new(FormatEncoding::Pcm),
+                    FormatInfo::new(FormatEncoding::Ac3Iec61937),
+                ],
+            },
+            DeviceFormats {
+                device_type: DeviceType::Sink,
+                index: 1,
+                formats: vec![],
+            },
+        ];
+
+        test_serde(&list)
+    }
+}
@@ -0,0 +1,284 @@
+use super::*;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Primitive)]
+enum Subcommand {
+    Test = 0,
+    Read = 1,
+    Write = 2,
+    Delete = 3,
+    Subscribe = 4,
+    Event = 5,
+}
+
+/// A subcommand for module-stream-restore, which stores the volume, mute
+/// state and device of streams, keyed by their role or application.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum StreamRestoreCommand {
+    /// Queries the version of the extension. The reply is an
+    /// [`ExtensionVersionReply`].
+    Test,
+    /// Reads all entries. The reply is a [`StreamRestoreEntryList`].
+    Read,
+    /// Updates or replaces entries.
+    Write(StreamRestoreWriteParams),
+    /// Deletes entries by name.
+    Delete(Vec<CString>),
+    /// Enables or disables [`StreamRestoreCommand::Event`] for the client.
+    Subscribe(bool),
+    /// Sent by the server to subscribed clients when the entries change. The
+    /// [Client](crate::Client) delivers these through
+    /// [Client::subscribe_stream_restore](crate::Client::subscribe_stream_restore).
+    Event,
+}
+
+impl TagStructRead for StreamRestoreCommand {
+    fn read(ts: &mut TagStructReader<'_>, _protocol_version: u16) -> Result<Self, ProtocolError> {
+        Ok(match ts.read_enum()? {
+            Subcommand::Test => Self::Test,
+            Subcommand::Read => Self::Read,
+            Subcommand::Write => Self::Write(ts.read()?),
+            Subcommand::Delete => {
+                let mut names = Vec::new();
+                while ts.has_data_left()? {
+                    names.push(ts.read_string_non_null()?);
+                }
+
+                Self::Delete(names)
+            }
+            Subcommand::Subscribe => Self::Subscribe(ts.read_bool()?),
+            Subcommand::Event => Self::Event,
+        })
+    }
+}
+
+impl TagStructWrite for StreamRestoreCommand {
+    fn write(
+        &self,
+        w: &mut TagStructWriter<'_>,
+        _protocol_version: u16,
+    ) -> Result<(), ProtocolError> {
+        match self {
+            Self::Test => w.write_u32(Subcommand::Test as u32)?,
+            Self::Read => w.write_u32(Subcommand::Read as u32)?,
+            Self::Write(params) => {
+                w.write_u32(Subcommand::Write as u32)?;
+                w.write(params)?;
+            }
+            Self::Delete(names) => {
+                w.write_u32(Subcommand::Delete as u32)?;
+                for name in names {
+                    w.write_string(Some(name))?;
+                }
+            }
+            Self::Subscribe(enable) => {
+                w.write_u32(Subcommand::Subscribe as u32)?;
+                w.write_bool(*enable)?;
+            }
+            Self::Event => w.write_u32(Subcommand::Event as u32)?,
+        }
+
+        Ok(())
+    }
+}
+
+/// Parameters for [`StreamRestoreCommand::Write`].
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct StreamRestoreWriteParams {
+    /// How to combine the entries with the existing ones. With
+    /// [`PropsUpdateMode::Set`](props::PropsUpdateMode::Set), all existing
+    /// entries are removed first.
+    pub mode: props::PropsUpdateMode,
+
+    /// Whether to apply the entries to existing streams right away.
+    pub apply_immediately: bool,
+
+    /// The entries to write.
+    pub entries: Vec<StreamRestoreEntry>,
+}
+
+impl TagStructRead for StreamRestoreWriteParams {
+    fn read(ts: &mut TagStructReader<'_>, _protocol_version: u16) -> Result<Self, ProtocolError> {
+        Ok(Self {
+            mode: ts.read_enum()?,
+            apply_immediately: ts.read_bool()?,
+            entries: ts.read()?,
+        })
+    }
+}
+
+impl TagStructWrite for StreamRestoreWriteParams {
+    fn write(
+        &self,
+        w: &mut TagStructWriter<'_>,
+        _protocol_version: u16,
+    ) -> Result<(), ProtocolError> {
+        w.write_u32(self.mode as u32)?;
+        w.write_bool(self.apply_immediately)?;
+        w.write(&self.entries)?;
+        Ok(())
+    }
+}
+
+/// An entry in the stream-restore database.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct StreamRestoreEntry {
+    /// The name of the entry, like `sink-input-by-media-role:music`.
+    pub name: CString,
+
+    /// The channel map for `volume`. Empty if no volume is stored.
+    pub channel_map: ChannelMap,
+
+    /// The stored volume, if any.
+    pub volume: Option<ChannelVolume>,
+
+    /// The name of the stored device, if any.
+    pub device: Option<CString>,
+
+    /// Whether the stream is muted.
+    pub muted: bool,
+}
+
+impl TagStructRead for StreamRestoreEntry {
+    fn read(ts: &mut TagStructReader<'_>, _protocol_version: u16) -> Result<Self, ProtocolError> {
+        let name = ts.read_string_non_null()?;
+        let channel_map: ChannelMap = ts.read()?;
+        let volume = ChannelVolume::read_maybe_empty(ts)?;
+
+        let volume = if volume.channels().is_empty() {
+            None
+        } else if volume.channels().len() != channel_map.num_channels() as usize {
+            return Err(ProtocolError::Invalid(format!(
+                "stream-restore entry {name:?} has mismatched volume and channel map"
+            )));
+        } else {
+            Some(volume)
+        };
+
+        Ok(Self {
+            name,
+            channel_map,
+            volume,
+            device: ts.read_string()?,
+            muted: ts.read_bool()?,
+        })
+    }
+}
+
+impl TagStructWrite for StreamRestoreEntry {
+    fn write(
+        &self,
+        w: &mut TagStructWriter<'_>,
+        _protocol_version: u16,
+    ) -> Result<(), ProtocolError> {
+        w.write_string(Some(&self.name))?;
+        w.write(self.channel_map)?;
+        w.write(self.volume.unwrap_or_else(ChannelVolume::empty))?;
+        w.write_string(self.device.as_ref())?;
+        w.write_bool(self.muted)?;
+        Ok(())
+    }
+}
+
+/// The server reply to [`StreamRestoreCommand::Read`].
+pub type StreamRestoreEntryList = Vec<StreamRestoreEntry>;
+
+impl CommandReply for StreamRestoreEntryList {}
+
+impl TagStructRead for StreamRestoreEntryList {
+    fn read(ts: &mut TagStructReader<'_>, _protocol_version: u16) -> Result<Self, ProtocolError> {
+        let mut entries = Vec::new();
+        while ts.has_data_left()? {
+            entries.push(ts.read()?);
+        }
+
+        Ok(entries)
+    }
+}
+
+impl TagStructWrite for StreamRestoreEntryList {
+    fn write(
+        &self,
+        w: &mut TagStructWriter<'_>,
+        _protocol_version: u16,
+    ) -> Result<(), ProtocolError> {
+        for entry in self {
+            w.write(entry)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::test_util::test_serde;
+
+    fn entries() -> StreamRestoreEntryList {
+        let mut volume = ChannelVolume::empty();
+        volume.push(Volume::NORM);
+        volume.push(Volume::MUTED);
+
+        vec![
+            StreamRestoreEntry {
+                name: CString::new("sink-input-by-media-role:music").unwrap(),
+                channel_map: ChannelMap::stereo(),
+                volume: Some(volume),
+                device: Some(CString::new("speakers").unwrap()),
+                muted: false,
+            },
+            StreamRestoreEntry {
+                name: CString::new("sink-input-by-media-role:event").unwrap(),
+                channel_map: ChannelMap::empty(),
+                volume: None,
+                device: None,
+                muted: true,
+            },
+        ]
+    }
+
+    #[test]
+    fn test_stream_restore_command_serde() -> anyhow::Result<()> {
+        test_serde(&StreamRestoreCommand::Test)?;
+        test_serde(&StreamRestoreCommand::Read)?;
+        test_serde(&StreamRestoreCommand::Write(StreamRestoreWriteParams {
+            mode: props::PropsUpdateMode::Replace,
+            apply_immediately: true,
+            entries: entries(),
+        }))?;
+        test_serde(&StreamRestoreCommand::Delete(vec![
+            CString::new("foo").unwrap(),
+            CString::new("bar").unwrap(),
+        ]))?;
+        test_serde(&StreamRestoreCommand::Subscribe(false))?;
+        test_serde(&StreamRestoreCommand::Event)
+    }
+
+    #[test]
+    fn test_stream_restore_entry_list_serde() -> anyhow::Result<()> {
+        test_serde(&entries())
+    }
+}
+
+#[cfg(test)]
+#[cfg(feature = "_integration-tests")]
+mod integration_tests {
+    use crate::{integration_test_util::connect_and_init, protocol::*};
+
+    #[test]
+    fn test_stream_restore_test() -> anyhow::Result<()> {
+        let (mut sock, protocol_version) = connect_and_init()?;
+
+        write_command_message(
+            sock.get_mut(),
+            0,
+            &Command::Extension(ExtensionParams::stream_restore(StreamRestoreCommand::Test)),
+            protocol_version,
+        )?;
+
+        let (_, reply): (_, ExtensionVersionReply) =
+            read_reply_message(&mut sock, protocol_version)?;
+        assert_eq!(reply.0, 1);
+
+        Ok(())
+    }
+}
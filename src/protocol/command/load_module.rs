@@ -1,3 +1,5 @@
+use std::{collections::BTreeMap, fmt, str::FromStr};
+
 use super::*;
 
 /// Parameters for [`super::Command::LoadModule`].
@@ -6,7 +8,8 @@ pub struct LoadModuleParams {
     /// The name of the module to load.
     pub name: CString,
 
-    /// The arguments to pass to the module.
+    /// The arguments to pass to the module. See [`ModuleArgs`] for a way to
+    /// build or parse the argument string.
     pub arguments: Option<CString>,
 }
 
@@ -33,6 +36,269 @@ impl TagStructWrite for LoadModuleParams {
     }
 }
 
+/// The server response to [`super::Command::LoadModule`], containing the index
+/// of the newly loaded module.
+#[derive(Default, Debug, Copy, Clone, Eq, PartialEq)]
+pub struct LoadModuleReply(pub u32);
+
+impl CommandReply for LoadModuleReply {}
+
+impl TagStructRead for LoadModuleReply {
+    fn read(ts: &mut TagStructReader<'_>, _protocol_version: u16) -> Result<Self, ProtocolError> {
+        Ok(Self(ts.read_u32()?))
+    }
+}
+
+impl TagStructWrite for LoadModuleReply {
+    fn write(
+        &self,
+        w: &mut TagStructWriter<'_>,
+        _protocol_version: u16,
+    ) -> Result<(), ProtocolError> {
+        w.write_u32(self.0)?;
+        Ok(())
+    }
+}
+
+/// A set of module arguments, as passed to [`super::Command::LoadModule`] or
+/// returned in [`ModuleInfo::argument`].
+///
+/// PulseAudio module arguments are a whitespace-separated list of `key=value`
+/// pairs. Values may be quoted with single or double quotes, and a backslash
+/// escapes the following character. For example:
+///
+/// ```
+/// # use pulseaudio::protocol::ModuleArgs;
+/// let args: ModuleArgs = r#"sink_name=test sink_properties='device.description="Test Sink"'"#
+///     .parse()
+///     .unwrap();
+///
+/// assert_eq!(args.get("sink_name"), Some("test"));
+/// assert_eq!(
+///     args.get("sink_properties"),
+///     Some(r#"device.description="Test Sink""#)
+/// );
+/// ```
+///
+/// When formatted with [`Display`](fmt::Display), values are quoted and
+/// escaped as necessary, so that the result can be parsed back by the server.
+#[derive(Default, Clone, PartialEq, Eq)]
+pub struct ModuleArgs(BTreeMap<String, String>);
+
+impl ModuleArgs {
+    /// Creates an empty set of arguments.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets an argument, overwriting any previous value.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the key is empty or contains whitespace or `=`.
+    pub fn set(&mut self, key: impl Into<String>, value: impl Into<String>) {
+        let key = key.into();
+        assert!(is_valid_key(&key), "invalid module argument key: {key:?}");
+
+        self.0.insert(key, value.into());
+    }
+
+    /// Sets an argument, and returns `self` for chaining.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the key is empty or contains whitespace or `=`.
+    pub fn with(mut self, key: impl Into<String>, value: impl Into<String>) -> Self {
+        self.set(key, value);
+        self
+    }
+
+    /// Gets the value of an argument.
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).map(String::as_str)
+    }
+
+    /// Removes an argument, returning its value.
+    pub fn remove(&mut self, key: &str) -> Option<String> {
+        self.0.remove(key)
+    }
+
+    /// Returns the number of arguments.
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    /// Returns true if there are no arguments.
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Create an Iterator over the arguments.
+    pub fn iter(&self) -> std::collections::btree_map::Iter<'_, String, String> {
+        self.0.iter()
+    }
+
+    /// Formats the arguments as a string suitable for
+    /// [`LoadModuleParams::arguments`].
+    pub fn to_c_string(&self) -> Result<CString, ProtocolError> {
+        CString::new(self.to_string())
+            .map_err(|_| ProtocolError::Invalid("module argument contains a NUL byte".into()))
+    }
+}
+
+fn is_valid_key(key: &str) -> bool {
+    !key.is_empty() && !key.contains(|c: char| c == '=' || c.is_ascii_whitespace())
+}
+
+impl fmt::Debug for ModuleArgs {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_map().entries(self.0.iter()).finish()
+    }
+}
+
+impl fmt::Display for ModuleArgs {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, (k, v)) in self.0.iter().enumerate() {
+            if i > 0 {
+                f.write_str(" ")?;
+            }
+
+            write!(f, "{k}=")?;
+
+            let needs_quotes = v.is_empty()
+                || v.contains(|c: char| {
+                    c.is_ascii_whitespace() || c == '\'' || c == '"' || c == '\\'
+                });
+
+            if !needs_quotes {
+                f.write_str(v)?;
+                continue;
+            }
+
+            f.write_str("'")?;
+            for c in v.chars() {
+                if c == '\'' || c == '\\' {
+                    f.write_str("\\")?;
+                }
+
+                write!(f, "{c}")?;
+            }
+            f.write_str("'")?;
+        }
+
+        Ok(())
+    }
+}
+
+impl FromStr for ModuleArgs {
+    type Err = ProtocolError;
+
+    /// Parses an argument string, following the rules of `pa_modargs_new`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        #[derive(Clone, Copy, PartialEq, Eq)]
+        enum State {
+            Whitespace,
+            Key,
+            ValueStart,
+            Value(Option<char>),
+            Escaped(Option<char>),
+        }
+
+        let mut args = BTreeMap::new();
+        let mut insert = |key: &mut String, value: &mut String| {
+            let key = std::mem::take(key);
+            if args.contains_key(&key) {
+                return Err(ProtocolError::Invalid(format!(
+                    "duplicate module argument: {key}"
+                )));
+            }
+
+            args.insert(key, std::mem::take(value));
+            Ok(())
+        };
+
+        let mut state = State::Whitespace;
+        let mut key = String::new();
+        let mut value = String::new();
+
+        for c in s.chars() {
+            state = match (state, c) {
+                (State::Whitespace, '=') => {
+                    return Err(ProtocolError::Invalid(
+                        "module argument with empty key".into(),
+                    ));
+                }
+                (State::Whitespace, c) if c.is_ascii_whitespace() => State::Whitespace,
+                (State::Whitespace, c) => {
+                    key.push(c);
+                    State::Key
+                }
+                (State::Key, '=') => State::ValueStart,
+                (State::Key, c) if c.is_ascii_whitespace() => {
+                    return Err(ProtocolError::Invalid(format!(
+                        "module argument without value: {key}"
+                    )));
+                }
+                (State::Key, c) => {
+                    key.push(c);
+                    State::Key
+                }
+                (State::ValueStart, c) if c.is_ascii_whitespace() => {
+                    insert(&mut key, &mut value)?;
+                    State::Whitespace
+                }
+                (State::ValueStart, q @ ('\'' | '"')) => State::Value(Some(q)),
+                (State::ValueStart, '\\') => State::Escaped(None),
+                (State::ValueStart, c) => {
+                    value.push(c);
+                    State::Value(None)
+                }
+                (State::Value(None), c) if c.is_ascii_whitespace() => {
+                    insert(&mut key, &mut value)?;
+                    State::Whitespace
+                }
+                (State::Value(Some(q)), c) if c == q => {
+                    insert(&mut key, &mut value)?;
+                    State::Whitespace
+                }
+                (State::Value(quote), '\\') => State::Escaped(quote),
+                (State::Value(quote), c) | (State::Escaped(quote), c) => {
+                    value.push(c);
+                    State::Value(quote)
+                }
+            };
+        }
+
+        match state {
+            State::Whitespace => (),
+            State::ValueStart | State::Value(None) => insert(&mut key, &mut value)?,
+            State::Key => {
+                return Err(ProtocolError::Invalid(format!(
+                    "module argument without value: {key}"
+                )));
+            }
+            State::Value(Some(_)) | State::Escaped(_) => {
+                return Err(ProtocolError::Invalid(format!(
+                    "unterminated value for module argument: {key}"
+                )));
+            }
+        }
+
+        Ok(Self(args))
+    }
+}
+
+impl<K: Into<String>, V: Into<String>> FromIterator<(K, V)> for ModuleArgs {
+    fn from_iter<T: IntoIterator<Item = (K, V)>>(iter: T) -> Self {
+        let mut args = Self::new();
+        for (k, v) in iter {
+            args.set(k, v);
+        }
+
+        args
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -47,4 +313,101 @@ mod tests {
 
         test_serde(&params)
     }
+
+    #[test]
+    fn test_load_module_reply_serde() -> anyhow::Result<()> {
+        test_serde(&LoadModuleReply(42))
+    }
+
+    #[test]
+    fn module_args_parse() -> anyhow::Result<()> {
+        let args: ModuleArgs =
+            r#"  a=1 b='two words'  c="double \"quoted\"" d= e=esc\ aped f='it\'s' g="#.parse()?;
+
+        assert_eq!(args.len(), 7);
+        assert_eq!(args.get("a"), Some("1"));
+        assert_eq!(args.get("b"), Some("two words"));
+        assert_eq!(args.get("c"), Some(r#"double "quoted""#));
+        assert_eq!(args.get("d"), Some(""));
+        assert_eq!(args.get("e"), Some("esc aped"));
+        assert_eq!(args.get("f"), Some("it's"));
+        assert_eq!(args.get("g"), Some(""));
+
+        Ok(())
+    }
+
+    #[test]
+    fn module_args_parse_invalid() {
+        for s in [
+            "=foo",
+            "foo",
+            "foo bar=baz",
+            "a=1 a=2",
+            "a='unterminated",
+            "a=\\",
+        ] {
+            assert!(s.parse::<ModuleArgs>().is_err(), "{s:?} should not parse");
+        }
+    }
+
+    #[test]
+    fn module_args_roundtrip() -> anyhow::Result<()> {
+        let args = ModuleArgs::new()
+            .with("sink_name", "test")
+            .with("sink_properties", r#"device.description="Test Sink""#)
+            .with("empty", "")
+            .with("quotes", r"it's a \ backslash");
+
+        let s = args.to_string();
+        assert_eq!(
+            s,
+            r#"empty='' quotes='it\'s a \\ backslash' sink_name=test sink_properties='device.description="Test Sink"'"#
+        );
+
+        assert_eq!(s.parse::<ModuleArgs>()?, args);
+        Ok(())
+    }
+
+    #[test]
+    #[should_panic]
+    fn module_args_invalid_key() {
+        ModuleArgs::new().set("foo bar", "baz");
+    }
+}
+
+#[cfg(test)]
+#[cfg(feature = "_integration-tests")]
+mod integration_tests {
+    use std::ffi::CString;
+
+    use crate::{integration_test_util::connect_and_init, protocol::*};
+
+    #[test]
+    fn test_load_unload_module() -> anyhow::Result<()> {
+        let (mut sock, protocol_version) = connect_and_init()?;
+
+        let args = ModuleArgs::new().with("sink_name", "pulseaudio_rs_test_load_module");
+        write_command_message(
+            sock.get_mut(),
+            0,
+            &Command::LoadModule(LoadModuleParams {
+                name: CString::new("module-null-sink")?,
+                arguments: Some(args.to_c_string()?),
+            }),
+            protocol_version,
+        )?;
+
+        let (_, LoadModuleReply(index)) =
+            read_reply_message::<LoadModuleReply>(&mut sock, protocol_version)?;
+
+        write_command_message(
+            sock.get_mut(),
+            1,
+            &Command::UnloadModule(index),
+            protocol_version,
+        )?;
+        assert_eq!(1, read_ack_message(&mut sock)?);
+
+        Ok(())
+    }
 }
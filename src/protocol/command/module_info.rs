@@ -2,7 +2,7 @@ use std::ffi::CString;
 
 use crate::protocol::{serde::*, ProtocolError};
 
-use super::CommandReply;
+use super::{CommandReply, ModuleArgs};
 
 /// Server state for a module, in response to [`super::Command::GetModuleInfo`].
 #[derive(Default, Debug, Clone, Eq, PartialEq)]
@@ -27,6 +27,21 @@ pub struct ModuleInfo {
     pub props: Props,
 }
 
+impl ModuleInfo {
+    /// Parses the module's argument string into a map. A module with no
+    /// arguments returns an empty map.
+    pub fn parse_arguments(&self) -> Result<ModuleArgs, ProtocolError> {
+        let Some(argument) = &self.argument else {
+            return Ok(ModuleArgs::new());
+        };
+
+        argument
+            .to_str()
+            .map_err(|_| ProtocolError::Invalid("module argument is not valid UTF-8".into()))?
+            .parse()
+    }
+}
+
 impl CommandReply for ModuleInfo {}
 
 impl TagStructRead for ModuleInfo {
@@ -124,6 +139,24 @@ mod tests {
 
         test_serde(&modules)
     }
+
+    #[test]
+    fn module_info_parse_arguments() -> anyhow::Result<()> {
+        let mut info = ModuleInfo {
+            index: 0,
+            name: CString::new("module-null-sink")?,
+            ..Default::default()
+        };
+
+        assert!(info.parse_arguments()?.is_empty());
+
+        info.argument = Some(CString::new("sink_name=foo rate='48000'")?);
+        let args = info.parse_arguments()?;
+        assert_eq!(args.get("sink_name"), Some("foo"));
+        assert_eq!(args.get("rate"), Some("48000"));
+
+        Ok(())
+    }
 }
 
 #[cfg(test)]
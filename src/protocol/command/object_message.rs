@@ -0,0 +1,365 @@
+use std::{fmt, str::FromStr};
+
+use super::*;
+
+/// Parameters for [`super::Command::SendObjectMessage`].
+///
+/// Messages are sent to objects on the server, identified by a path like
+/// `/core` or `/card/<name>/bluez`. Sending the `list-handlers` message to
+/// `/core` returns all the available objects.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct SendObjectMessageParams {
+    /// The path of the object.
+    pub object_path: CString,
+
+    /// The message to send.
+    pub message: CString,
+
+    /// The parameters for the message. See [`MessageParams`] for a way to
+    /// build or parse them.
+    pub parameters: Option<CString>,
+}
+
+impl TagStructRead for SendObjectMessageParams {
+    fn read(ts: &mut TagStructReader<'_>, _protocol_version: u16) -> Result<Self, ProtocolError> {
+        Ok(Self {
+            object_path: ts.read_string_non_null()?,
+            message: ts.read_string_non_null()?,
+            parameters: ts.read_string()?,
+        })
+    }
+}
+
+impl TagStructWrite for SendObjectMessageParams {
+    fn write(
+        &self,
+        ts: &mut TagStructWriter<'_>,
+        _protocol_version: u16,
+    ) -> Result<(), ProtocolError> {
+        ts.write_string(Some(&self.object_path))?;
+        ts.write_string(Some(&self.message))?;
+        ts.write_string(self.parameters.as_ref())?;
+        Ok(())
+    }
+}
+
+/// The server response to [`super::Command::SendObjectMessage`]. The
+/// response is usually in the format understood by [`MessageParams`], and is
+/// unset if the handler didn't return anything.
+#[derive(Default, Debug, Clone, Eq, PartialEq)]
+pub struct SendObjectMessageReply(pub Option<CString>);
+
+impl CommandReply for SendObjectMessageReply {}
+
+impl TagStructRead for SendObjectMessageReply {
+    fn read(ts: &mut TagStructReader<'_>, _protocol_version: u16) -> Result<Self, ProtocolError> {
+        Ok(Self(ts.read_string()?))
+    }
+}
+
+impl TagStructWrite for SendObjectMessageReply {
+    fn write(
+        &self,
+        w: &mut TagStructWriter<'_>,
+        _protocol_version: u16,
+    ) -> Result<(), ProtocolError> {
+        w.write_string(self.0.as_ref())?;
+        Ok(())
+    }
+}
+
+/// A single element of [`MessageParams`].
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum MessageParam {
+    /// A plain value. Numbers and booleans are also sent as strings.
+    Value(String),
+    /// A nested list of elements.
+    List(Vec<MessageParam>),
+}
+
+impl MessageParam {
+    /// Returns the value, if this is a plain value.
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            MessageParam::Value(v) => Some(v),
+            MessageParam::List(_) => None,
+        }
+    }
+
+    /// Returns the elements, if this is a list.
+    pub fn as_list(&self) -> Option<&[MessageParam]> {
+        match self {
+            MessageParam::Value(_) => None,
+            MessageParam::List(l) => Some(l),
+        }
+    }
+}
+
+impl From<&str> for MessageParam {
+    fn from(v: &str) -> Self {
+        MessageParam::Value(v.to_owned())
+    }
+}
+
+impl From<String> for MessageParam {
+    fn from(v: String) -> Self {
+        MessageParam::Value(v)
+    }
+}
+
+impl From<Vec<MessageParam>> for MessageParam {
+    fn from(l: Vec<MessageParam>) -> Self {
+        MessageParam::List(l)
+    }
+}
+
+impl fmt::Display for MessageParam {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("{")?;
+        match self {
+            MessageParam::Value(v) => {
+                for c in v.chars() {
+                    if matches!(c, '{' | '}' | '\\') {
+                        f.write_str("\\")?;
+                    }
+
+                    write!(f, "{c}")?;
+                }
+            }
+            MessageParam::List(l) => {
+                for param in l {
+                    write!(f, "{param}")?;
+                }
+            }
+        }
+        f.write_str("}")
+    }
+}
+
+/// The parameters of an object message, as passed in
+/// [`SendObjectMessageParams::parameters`] or returned in
+/// [`SendObjectMessageReply`].
+///
+/// PulseAudio uses a simple structured format for these, where each element
+/// is enclosed in curly braces, and elements containing other elements are
+/// nested lists. Text between elements is ignored. A backslash escapes braces
+/// and backslashes inside values. For example:
+///
+/// ```
+/// # use pulseaudio::protocol::{MessageParam, MessageParams};
+/// let params: MessageParams = r#"{{/core}{Core}}{{/card/foo}{Card \{foo\}}}"#
+///     .parse()
+///     .unwrap();
+///
+/// let handlers = params
+///     .iter()
+///     .filter_map(|h| h.as_list()?.first()?.as_str())
+///     .collect::<Vec<_>>();
+///
+/// assert_eq!(handlers, ["/core", "/card/foo"]);
+/// assert_eq!(params[1].as_list().unwrap()[1].as_str(), Some("Card {foo}"));
+/// ```
+///
+/// Note that an empty list and an empty value are both written as `{}`, and
+/// are parsed as an empty value.
+#[derive(Default, Debug, Clone, PartialEq, Eq)]
+pub struct MessageParams(Vec<MessageParam>);
+
+impl MessageParams {
+    /// Creates an empty set of parameters.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Appends an element, and returns `self` for chaining.
+    pub fn with(mut self, param: impl Into<MessageParam>) -> Self {
+        self.push(param);
+        self
+    }
+
+    /// Appends an element.
+    pub fn push(&mut self, param: impl Into<MessageParam>) {
+        self.0.push(param.into());
+    }
+
+    /// Formats the parameters as a string suitable for
+    /// [`SendObjectMessageParams::parameters`].
+    pub fn to_c_string(&self) -> Result<CString, ProtocolError> {
+        CString::new(self.to_string())
+            .map_err(|_| ProtocolError::Invalid("message parameter contains a NUL byte".into()))
+    }
+}
+
+impl std::ops::Deref for MessageParams {
+    type Target = [MessageParam];
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl From<Vec<MessageParam>> for MessageParams {
+    fn from(l: Vec<MessageParam>) -> Self {
+        Self(l)
+    }
+}
+
+impl fmt::Display for MessageParams {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for param in &self.0 {
+            write!(f, "{param}")?;
+        }
+
+        Ok(())
+    }
+}
+
+impl FromStr for MessageParams {
+    type Err = ProtocolError;
+
+    /// Parses a parameter string, following the rules of
+    /// `pa_message_params_read_raw`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        parse_list(s).map(Self)
+    }
+}
+
+/// Splits a list into its elements, like `split_list` in PulseAudio. Anything
+/// between elements is skipped, except for an unescaped closing brace.
+fn parse_list(s: &str) -> Result<Vec<MessageParam>, ProtocolError> {
+    let mut params = Vec::new();
+    let mut chars = s.char_indices();
+
+    while let Some((start, c)) = chars.next() {
+        match c {
+            '\\' => {
+                chars.next();
+                continue;
+            }
+            '{' => (),
+            '}' => {
+                return Err(ProtocolError::Invalid(
+                    "unexpected '}' in message parameters".into(),
+                ));
+            }
+            _ => continue,
+        }
+
+        // Find the matching closing brace. Any nested element makes this
+        // element a list.
+        let mut depth = 1;
+        let mut end = None;
+        let mut is_list = false;
+        while let Some((i, c)) = chars.next() {
+            match c {
+                '\\' => {
+                    chars.next();
+                }
+                '{' => {
+                    depth += 1;
+                    is_list = true;
+                }
+                '}' => {
+                    depth -= 1;
+                    if depth == 0 {
+                        end = Some(i);
+                        break;
+                    }
+                }
+                _ => (),
+            }
+        }
+
+        let Some(end) = end else {
+            return Err(ProtocolError::Invalid(
+                "unterminated element in message parameters".into(),
+            ));
+        };
+
+        let inner = &s[start + 1..end];
+        if is_list {
+            params.push(MessageParam::List(parse_list(inner)?));
+        } else {
+            params.push(MessageParam::Value(unescape(inner)));
+        }
+    }
+
+    Ok(params)
+}
+
+fn unescape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => out.extend(chars.next()),
+            c => out.push(c),
+        }
+    }
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::test_util::test_serde;
+
+    #[test]
+    fn test_send_object_message_params_serde() -> anyhow::Result<()> {
+        let params = SendObjectMessageParams {
+            object_path: CString::new("/core").unwrap(),
+            message: CString::new("list-handlers").unwrap(),
+            parameters: None,
+        };
+
+        test_serde(&params)
+    }
+
+    #[test]
+    fn test_send_object_message_reply_serde() -> anyhow::Result<()> {
+        let reply = SendObjectMessageReply(Some(CString::new("{{/core}{Core}}").unwrap()));
+        test_serde(&reply)
+    }
+
+    #[test]
+    fn parse_message_params() -> anyhow::Result<()> {
+        let params: MessageParams = " {1} { {a}{b\\}c} } {} ".parse()?;
+        assert_eq!(
+            params,
+            MessageParams::new()
+                .with("1")
+                .with(vec!["a".into(), "b}c".into()])
+                .with("")
+        );
+
+        // Like PulseAudio, text between elements is skipped, and any nested
+        // element makes a list.
+        let params: MessageParams = r"x{1}\{y".parse()?;
+        assert_eq!(params, MessageParams::new().with("1"));
+        let params: MessageParams = "{a{b}}".parse()?;
+        assert_eq!(params, MessageParams::new().with(vec!["b".into()]));
+
+        assert!("{1".parse::<MessageParams>().is_err());
+        assert!("{1}}".parse::<MessageParams>().is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn message_params_roundtrip() -> anyhow::Result<()> {
+        let params = MessageParams::new()
+            .with("plain")
+            .with(r"\{escaped}")
+            .with(vec![
+                MessageParam::from("nested"),
+                vec!["deeper".into()].into(),
+            ]);
+
+        let s = params.to_string();
+        assert_eq!(s, r"{plain}{\\\{escaped\}}{{nested}{{deeper}}}");
+        assert_eq!(s.parse::<MessageParams>()?, params);
+
+        Ok(())
+    }
+}
@@ -46,6 +46,30 @@ impl TagStructWrite for PlaySampleParams {
     }
 }
 
+/// The server response to [`super::Command::PlaySample`], containing the
+/// index of the sink input created to play the sample.
+#[derive(Default, Debug, Copy, Clone, Eq, PartialEq)]
+pub struct PlaySampleReply(pub u32);
+
+impl CommandReply for PlaySampleReply {}
+
+impl TagStructRead for PlaySampleReply {
+    fn read(ts: &mut TagStructReader<'_>, _protocol_version: u16) -> Result<Self, ProtocolError> {
+        Ok(Self(ts.read_u32()?))
+    }
+}
+
+impl TagStructWrite for PlaySampleReply {
+    fn write(
+        &self,
+        w: &mut TagStructWriter<'_>,
+        _protocol_version: u16,
+    ) -> Result<(), ProtocolError> {
+        w.write_u32(self.0)?;
+        Ok(())
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use self::test_util::test_serde;
@@ -64,6 +88,11 @@ mod tests {
 
         test_serde(&params)
     }
+
+    #[test]
+    fn test_play_sample_reply_serde() -> anyhow::Result<()> {
+        test_serde(&PlaySampleReply(42))
+    }
 }
 
 #[cfg(test)]
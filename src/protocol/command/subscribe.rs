@@ -85,6 +85,13 @@ pub enum SubscriptionEventType {
     Removed = 0x20,
 }
 
+impl SubscriptionEventFacility {
+    /// The [`SubscriptionMask`] bit that enables events from this facility.
+    pub fn mask(self) -> SubscriptionMask {
+        SubscriptionMask::from_bits_truncate(1 << (self as u32))
+    }
+}
+
 const FACILITY_MASK: u32 = 0x0F;
 const EVENT_TYPE_MASK: u32 = 0x30;
 
@@ -157,6 +164,22 @@ mod tests {
         };
         test_serde_version(&event, MAX_VERSION)
     }
+
+    #[test]
+    fn facility_mask() {
+        assert_eq!(
+            SubscriptionEventFacility::Sink.mask(),
+            SubscriptionMask::SINK
+        );
+        assert_eq!(
+            SubscriptionEventFacility::Server.mask(),
+            SubscriptionMask::SERVER
+        );
+        assert_eq!(
+            SubscriptionEventFacility::Card.mask(),
+            SubscriptionMask::CARD
+        );
+    }
 }
 
 #[cfg(test)]
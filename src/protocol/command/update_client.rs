@@ -31,6 +31,38 @@ impl TagStructWrite for UpdateClientProplistParams {
     }
 }
 
+/// Parameters for [`super::Command::RemoveClientProplist`].
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct RemoveClientProplistParams {
+    /// The keys of the props to remove.
+    pub keys: Vec<CString>,
+}
+
+impl TagStructRead for RemoveClientProplistParams {
+    fn read(ts: &mut TagStructReader<'_>, _protocol_version: u16) -> Result<Self, ProtocolError> {
+        let mut keys = Vec::new();
+        while let Some(key) = ts.read_string()? {
+            keys.push(key);
+        }
+
+        Ok(Self { keys })
+    }
+}
+
+impl TagStructWrite for RemoveClientProplistParams {
+    fn write(
+        &self,
+        ts: &mut TagStructWriter<'_>,
+        _protocol_version: u16,
+    ) -> Result<(), ProtocolError> {
+        for key in &self.keys {
+            ts.write_string(Some(key))?;
+        }
+        ts.write_null_string()?;
+        Ok(())
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -45,6 +77,15 @@ mod tests {
 
         test_serde(&params)
     }
+
+    #[test]
+    fn test_remove_client_props_params_serde() -> anyhow::Result<()> {
+        let params = RemoveClientProplistParams {
+            keys: vec![CString::new("application.name").unwrap()],
+        };
+
+        test_serde(&params)
+    }
 }
 
 #[cfg(test)]
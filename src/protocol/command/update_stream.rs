@@ -268,6 +268,45 @@ impl TagStructWrite for UpdatePropsParams {
     }
 }
 
+/// Parameters for [`super::Command::RemovePlaybackStreamProplist`] and [`super::Command::RemoveRecordStreamProplist`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RemovePropsParams {
+    /// The index of the object to update.
+    pub index: u32,
+
+    /// The keys of the props to remove.
+    pub keys: Vec<CString>,
+}
+
+impl TagStructRead for RemovePropsParams {
+    fn read(ts: &mut TagStructReader<'_>, _protocol_version: u16) -> Result<Self, ProtocolError> {
+        let index = ts.read_u32()?;
+
+        let mut keys = Vec::new();
+        while let Some(key) = ts.read_string()? {
+            keys.push(key);
+        }
+
+        Ok(Self { index, keys })
+    }
+}
+
+impl TagStructWrite for RemovePropsParams {
+    fn write(
+        &self,
+        w: &mut TagStructWriter<'_>,
+        _protocol_version: u16,
+    ) -> Result<(), ProtocolError> {
+        w.write_u32(self.index)?;
+        for key in &self.keys {
+            w.write_string(Some(key))?;
+        }
+        w.write_null_string()?;
+
+        Ok(())
+    }
+}
+
 /// Parameters for [`super::Command::UpdatePlaybackStreamSampleRate`] and [`super::Command::UpdateRecordStreamSampleRate`].
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub struct UpdateSampleRateParams {
@@ -328,6 +367,19 @@ mod tests {
         test_serde(&params)
     }
 
+    #[test]
+    fn test_remove_props_params_serde() -> anyhow::Result<()> {
+        let params = RemovePropsParams {
+            index: 0,
+            keys: vec![
+                CString::new("media.title").unwrap(),
+                CString::new("media.artist").unwrap(),
+            ],
+        };
+
+        test_serde(&params)
+    }
+
     #[test]
     fn test_update_sample_rate_params_serde() -> anyhow::Result<()> {
         let params = UpdateSampleRateParams {
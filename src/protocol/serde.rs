@@ -215,6 +215,13 @@ impl<'a> TagStructReader<'a> {
         T::read(self, self.protocol_version)
     }
 
+    /// Reads the rest of the input as raw tagstruct data, without parsing it.
+    pub fn read_raw_remaining(&mut self) -> Result<Vec<u8>, ProtocolError> {
+        let mut buf = Vec::new();
+        self.inner.read_to_end(&mut buf)?;
+        Ok(buf)
+    }
+
     /// Returns whether there is any data left in the input stream.
     pub fn has_data_left(&mut self) -> Result<bool, ProtocolError> {
         Ok(self.inner.fill_buf().map(|b| !b.is_empty())?)
@@ -345,6 +352,13 @@ impl<'a> TagStructWriter<'a> {
         Ok(())
     }
 
+    /// Writes raw tagstruct data, as returned by
+    /// [`TagStructReader::read_raw_remaining`].
+    pub fn write_raw(&mut self, bytes: &[u8]) -> Result<(), ProtocolError> {
+        self.inner.write_all(bytes)?;
+        Ok(())
+    }
+
     /// Appends a single value to the tagstruct.
     ///
     /// To append multiple values at once, use the `Extend` implementation.
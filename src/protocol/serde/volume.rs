@@ -188,11 +188,17 @@ impl<'a> Iterator for Iter<'a> {
     }
 }
 
-impl TagStructRead for ChannelVolume {
-    fn read(ts: &mut TagStructReader<'_>, _protocol_version: u16) -> Result<Self, ProtocolError> {
+impl ChannelVolume {
+    /// Reads a `ChannelVolume` which may have no channels, as sent by some
+    /// extensions to mean that no volume is set.
+    pub(crate) fn read_maybe_empty(ts: &mut TagStructReader<'_>) -> Result<Self, ProtocolError> {
+        Self::read_channels(ts, true)
+    }
+
+    fn read_channels(ts: &mut TagStructReader<'_>, allow_empty: bool) -> Result<Self, ProtocolError> {
         ts.expect_tag(Tag::CVolume)?;
         let n_channels = ts.inner.read_u8()?;
-        if n_channels == 0 || n_channels > MAX_CHANNELS {
+        if (n_channels == 0 && !allow_empty) || n_channels > MAX_CHANNELS {
             return Err(ProtocolError::Invalid(format!(
                 "invalid cvolume channel count {n_channels}, must be between 1 and {MAX_CHANNELS}"
             )));
@@ -208,6 +214,12 @@ impl TagStructRead for ChannelVolume {
     }
 }
 
+impl TagStructRead for ChannelVolume {
+    fn read(ts: &mut TagStructReader<'_>, _protocol_version: u16) -> Result<Self, ProtocolError> {
+        Self::read_channels(ts, false)
+    }
+}
+
 impl TagStructWrite for ChannelVolume {
     fn write(
         &self,